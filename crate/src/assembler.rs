@@ -0,0 +1,1083 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::diagnostics::{Diagnostic, Location, SourceId, Sources};
+use crate::instructions::{InstrSpec, IsaProfile, OperandKind};
+use crate::lexer::Token;
+use crate::preprocessor::{preprocess, Origin, Tok};
+
+pub use crate::diagnostics::Span;
+
+const INSTRUCTION_SIZE: usize = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AssemblyErrorKind {
+    UnknownToken,
+    UnexpectedToken,
+    InvalidInteger,
+    UnknownMnemonic(String),
+    DuplicateLabel(String),
+    /// A label or constant was given the name of a register, which operands would read as the register.
+    RegisterName(String),
+    UndefinedLabel(String),
+    ExpectedRegister,
+    ExpectedValue,
+    OperandOutOfRange {
+        value: u32,
+        max: u16,
+    },
+    WrongOperandCount {
+        expected: usize,
+        found: usize,
+    },
+    /// The program does not fit in the given number of bytes of memory.
+    ProgramTooLarge(usize),
+    UnknownDirective(String),
+    ExpectedString,
+    InvalidString,
+    ExpectedName,
+    /// A symbol was used by a directive which needs its value before the symbol is defined.
+    DefinedTooLate(String),
+    InvalidAlignment(u32),
+    /// The byte at the address is written by more than one statement.
+    Overlap(u16),
+    UnterminatedMacro,
+    NestedMacro,
+    DuplicateMacro(String),
+    /// Expanding the macro needed too many nested expansions, so it probably uses itself.
+    MacroTooDeep(String),
+    RecursiveInclude(String),
+    /// Includes were nested too deeply, so a file probably includes itself.
+    IncludeTooDeep(String),
+    /// The path of the file, and why it could not be read.
+    IncludeFailed(String, String),
+}
+
+impl Display for AssemblyErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssemblyErrorKind::UnknownToken => write!(f, "Unknown token"),
+            AssemblyErrorKind::UnexpectedToken => write!(f, "Unexpected token"),
+            AssemblyErrorKind::InvalidInteger => write!(f, "Invalid integer literal"),
+            AssemblyErrorKind::UnknownMnemonic(m) => write!(f, "Unknown mnemonic `{}`", m),
+            AssemblyErrorKind::DuplicateLabel(l) => write!(f, "Label `{}` is defined twice", l),
+            AssemblyErrorKind::RegisterName(l) => {
+                write!(
+                    f,
+                    "`{}` is a register, so it can not be a label or constant",
+                    l
+                )
+            }
+            AssemblyErrorKind::UndefinedLabel(l) => write!(f, "Label `{}` is not defined", l),
+            AssemblyErrorKind::ExpectedRegister => write!(f, "Expected a register"),
+            AssemblyErrorKind::ExpectedValue => write!(f, "Expected a value or label"),
+            AssemblyErrorKind::OperandOutOfRange { value, max } => {
+                write!(
+                    f,
+                    "Value {:#x} is out of range, the maximum is {:#x}",
+                    value, max
+                )
+            }
+            AssemblyErrorKind::WrongOperandCount { expected, found } => {
+                write!(f, "Expected {} operands, found {}", expected, found)
+            }
+            AssemblyErrorKind::ProgramTooLarge(size) => {
+                write!(f, "Program does not fit in {} bytes of memory", size)
+            }
+            AssemblyErrorKind::UnknownDirective(d) => write!(f, "Unknown directive `{}`", d),
+            AssemblyErrorKind::ExpectedString => write!(f, "Expected a string"),
+            AssemblyErrorKind::InvalidString => {
+                write!(f, "Strings may only contain ASCII and the escapes \\n, \\r, \\t, \\0, \\\\, \\\" and \\xHH")
+            }
+            AssemblyErrorKind::ExpectedName => write!(f, "Expected a name"),
+            AssemblyErrorKind::DefinedTooLate(l) => {
+                write!(f, "`{}` must be defined before it is used here", l)
+            }
+            AssemblyErrorKind::InvalidAlignment(a) => {
+                write!(f, "Alignment {} is not a power of two", a)
+            }
+            AssemblyErrorKind::UnterminatedMacro => write!(f, "Macro has no matching `.endm`"),
+            AssemblyErrorKind::NestedMacro => {
+                write!(f, "Macros can not be defined inside other macros")
+            }
+            AssemblyErrorKind::DuplicateMacro(m) => write!(f, "Macro `{}` is defined twice", m),
+            AssemblyErrorKind::MacroTooDeep(m) => {
+                write!(f, "Macro `{}` expands too deeply, does it use itself?", m)
+            }
+            AssemblyErrorKind::RecursiveInclude(path) => {
+                write!(f, "`{}` includes itself", path)
+            }
+            AssemblyErrorKind::IncludeTooDeep(path) => {
+                write!(
+                    f,
+                    "Including `{}` nests too deeply, does it include itself?",
+                    path
+                )
+            }
+            AssemblyErrorKind::IncludeFailed(path, error) => {
+                write!(f, "Could not include `{}`: {}", path, error)
+            }
+            AssemblyErrorKind::Overlap(address) => {
+                write!(
+                    f,
+                    "Address {:#04x} is already used by another statement",
+                    address
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssemblyError {
+    pub kind: AssemblyErrorKind,
+    /// Boxed, as the expansions it carries would make every `Result` with an assembly error large.
+    pub origin: Box<Origin>,
+    /// Other locations which explain the error, such as where a duplicate label was first defined.
+    pub related: Vec<(Location, String)>,
+    pub help: Option<String>,
+}
+
+impl AssemblyError {
+    pub(crate) fn new(kind: AssemblyErrorKind, origin: Origin) -> Self {
+        Self {
+            kind,
+            origin: Box::new(origin),
+            related: vec![],
+            help: None,
+        }
+    }
+
+    pub(crate) fn with_related(mut self, origin: &Origin, message: impl ToString) -> Self {
+        self.related.push((origin.location(), message.to_string()));
+        self
+    }
+
+    pub(crate) fn with_help(mut self, help: impl ToString) -> Self {
+        self.help = Some(help.to_string());
+        self
+    }
+
+    /// Converts the error into a diagnostic, labelling the related locations and every macro expansion it came from.
+    pub fn diagnostic(&self) -> Diagnostic {
+        let mut diagnostic = Diagnostic::error(&self.kind, self.origin.location());
+        for (location, message) in &self.related {
+            diagnostic = diagnostic.with_label(location.clone(), message);
+        }
+        for expansion in &self.origin.expansions {
+            diagnostic = diagnostic.with_label(expansion.clone(), "In this macro expansion");
+        }
+        match &self.help {
+            Some(help) => diagnostic.with_help(help),
+            None => diagnostic,
+        }
+    }
+}
+
+/// The result of assembling a program.
+#[derive(Debug)]
+pub struct Assembly {
+    /// Every byte of the memory of the instruction set the program was assembled for.
+    pub memory: Vec<u8>,
+    /// The address of every label in the program.
+    pub labels: BTreeMap<String, u16>,
+    /// The value of every constant defined with `.equ`.
+    pub constants: BTreeMap<String, u32>,
+    /// The number of bytes of memory used by the program, from address 0 to the end of the highest statement.
+    pub len: usize,
+}
+
+#[derive(Debug)]
+enum Operand {
+    Register(u32),
+    Integer(u32),
+    Symbol(String),
+    Missing,
+}
+
+#[derive(Debug)]
+enum Item {
+    Instr(InstrSpec, Vec<(Operand, Origin)>),
+    /// Big endian values of the given number of bytes each, which may refer to labels defined later.
+    Data(usize, Vec<(Operand, Origin)>),
+    Bytes(Vec<u8>),
+}
+
+impl Item {
+    fn size(&self) -> usize {
+        match self {
+            Item::Instr(..) => INSTRUCTION_SIZE,
+            Item::Data(width, values) => width * values.len(),
+            Item::Bytes(bytes) => bytes.len(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Statement {
+    address: usize,
+    /// The origin of the mnemonic or directive.
+    origin: Origin,
+    item: Item,
+}
+
+/// Every label and constant, which share a namespace.
+#[derive(Debug, Default)]
+struct Symbols {
+    labels: BTreeMap<String, u16>,
+    constants: BTreeMap<String, u32>,
+    /// Where every symbol was defined.
+    definitions: BTreeMap<String, Origin>,
+    /// The bytes of memory the program is assembled into, which labels must lie within.
+    memory_size: usize,
+}
+
+impl Symbols {
+    fn get(&self, name: &str) -> Option<u32> {
+        self.labels
+            .get(name)
+            .map(|&address| address as u32)
+            .or_else(|| self.constants.get(name).copied())
+    }
+
+    fn define(
+        &mut self,
+        name: String,
+        value: u32,
+        is_label: bool,
+        origin: &Origin,
+        errors: &mut Vec<AssemblyError>,
+    ) {
+        if parse_register(&name).is_some() {
+            errors.push(AssemblyError::new(
+                AssemblyErrorKind::RegisterName(name),
+                origin.clone(),
+            ));
+            return;
+        }
+        if let Some(first) = self.definitions.get(&name) {
+            errors.push(
+                AssemblyError::new(AssemblyErrorKind::DuplicateLabel(name), origin.clone())
+                    .with_related(first, "First defined here"),
+            );
+            return;
+        }
+        self.definitions.insert(name.clone(), origin.clone());
+        if is_label && value as usize >= self.memory_size {
+            errors.push(
+                AssemblyError::new(
+                    AssemblyErrorKind::ProgramTooLarge(self.memory_size),
+                    origin.clone(),
+                )
+                .with_help(format!(
+                    "Label `{}` is at address {:#x}, but the last address is {:#04x}",
+                    name,
+                    value,
+                    self.memory_size - 1
+                )),
+            );
+            // Kept as a constant, so that uses of the label are not also reported as undefined.
+            self.constants.insert(name, value);
+        } else if is_label {
+            self.labels.insert(name, value as u16);
+        } else {
+            self.constants.insert(name, value);
+        }
+    }
+
+    /// The defined symbol most similar to a misspelt name, if any are close enough.
+    fn similar(&self, name: &str) -> Option<(&String, &Origin)> {
+        self.definitions
+            .iter()
+            .map(|(symbol, origin)| (edit_distance(name, symbol), symbol, origin))
+            .filter(|&(distance, symbol, _)| distance <= symbol.len().min(name.len()) / 3 + 1)
+            .min_by_key(|&(distance, _, _)| distance)
+            .map(|(_, symbol, origin)| (symbol, origin))
+    }
+}
+
+/// The number of single character insertions, deletions and substitutions needed to turn one string into another, ignoring case.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().map(|c| c.to_ascii_lowercase()).collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().map(|c| c.to_ascii_lowercase()).enumerate() {
+        let mut current = vec![i + 1];
+        for (j, &b) in b.iter().enumerate() {
+            let substitution = previous[j] + (a != b) as usize;
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Tracks which bytes of memory have been used by a statement, to detect overlapping sections.
+struct Sections {
+    /// The statement which used each byte.
+    owners: Vec<Option<Origin>>,
+    too_large: bool,
+}
+
+impl Sections {
+    fn new(memory_size: usize) -> Self {
+        Self {
+            owners: vec![None; memory_size],
+            too_large: false,
+        }
+    }
+
+    /// Marks the bytes as used, reporting any which were already used or lie past the end of memory.
+    fn claim(&mut self, range: Range<usize>, origin: &Origin, errors: &mut Vec<AssemblyError>) {
+        let size = self.owners.len();
+        if range.end > size && !self.too_large {
+            self.too_large = true;
+            errors.push(
+                AssemblyError::new(AssemblyErrorKind::ProgramTooLarge(size), origin.clone()).with_help(
+                    format!(
+                        "This statement uses addresses {:#04x} to {:#x}, but the last address is {:#04x}",
+                        range.start,
+                        range.end - 1,
+                        size - 1
+                    ),
+                ),
+            );
+        }
+        let range = range.start.min(size)..range.end.min(size);
+        if let Some(address) = range
+            .clone()
+            .find(|&address| self.owners[address].is_some())
+        {
+            let owner = self.owners[address].as_ref().unwrap();
+            errors.push(
+                AssemblyError::new(AssemblyErrorKind::Overlap(address as u16), origin.clone())
+                    .with_related(
+                        owner,
+                        format!("Address {:#04x} is already used here", address),
+                    ),
+            );
+        }
+        self.owners[range].fill(Some(origin.clone()));
+    }
+}
+
+/// Parses an integer literal token in any of the bases understood by the lexer.
+pub fn parse_integer(token: &Token, text: &str) -> Option<u32> {
+    let (digits, radix) = match token {
+        Token::Integer => (text, 10),
+        Token::HexInteger => (&text[2..], 16),
+        Token::OctalInteger => (&text[2..], 8),
+        Token::BinaryInteger => (&text[2..], 2),
+        _ => return None,
+    };
+    u32::from_str_radix(&digits.replace('_', ""), radix).ok()
+}
+
+/// Parses a register name such as `r3`, `R12` or `rA`.
+pub(crate) fn parse_register(text: &str) -> Option<u32> {
+    let number = text.strip_prefix(['r', 'R'])?;
+    match number.len() {
+        1 => u32::from_str_radix(number, 16).ok(),
+        2 => number.parse().ok(),
+        _ => None,
+    }
+}
+
+fn parse_operand(tok: &Tok) -> Result<Operand, AssemblyError> {
+    let text = &tok.text;
+    match tok.token {
+        Token::Identifier => Ok(parse_register(text)
+            .map(Operand::Register)
+            .unwrap_or_else(|| Operand::Symbol(text.to_owned()))),
+        Token::Integer | Token::HexInteger | Token::OctalInteger | Token::BinaryInteger => {
+            parse_integer(&tok.token, text)
+                .map(Operand::Integer)
+                .ok_or_else(|| {
+                    AssemblyError::new(AssemblyErrorKind::InvalidInteger, tok.origin.clone())
+                })
+        }
+        _ => Err(AssemblyError::new(
+            AssemblyErrorKind::UnexpectedToken,
+            tok.origin.clone(),
+        )),
+    }
+}
+
+/// Parses the operand list following a mnemonic, which is separated by commas.
+fn parse_operands(tokens: &[Tok], errors: &mut Vec<AssemblyError>) -> Vec<(Operand, Origin)> {
+    let mut operands = vec![];
+    let Some(last) = tokens.last() else {
+        return operands;
+    };
+    for group in tokens.split(|tok| tok.token == Token::Comma) {
+        match group {
+            [] => {
+                let end = last.origin.span.end;
+                let origin = Origin {
+                    span: end..end,
+                    ..last.origin.clone()
+                };
+                operands.push((Operand::Missing, origin));
+            }
+            [tok] => match parse_operand(tok) {
+                Ok(operand) => operands.push((operand, tok.origin.clone())),
+                Err(e) => errors.push(e),
+            },
+            [_, tok, ..] => errors.push(AssemblyError::new(
+                AssemblyErrorKind::UnexpectedToken,
+                tok.origin.clone(),
+            )),
+        }
+    }
+    operands
+}
+
+/// Parses the contents of a string literal, including its quotes.
+fn parse_string(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut chars = text[1..text.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        let byte = match c {
+            '\\' => match chars.next()? {
+                'n' => b'\n',
+                'r' => b'\r',
+                't' => b'\t',
+                '0' => b'\0',
+                '\\' => b'\\',
+                '"' => b'"',
+                'x' => {
+                    let digits: String = chars.by_ref().take(2).collect();
+                    u8::from_str_radix(&digits, 16).ok()?
+                }
+                _ => return None,
+            },
+            c if c.is_ascii() => c as u8,
+            _ => return None,
+        };
+        bytes.push(byte);
+    }
+    Some(bytes)
+}
+
+/// Evaluates an operand of a directive which affects the layout, so can only refer to symbols defined above it.
+fn evaluate(operand: &(Operand, Origin), symbols: &Symbols) -> Result<u32, AssemblyError> {
+    match operand {
+        (Operand::Integer(i), _) => Ok(*i),
+        (Operand::Symbol(name), origin) => symbols.get(name).ok_or_else(|| {
+            AssemblyError::new(
+                AssemblyErrorKind::DefinedTooLate(name.clone()),
+                origin.clone(),
+            )
+        }),
+        (Operand::Register(_) | Operand::Missing, origin) => Err(AssemblyError::new(
+            AssemblyErrorKind::ExpectedValue,
+            origin.clone(),
+        )),
+    }
+}
+
+/// Checks that a directive has the expected number of operands, reporting the directive otherwise.
+fn expect_operands(
+    operands: Vec<(Operand, Origin)>,
+    expected: usize,
+    origin: &Origin,
+    errors: &mut Vec<AssemblyError>,
+) -> Option<Vec<(Operand, Origin)>> {
+    if operands.len() == expected {
+        Some(operands)
+    } else {
+        errors.push(AssemblyError::new(
+            AssemblyErrorKind::WrongOperandCount {
+                expected,
+                found: operands.len(),
+            },
+            origin.clone(),
+        ));
+        None
+    }
+}
+
+/// Lays out a single directive, returning the statement it emits, if any.
+fn directive(
+    tok: &Tok,
+    rest: &[Tok],
+    address: &mut usize,
+    symbols: &mut Symbols,
+    sections: &mut Sections,
+    errors: &mut Vec<AssemblyError>,
+) -> Option<Item> {
+    let origin = &tok.origin;
+    let name = &tok.text[1..];
+    if name.eq_ignore_ascii_case("ascii") {
+        return match rest {
+            [string] if string.token == Token::String => match parse_string(&string.text) {
+                Some(bytes) => Some(Item::Bytes(bytes)),
+                None => {
+                    errors.push(AssemblyError::new(
+                        AssemblyErrorKind::InvalidString,
+                        string.origin.clone(),
+                    ));
+                    None
+                }
+            },
+            _ => {
+                let string = rest.first().unwrap_or(tok).origin.clone();
+                errors.push(AssemblyError::new(
+                    AssemblyErrorKind::ExpectedString,
+                    string,
+                ));
+                None
+            }
+        };
+    }
+
+    let operands = parse_operands(rest, errors);
+    match name.to_ascii_lowercase().as_str() {
+        "byte" => Some(Item::Data(1, operands)),
+        "word" => Some(Item::Data(2, operands)),
+        "org" => {
+            let operands = expect_operands(operands, 1, origin, errors)?;
+            match evaluate(&operands[0], symbols) {
+                Ok(start) if start as usize >= symbols.memory_size => {
+                    errors.push(AssemblyError::new(
+                        AssemblyErrorKind::OperandOutOfRange {
+                            value: start,
+                            max: (symbols.memory_size - 1) as u16,
+                        },
+                        operands[0].1.clone(),
+                    ));
+                }
+                Ok(start) => *address = start as usize,
+                Err(e) => errors.push(e),
+            }
+            None
+        }
+        "align" => {
+            let operands = expect_operands(operands, 1, origin, errors)?;
+            match evaluate(&operands[0], symbols) {
+                Ok(alignment) if !alignment.is_power_of_two() => {
+                    errors.push(AssemblyError::new(
+                        AssemblyErrorKind::InvalidAlignment(alignment),
+                        operands[0].1.clone(),
+                    ));
+                }
+                Ok(alignment) => *address = address.next_multiple_of(alignment as usize),
+                Err(e) => errors.push(e),
+            }
+            None
+        }
+        "space" => {
+            let operands = expect_operands(operands, 1, origin, errors)?;
+            match evaluate(&operands[0], symbols) {
+                Ok(size) => {
+                    let end = *address + size as usize;
+                    sections.claim(*address..end, origin, errors);
+                    *address = end;
+                }
+                Err(e) => errors.push(e),
+            }
+            None
+        }
+        "equ" => {
+            let operands = expect_operands(operands, 2, origin, errors)?;
+            let name = match &operands[0].0 {
+                Operand::Symbol(name) => name,
+                Operand::Register(_) => {
+                    errors.push(AssemblyError::new(
+                        AssemblyErrorKind::RegisterName(rest[0].text.clone()),
+                        operands[0].1.clone(),
+                    ));
+                    return None;
+                }
+                _ => {
+                    errors.push(AssemblyError::new(
+                        AssemblyErrorKind::ExpectedName,
+                        operands[0].1.clone(),
+                    ));
+                    return None;
+                }
+            };
+            match evaluate(&operands[1], symbols) {
+                Ok(value) => symbols.define(name.clone(), value, false, &operands[0].1, errors),
+                Err(e) => errors.push(e),
+            }
+            None
+        }
+        _ => {
+            errors.push(AssemblyError::new(
+                AssemblyErrorKind::UnknownDirective(tok.text.clone()),
+                origin.clone(),
+            ));
+            None
+        }
+    }
+}
+
+/// First pass: lays out every statement and records the address of each label.
+fn layout(
+    lines: Vec<Vec<Tok>>,
+    isa: &IsaProfile,
+    symbols: &mut Symbols,
+    errors: &mut Vec<AssemblyError>,
+) -> Vec<Statement> {
+    let mut statements = vec![];
+    let mut address = 0;
+    let mut sections = Sections::new(symbols.memory_size);
+    for line in lines {
+        let mut tokens = &line[..];
+        while let [label, rest @ ..] = tokens {
+            if label.token != Token::Label {
+                break;
+            }
+            let name = label.text.trim_end_matches(':').to_owned();
+            symbols.define(name, address as u32, true, &label.origin, errors);
+            tokens = rest;
+        }
+        let Some((tok, rest)) = tokens.split_first() else {
+            continue;
+        };
+        let item = match tok.token {
+            Token::Directive => directive(tok, rest, &mut address, symbols, &mut sections, errors),
+            Token::Identifier => match isa.spec(&tok.text) {
+                Some(spec) => Some(Item::Instr(spec.clone(), parse_operands(rest, errors))),
+                None => {
+                    errors.push(AssemblyError::new(
+                        AssemblyErrorKind::UnknownMnemonic(tok.text.clone()),
+                        tok.origin.clone(),
+                    ));
+                    None
+                }
+            },
+            _ => {
+                errors.push(AssemblyError::new(
+                    AssemblyErrorKind::UnexpectedToken,
+                    tok.origin.clone(),
+                ));
+                None
+            }
+        };
+        if let Some(item) = item {
+            let size = item.size();
+            sections.claim(address..address + size, &tok.origin, errors);
+            statements.push(Statement {
+                address,
+                origin: tok.origin.clone(),
+                item,
+            });
+            address += size;
+        }
+    }
+    statements
+}
+
+/// Resolves a value operand to a number no larger than `max`.
+fn resolve_value(
+    operand: &Operand,
+    origin: &Origin,
+    max: u16,
+    symbols: &Symbols,
+) -> Result<u32, AssemblyError> {
+    let value = match operand {
+        Operand::Integer(i) => *i,
+        Operand::Symbol(name) => match symbols.get(name) {
+            Some(value) => value,
+            None => {
+                let error = AssemblyError::new(
+                    AssemblyErrorKind::UndefinedLabel(name.clone()),
+                    origin.clone(),
+                );
+                return Err(match symbols.similar(name) {
+                    Some((similar, definition)) => error
+                        .with_related(definition, format!("`{}` is defined here", similar))
+                        .with_help(format!("Did you mean `{}`?", similar)),
+                    None => error.with_help(format!("Define it with `{}:`", name)),
+                });
+            }
+        },
+        Operand::Register(_) | Operand::Missing => {
+            return Err(AssemblyError::new(
+                AssemblyErrorKind::ExpectedValue,
+                origin.clone(),
+            ));
+        }
+    };
+    if value > max as u32 {
+        return Err(AssemblyError::new(
+            AssemblyErrorKind::OperandOutOfRange { value, max },
+            origin.clone(),
+        ));
+    }
+    Ok(value)
+}
+
+/// Second pass: resolves labels and checks the operands of a single instruction.
+fn resolve(
+    spec: &InstrSpec,
+    operands: &[(Operand, Origin)],
+    origin: &Origin,
+    symbols: &Symbols,
+    errors: &mut Vec<AssemblyError>,
+) -> Option<u16> {
+    let expected = spec.operands.len();
+    if operands.len() != expected {
+        let mut error = AssemblyError::new(
+            AssemblyErrorKind::WrongOperandCount {
+                expected,
+                found: operands.len(),
+            },
+            origin.clone(),
+        )
+        .with_help(format!("Write `{}`", spec.syntax()));
+        for (_, extra) in operands.iter().skip(expected) {
+            error = error.with_related(extra, "Unexpected operand");
+        }
+        errors.push(error);
+        return None;
+    }
+
+    let mut values = vec![];
+    for (spec_operand, (operand, operand_origin)) in spec.operands.iter().zip(operands) {
+        let value = match (spec_operand.kind, operand) {
+            (OperandKind::Register, Operand::Register(r)) if *r > spec_operand.mask as u32 => {
+                Err(AssemblyError::new(
+                    AssemblyErrorKind::OperandOutOfRange {
+                        value: *r,
+                        max: spec_operand.mask,
+                    },
+                    operand_origin.clone(),
+                ))
+            }
+            (OperandKind::Register, Operand::Register(r)) => Ok(*r),
+            (OperandKind::Register, _) => Err(AssemblyError::new(
+                AssemblyErrorKind::ExpectedRegister,
+                operand_origin.clone(),
+            )),
+            (_, operand) => resolve_value(operand, operand_origin, spec_operand.mask, symbols),
+        };
+        match value {
+            Ok(value) => values.push(value as u16),
+            Err(
+                error @ AssemblyError {
+                    kind: AssemblyErrorKind::OperandOutOfRange { max, .. },
+                    ..
+                },
+            ) => errors.push(error.with_related(
+                origin,
+                format!(
+                    "Operand `{}` of `{}` is at most {:#x}",
+                    spec_operand.name, spec.mnemonic, max
+                ),
+            )),
+            Err(error) => errors.push(error),
+        }
+    }
+
+    (values.len() == expected).then(|| spec.encode(&values))
+}
+
+/// Assembles a program, starting at address 0.
+/// Every instruction is written as its mnemonic followed by comma separated operands, and labels may be used in place of any address or value.
+///
+/// The directives are:
+/// - `.org address` continues the program at the address.
+/// - `.byte value, ...` and `.word value, ...` write bytes and big endian words.
+/// - `.ascii "text"` writes the bytes of a string.
+/// - `.equ name, value` defines a constant, which can be used like a label.
+/// - `.align n` skips to the next multiple of n, which must be a power of two.
+/// - `.space n` reserves n bytes without writing them.
+///
+/// The operands of `.org`, `.equ`, `.align` and `.space` can only refer to symbols defined above them.
+/// Statements which write the same byte of memory are reported as overlapping.
+///
+/// Files can be included with `.include "path"`, and macros defined with `.macro name param, ...` up to `.endm`.
+/// Included files are read relative to the working directory.
+pub fn assemble(source: &str) -> Result<Assembly, Vec<AssemblyError>> {
+    let mut sources = Sources::default();
+    let root = sources.add("", source);
+    assemble_sources(&mut sources, root, &IsaProfile::default(), &mut |path| {
+        fs::read_to_string(path)
+    })
+}
+
+/// Like [assemble], but reads the program from one of `sources`, adding every file it includes,
+/// and accepts the mnemonics of `isa` and any address in its [memory](IsaProfile::memory_size).
+/// Included files are read with `load`, relative to the file that includes them.
+pub fn assemble_sources(
+    sources: &mut Sources,
+    root: SourceId,
+    isa: &IsaProfile,
+    load: &mut dyn FnMut(&Path) -> io::Result<String>,
+) -> Result<Assembly, Vec<AssemblyError>> {
+    let mut errors = vec![];
+    let mut symbols = Symbols {
+        memory_size: isa.memory_size(),
+        ..Default::default()
+    };
+
+    let lines = preprocess(sources, root, load, &mut errors);
+    let statements = layout(lines, isa, &mut symbols, &mut errors);
+
+    let mut memory = vec![0; isa.memory_size()];
+    let mut len = 0;
+    for statement in &statements {
+        let bytes = match &statement.item {
+            Item::Instr(spec, operands) => {
+                resolve(spec, operands, &statement.origin, &symbols, &mut errors)
+                    .map(|instr| instr.to_be_bytes().to_vec())
+            }
+            Item::Data(width, values) => {
+                let max = (1u32 << (8 * width)) - 1;
+                let resolved: Vec<u8> = values
+                    .iter()
+                    .filter_map(|(value, origin)| {
+                        match resolve_value(value, origin, max as u16, &symbols) {
+                            Ok(value) => Some(value),
+                            Err(error) => {
+                                let error = match error.kind {
+                                    AssemblyErrorKind::OperandOutOfRange { .. } => error
+                                        .with_related(
+                                            &statement.origin,
+                                            format!(
+                                                "`.{}` values are at most {:#x}",
+                                                if *width == 1 { "byte" } else { "word" },
+                                                max
+                                            ),
+                                        ),
+                                    _ => error,
+                                };
+                                errors.push(error);
+                                None
+                            }
+                        }
+                    })
+                    .flat_map(|value| value.to_be_bytes()[4 - width..].to_vec())
+                    .collect();
+                (resolved.len() == statement.item.size()).then_some(resolved)
+            }
+            Item::Bytes(bytes) => Some(bytes.clone()),
+        };
+        let end = statement.address + statement.item.size();
+        len = len.max(end);
+        if let Some(bytes) = bytes {
+            if end <= memory.len() {
+                memory[statement.address..end].copy_from_slice(&bytes);
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(Assembly {
+            memory,
+            labels: symbols.labels,
+            constants: symbols.constants,
+            len,
+        })
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn assemble_works() {
+    let source = "
+        load_value r1, 0x01 // Counter increment
+        load_value r0, 5
+    loop:
+        add_integer r2, r2, r1
+        jump_if_eq r2, done
+        jump loop
+    done:
+        store_memory r2, 0x80
+        halt
+    ";
+    let assembly = assemble(source).unwrap();
+    assert_eq!(assembly.len, 14);
+    assert_eq!(assembly.labels["loop"], 4);
+    assert_eq!(assembly.labels["done"], 10);
+    assert_eq!(
+        assembly.memory[..14],
+        [0x21, 0x01, 0x20, 0x05, 0x52, 0x21, 0xB2, 0x0A, 0xB0, 0x04, 0x32, 0x80, 0xC0, 0x00]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn assemble_reports_errors() {
+    let source = "
+    start:
+        load_value r16, 0x100
+        jump nowhere
+        frobnicate r1
+        move_register r1
+    start:
+    ";
+    let kinds: Vec<_> = assemble(source)
+        .unwrap_err()
+        .into_iter()
+        .map(|e| e.kind)
+        .collect();
+    assert_eq!(
+        kinds,
+        [
+            AssemblyErrorKind::UnknownMnemonic("frobnicate".to_owned()),
+            AssemblyErrorKind::DuplicateLabel("start".to_owned()),
+            AssemblyErrorKind::OperandOutOfRange {
+                value: 16,
+                max: 0xf
+            },
+            AssemblyErrorKind::OperandOutOfRange {
+                value: 0x100,
+                max: 0xff
+            },
+            AssemblyErrorKind::UndefinedLabel("nowhere".to_owned()),
+            AssemblyErrorKind::WrongOperandCount {
+                expected: 2,
+                found: 1
+            },
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn assemble_errors_explain_themselves() {
+    let source = "counter: halt\ncounter: jump countr\nload_value r1\n.org 0\nhalt\n";
+    let errors = assemble(source).unwrap_err();
+    let diagnostics: Vec<Diagnostic> = errors.iter().map(|e| e.diagnostic()).collect();
+    let spans: Vec<Vec<(usize, usize)>> = diagnostics
+        .iter()
+        .map(|d| {
+            d.labels
+                .iter()
+                .map(|l| (l.location.span.start, l.location.span.end))
+                .collect()
+        })
+        .collect();
+    // The duplicate label points at the first definition, and the overlap at the halt it overwrites.
+    assert_eq!(
+        spans,
+        [
+            vec![(14, 22), (0, 8)],
+            vec![(56, 60), (9, 13)],
+            vec![(28, 34), (0, 8)],
+            vec![(35, 45)]
+        ]
+    );
+    assert_eq!(
+        diagnostics[2].help.as_deref(),
+        Some("Did you mean `counter`?")
+    );
+    assert_eq!(
+        diagnostics[3].help.as_deref(),
+        Some("Write `load_value r, xy`")
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn assemble_directives_work() {
+    let source = r#"
+        .equ COUNT, 3
+        .equ TABLE, 0x40
+        load_memory r1, table
+        halt
+        .org TABLE
+    table:
+        .byte COUNT, 0b1010, end
+        .align 4
+        .word 0x1234
+        .space 2
+    message:
+        .ascii "hi\n\x7F"
+    end:
+    "#;
+    let assembly = assemble(source).unwrap();
+    assert_eq!(assembly.labels["table"], 0x40);
+    assert_eq!(assembly.labels["message"], 0x48);
+    assert_eq!(assembly.constants["COUNT"], 3);
+    assert_eq!(assembly.len, 0x4C);
+    assert_eq!(assembly.memory[..4], [0x11, 0x40, 0xC0, 0x00]);
+    assert_eq!(
+        assembly.memory[0x40..0x4C],
+        [3, 0x0A, 0x4C, 0, 0x12, 0x34, 0, 0, b'h', b'i', b'\n', 0x7F]
+    );
+
+    let source = r#"
+        .org 1
+        .byte 1, 2
+        .org 2
+        halt
+        .org later
+        .align 3
+        .word 0x10000
+        .ascii "café"
+        .frobnicate
+        .equ ra, 1
+    rc:
+    later:
+    "#;
+    let kinds: Vec<_> = assemble(source)
+        .unwrap_err()
+        .into_iter()
+        .map(|e| e.kind)
+        .collect();
+    assert_eq!(
+        kinds,
+        [
+            AssemblyErrorKind::Overlap(2),
+            AssemblyErrorKind::DefinedTooLate("later".to_owned()),
+            AssemblyErrorKind::InvalidAlignment(3),
+            AssemblyErrorKind::InvalidString,
+            AssemblyErrorKind::UnknownDirective(".frobnicate".to_owned()),
+            AssemblyErrorKind::RegisterName("ra".to_owned()),
+            AssemblyErrorKind::RegisterName("rc".to_owned()),
+            AssemblyErrorKind::OperandOutOfRange {
+                value: 0x10000,
+                max: 0xffff
+            },
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn assemble_uses_the_memory_of_the_profile() {
+    let source = ".org 0x180\nstart: halt\n";
+    let mut sources = Sources::default();
+    let root = sources.add("", source);
+    let assembly = assemble_sources(
+        &mut sources,
+        root,
+        &IsaProfile::Wide,
+        &mut |_| unreachable!(),
+    )
+    .unwrap();
+    assert_eq!(assembly.memory.len(), 0x10000);
+    assert_eq!(assembly.labels["start"], 0x180);
+    assert_eq!(assembly.memory[0x180..0x182], [0xC0, 0x00]);
+
+    let kinds: Vec<_> = assemble(source)
+        .unwrap_err()
+        .into_iter()
+        .map(|e| e.kind)
+        .collect();
+    assert_eq!(
+        kinds,
+        [AssemblyErrorKind::OperandOutOfRange {
+            value: 0x180,
+            max: 0xff
+        }]
+    );
+
+    let errors = assemble(".org 0xFE\n.word 0\nend:\n").unwrap_err();
+    assert_eq!(errors[0].kind, AssemblyErrorKind::ProgramTooLarge(0x100));
+    assert_eq!(
+        errors[0].help.as_deref(),
+        Some("Label `end` is at address 0x100, but the last address is 0xff")
+    );
+    assert_eq!(errors.len(), 1);
+}
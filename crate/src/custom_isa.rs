@@ -0,0 +1,419 @@
+use std::borrow::Cow;
+use std::fmt::{Debug, Display};
+use std::sync::Arc;
+
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize};
+
+use crate::float::{self, Rounding};
+use crate::instructions::{
+    encodings_overlap, fields_disjoint, fields_fit, InstrSpec, IsaProfile, OperandKind, OperandSpec,
+};
+use crate::machine_code::{Ctx, Res};
+use crate::rtl::{self, RtlError, Statement};
+
+/// The most operands a custom instruction can have.
+pub const MAX_OPERANDS: usize = 4;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IsaFile {
+    /// Overrides how the base profile rounds floating point sums.
+    float_rounding: Option<Rounding>,
+    /// Overrides the excess of the exponent of the base profile's floating point values.
+    float_excess: Option<u8>,
+    #[serde(default, rename = "instruction")]
+    instructions: Vec<InstructionEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InstructionEntry {
+    mnemonic: String,
+    pattern: u16,
+    mask: u16,
+    #[serde(default)]
+    operands: Vec<OperandEntry>,
+    semantics: String,
+    /// Shown by editors for the instruction.
+    #[serde(default)]
+    doc: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OperandEntry {
+    name: String,
+    kind: OperandKind,
+    shift: u8,
+    mask: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CustomIsaErrorKind {
+    /// The file is not TOML, or does not have the fields of an instruction set.
+    Syntax(String),
+    /// The mnemonic can not be written in assembly.
+    InvalidMnemonic,
+    /// The mnemonic is already used by another instruction.
+    DuplicateMnemonic,
+    TooManyOperands,
+    /// An operand name which is not an identifier, or is a keyword of the semantics.
+    InvalidOperandName(String),
+    /// The pattern sets bits outside the mask, or an operand is wider than 8 bits or lies outside the bits left free by the mask.
+    InvalidEncoding,
+    OperandsOverlap,
+    /// The encoding shares instruction words with the named instruction.
+    Overlaps(String),
+    Semantics(RtlError),
+    /// The excess of floating point exponents does not fit in 3 bits.
+    InvalidFloatExcess(u8),
+}
+
+/// A mistake in the description of an instruction set, with the mnemonic of the instruction it was found in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CustomIsaError {
+    pub mnemonic: Option<String>,
+    pub kind: CustomIsaErrorKind,
+}
+
+impl Display for CustomIsaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(mnemonic) = &self.mnemonic {
+            write!(f, "`{}`: ", mnemonic)?;
+        }
+        match &self.kind {
+            CustomIsaErrorKind::Syntax(message) => write!(f, "{}", message.trim_end()),
+            CustomIsaErrorKind::InvalidMnemonic => write!(
+                f,
+                "Mnemonics must start with a letter or `_`, followed by letters, digits and `_`"
+            ),
+            CustomIsaErrorKind::DuplicateMnemonic => write!(f, "Mnemonic is already used"),
+            CustomIsaErrorKind::TooManyOperands => {
+                write!(f, "Instructions have at most {} operands", MAX_OPERANDS)
+            }
+            CustomIsaErrorKind::InvalidOperandName(name) => write!(
+                f,
+                "Operand `{}` must be an identifier other than {}",
+                name,
+                rtl::KEYWORDS.map(|keyword| format!("`{}`", keyword)).join(", ")
+            ),
+            CustomIsaErrorKind::InvalidEncoding => write!(
+                f,
+                "The pattern sets bits outside the mask, or an operand is wider than 8 bits or lies outside the bits left free by the mask"
+            ),
+            CustomIsaErrorKind::OperandsOverlap => write!(f, "Operands overlap each other"),
+            CustomIsaErrorKind::Overlaps(other) => {
+                write!(f, "Encoding overlaps the encoding of `{}`", other)
+            }
+            CustomIsaErrorKind::Semantics(error) => write!(f, "{} in the semantics", error),
+            CustomIsaErrorKind::InvalidFloatExcess(excess) => write!(
+                f,
+                "Float excess {} must be at most {}",
+                excess,
+                float::MAX_EXCESS
+            ),
+        }
+    }
+}
+
+/// An instruction described in a TOML file, with its semantics.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CustomOp {
+    pub spec: InstrSpec,
+    pub semantics: Vec<Statement>,
+}
+
+/// Instructions described in a TOML file, which are added to the instructions of a built-in profile.
+///
+/// Each instruction is an `[[instruction]]` table with a `mnemonic`, a `pattern` and `mask` like those of the `instructions!` macro,
+/// `operands` which each have a `name`, a `kind` of `register`, `address` or `immediate`, a `shift` and a `mask`,
+/// and `semantics` in the register-transfer language of [rtl::parse].
+/// Instructions can only use encodings which no other instruction uses.
+/// The file can also set `float_rounding` to `truncate` or `nearest`, and `float_excess` to the excess of floating point exponents.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CustomIsa {
+    pub base: IsaProfile,
+    pub float_rounding: Rounding,
+    pub float_excess: u8,
+    pub instructions: Vec<Arc<CustomOp>>,
+    /// The custom instructions followed by those of the base profile, in decoding order.
+    specs: Vec<InstrSpec>,
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn check(
+    entry: &InstructionEntry,
+    spec: &InstrSpec,
+    earlier: &[InstrSpec],
+) -> Option<CustomIsaErrorKind> {
+    if !is_identifier(&entry.mnemonic) {
+        return Some(CustomIsaErrorKind::InvalidMnemonic);
+    }
+    if earlier
+        .iter()
+        .any(|other| other.mnemonic.eq_ignore_ascii_case(&entry.mnemonic))
+    {
+        return Some(CustomIsaErrorKind::DuplicateMnemonic);
+    }
+    if entry.operands.len() > MAX_OPERANDS {
+        return Some(CustomIsaErrorKind::TooManyOperands);
+    }
+    if let Some(operand) = entry
+        .operands
+        .iter()
+        .find(|operand| !is_identifier(&operand.name) || rtl::KEYWORDS.contains(&&*operand.name))
+    {
+        return Some(CustomIsaErrorKind::InvalidOperandName(operand.name.clone()));
+    }
+    let too_wide = |operand: &OperandEntry| operand.mask > 0xFF || operand.shift >= 16;
+    if entry.operands.iter().any(too_wide) || !fields_fit(spec) {
+        return Some(CustomIsaErrorKind::InvalidEncoding);
+    }
+    if !fields_disjoint(spec) {
+        return Some(CustomIsaErrorKind::OperandsOverlap);
+    }
+    earlier
+        .iter()
+        .find(|other| encodings_overlap(spec, other))
+        .map(|other| CustomIsaErrorKind::Overlaps(other.mnemonic.to_string()))
+}
+
+impl CustomIsa {
+    /// Reads the instructions described by a TOML file, adding them to those of `base`.
+    pub fn load(source: &str, base: IsaProfile) -> Result<CustomIsa, Vec<CustomIsaError>> {
+        let file: IsaFile = toml::from_str(source).map_err(|error| {
+            vec![CustomIsaError {
+                mnemonic: None,
+                kind: CustomIsaErrorKind::Syntax(error.to_string()),
+            }]
+        })?;
+
+        let mut errors = vec![];
+        if let Some(excess) = file
+            .float_excess
+            .filter(|&excess| excess > float::MAX_EXCESS)
+        {
+            errors.push(CustomIsaError {
+                mnemonic: None,
+                kind: CustomIsaErrorKind::InvalidFloatExcess(excess),
+            });
+        }
+        let mut earlier = base.specs().to_vec();
+        let mut instructions = vec![];
+        for entry in file.instructions {
+            let operands: Vec<OperandSpec> = entry
+                .operands
+                .iter()
+                .map(|operand| OperandSpec {
+                    name: Cow::Owned(operand.name.clone()),
+                    kind: operand.kind,
+                    shift: operand.shift,
+                    mask: operand.mask,
+                })
+                .collect();
+            let spec = InstrSpec {
+                mnemonic: Cow::Owned(entry.mnemonic.clone()),
+                doc: Cow::Owned(entry.doc.clone()),
+                pattern: entry.pattern,
+                mask: entry.mask,
+                operands: Cow::Owned(operands),
+            };
+            let names: Vec<&str> = spec.operands.iter().map(|operand| &*operand.name).collect();
+            let kind = match check(&entry, &spec, &earlier) {
+                Some(kind) => Err(kind),
+                None => rtl::parse(&entry.semantics, &names).map_err(CustomIsaErrorKind::Semantics),
+            };
+            match kind {
+                Ok(semantics) => {
+                    earlier.push(spec.clone());
+                    instructions.push(Arc::new(CustomOp { spec, semantics }));
+                }
+                Err(kind) => errors.push(CustomIsaError {
+                    mnemonic: Some(entry.mnemonic),
+                    kind,
+                }),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let specs = instructions
+            .iter()
+            .map(|op| op.spec.clone())
+            .chain(base.specs().iter().cloned())
+            .collect();
+        Ok(CustomIsa {
+            float_rounding: file.float_rounding.unwrap_or(base.float_rounding()),
+            float_excess: file.float_excess.unwrap_or(base.float_excess()),
+            base,
+            instructions,
+            specs,
+        })
+    }
+
+    /// The mnemonic and encoding of every instruction, in decoding order.
+    pub fn specs(&self) -> &[InstrSpec] {
+        &self.specs
+    }
+
+    /// Finds the custom instruction with the given mnemonic.
+    pub fn op(&self, mnemonic: &str) -> Option<&Arc<CustomOp>> {
+        self.instructions
+            .iter()
+            .find(|op| op.spec.mnemonic == mnemonic)
+    }
+}
+
+/// A decoded custom instruction, with its operand values.
+#[derive(Clone)]
+pub struct CustomInstr {
+    op: Arc<CustomOp>,
+    operands: [u8; MAX_OPERANDS],
+}
+
+impl CustomInstr {
+    pub fn new(op: Arc<CustomOp>, values: &[u8]) -> Self {
+        let mut operands = [0; MAX_OPERANDS];
+        operands[..values.len()].copy_from_slice(values);
+        Self { op, operands }
+    }
+
+    pub fn spec(&self) -> &InstrSpec {
+        &self.op.spec
+    }
+
+    /// The operand values of this instruction, in the order they are written in assembly.
+    pub fn operands(&self) -> Vec<u8> {
+        self.operands[..self.op.spec.operands.len()].to_vec()
+    }
+
+    pub fn execute<const M: usize, const R: usize>(&self, ctx: &mut Ctx<M, R>) -> Res {
+        rtl::execute(&self.op.semantics, &self.operands, ctx)
+    }
+}
+
+impl Debug for CustomInstr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut tuple = f.debug_tuple(&self.op.spec.mnemonic);
+        for operand in self.operands() {
+            tuple.field(&operand);
+        }
+        tuple.finish()
+    }
+}
+
+impl Serialize for CustomInstr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("CustomInstr", 2)?;
+        state.serialize_field("mnemonic", &self.op.spec.mnemonic)?;
+        state.serialize_field("operands", &self.operands())?;
+        state.end()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn custom_isa_loads_and_runs() {
+    use crate::assembler::assemble_sources;
+    use crate::diagnostics::Sources;
+    use crate::instructions::Instr;
+    use crate::machine_code::Err;
+
+    let source = r#"
+        [[instruction]]
+        mnemonic = "subtract"
+        pattern = 0x0100
+        mask = 0xFF00
+        operands = [
+            { name = "r", kind = "register", shift = 4, mask = 0xf },
+            { name = "s", kind = "register", shift = 0, mask = 0xf },
+        ]
+        semantics = "R[r] <- R[r] - R[s]"
+
+        [[instruction]]
+        mnemonic = "halt_if_zero"
+        pattern = 0x0200
+        mask = 0xFFF0
+        operands = [{ name = "r", kind = "register", shift = 0, mask = 0xf }]
+        semantics = "if R[r] == 0 then halt"
+    "#;
+    let isa = IsaProfile::Custom(Arc::new(
+        CustomIsa::load(source, IsaProfile::Classic).unwrap(),
+    ));
+
+    let mut sources = Sources::default();
+    let program =
+        "load_value r1, 3\nload_value r2, 1\nloop: subtract r1, r2\nhalt_if_zero r1\njump loop\n";
+    let root = sources.add("", program);
+    let assembly = assemble_sources(&mut sources, root, &isa, &mut |_| unreachable!()).unwrap();
+    assert_eq!(assembly.memory[4..8], [0x01, 0x12, 0x02, 0x01]);
+
+    let instr = isa.decode(0x0112).unwrap();
+    assert_eq!(format!("{:?}", instr), "Custom(subtract(1, 2))");
+    assert_eq!(isa.encode(&instr), Some(0x0112));
+    assert!(matches!(isa.decode(0x0FFF), Ok(Instr::NoOp())));
+    assert_eq!(isa.float_rounding(), Rounding::Nearest);
+    let truncating = CustomIsa::load("float_rounding = \"truncate\"", IsaProfile::Classic).unwrap();
+    assert_eq!(
+        IsaProfile::Custom(Arc::new(truncating)).float_rounding(),
+        Rounding::Truncate
+    );
+    let excess_3 = CustomIsa::load("float_excess = 3", IsaProfile::Classic).unwrap();
+    assert_eq!(IsaProfile::Custom(Arc::new(excess_3)).float_excess(), 3);
+    assert_eq!(
+        CustomIsa::load("float_excess = 8", IsaProfile::Classic).unwrap_err()[0].kind,
+        CustomIsaErrorKind::InvalidFloatExcess(8)
+    );
+
+    let mut ctx: crate::Ctx = crate::Ctx::new(assembly.memory.try_into().unwrap());
+    ctx.isa = isa;
+    assert_eq!(crate::execute(&mut ctx, 32).1, Err(Err::HaltExecution));
+    assert_eq!((ctx.registers[1], ctx.pc), (0, 0x08));
+
+    let invalid = r#"
+        [[instruction]]
+        mnemonic = "wait"
+        pattern = 0x0F00
+        mask = 0xFF00
+        semantics = ""
+
+        [[instruction]]
+        mnemonic = "clear"
+        pattern = 0x0300
+        mask = 0xFFF0
+        operands = [{ name = "r", kind = "register", shift = 0, mask = 0xf }]
+        semantics = "R[s] <- 0"
+
+        [[instruction]]
+        mnemonic = "bump"
+        pattern = 0x0400
+        mask = 0xFFF0
+        operands = [{ name = "M", kind = "register", shift = 0, mask = 0xf }]
+        semantics = "R[M] <- R[M] + 1"
+    "#;
+    let kinds: Vec<CustomIsaErrorKind> = CustomIsa::load(invalid, IsaProfile::Classic)
+        .unwrap_err()
+        .into_iter()
+        .map(|error| error.kind)
+        .collect();
+    assert_eq!(
+        kinds,
+        [
+            CustomIsaErrorKind::Overlaps("no_op".to_owned()),
+            CustomIsaErrorKind::Semantics(RtlError {
+                message: "Unknown operand `s`".to_owned(),
+                span: 2..3
+            }),
+            CustomIsaErrorKind::InvalidOperandName("M".to_owned()),
+        ]
+    );
+}
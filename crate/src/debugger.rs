@@ -0,0 +1,419 @@
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::io::{self, BufRead, Write};
+
+use colored::*;
+
+use crate::disassembler::format_instr;
+use crate::fetch;
+use crate::history::History;
+use crate::instructions::Instr;
+use crate::machine::{Machine, StopReason};
+use crate::machine_code::{Ctx, Err, MEMORY_SIZE, PC, REGISTER_COUNT};
+
+/// The maximum number of instructions run by a single `continue`, so that infinite loops return control to the user.
+const CONTINUE_LIMIT: usize = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Watchpoint {
+    Register(u8),
+    Memory(PC),
+}
+
+impl Watchpoint {
+    fn read<const M: usize, const R: usize>(self, ctx: &Ctx<M, R>) -> u8 {
+        match self {
+            Watchpoint::Register(r) => ctx.registers[r as usize],
+            Watchpoint::Memory(addr) => ctx.memory[addr as usize],
+        }
+    }
+}
+
+impl Display for Watchpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Watchpoint::Register(r) => write!(f, "r{}", r),
+            Watchpoint::Memory(addr) => write!(f, "[{:02X}]", addr),
+        }
+    }
+}
+
+/// Why the debugger returned control to the user.
+#[derive(Debug, PartialEq)]
+pub enum Stop {
+    /// The requested number of instructions were run.
+    Stepped,
+    /// A watched register or memory cell changed value.
+    Watchpoint { watch: Watchpoint, old: u8, new: u8 },
+    /// The machine stopped at a breakpoint, halted, faulted, or `continue` ran for too long.
+    Stopped(StopReason),
+}
+
+impl Display for Stop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stop::Stepped => Ok(()),
+            Stop::Watchpoint { watch, old, new } => {
+                write!(
+                    f,
+                    "Watchpoint {} changed from {:02X} to {:02X}",
+                    watch, old, new
+                )
+            }
+            Stop::Stopped(StopReason::OutOfFuel) => write!(
+                f,
+                "Still running after {} instructions, stopping",
+                CONTINUE_LIMIT
+            ),
+            Stop::Stopped(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// Runs a program one instruction at a time, stopping at breakpoints and watchpoints.
+pub struct Debugger<const M: usize = MEMORY_SIZE, const R: usize = REGISTER_COUNT> {
+    pub machine: Machine<M, R>,
+    pub watchpoints: BTreeSet<Watchpoint>,
+}
+
+impl<const M: usize, const R: usize> Debugger<M, R> {
+    pub fn new(ctx: Ctx<M, R>) -> Self {
+        let mut machine = Machine::new(ctx);
+        machine.history = Some(History::default());
+        Self {
+            machine,
+            watchpoints: BTreeSet::new(),
+        }
+    }
+
+    /// The instruction which will run next.
+    pub fn next_instr(&self) -> Result<Instr, Err> {
+        fetch(&self.machine.ctx)
+    }
+
+    /// Runs up to `fuel` instructions, stopping early at a watchpoint.
+    fn run(&mut self, fuel: usize) -> Stop {
+        let before: Vec<_> = self
+            .watchpoints
+            .iter()
+            .map(|&watch| (watch, watch.read(&self.machine.ctx)))
+            .collect();
+        let mut triggered = None;
+        let reason = self.machine.run_until(fuel, |ctx| {
+            triggered = before.iter().find_map(|&(watch, old)| {
+                let new = watch.read(ctx);
+                (old != new).then_some(Stop::Watchpoint { watch, old, new })
+            });
+            triggered.is_some()
+        });
+        triggered.unwrap_or(Stop::Stopped(reason))
+    }
+
+    /// Runs up to `count` instructions, stopping early at a breakpoint, a watchpoint or the end of the program.
+    pub fn step(&mut self, count: usize) -> Stop {
+        match self.run(count) {
+            Stop::Stopped(StopReason::OutOfFuel) => Stop::Stepped,
+            stop => stop,
+        }
+    }
+
+    /// Undoes up to `count` instructions, returning how many were undone.
+    pub fn step_back(&mut self, count: usize) -> usize {
+        (0..count).take_while(|_| self.machine.step_back()).count()
+    }
+
+    /// Runs until a breakpoint, a watchpoint or the end of the program.
+    pub fn resume(&mut self) -> Stop {
+        self.run(CONTINUE_LIMIT)
+    }
+
+    /// Writes the registers as a table, four to a row, after the program counter and the status flags if the instruction set has them.
+    pub fn show_registers(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "PC: {:02X}", self.machine.ctx.pc)?;
+        if self.machine.ctx.isa.has_flags() {
+            writeln!(w, "Flags: {}", self.machine.ctx.flags)?;
+        }
+        for (i, values) in self.machine.ctx.registers.chunks(4).enumerate() {
+            for (j, value) in values.iter().enumerate() {
+                let name = format!("r{:<2}", i * 4 + j);
+                let watched = self
+                    .watchpoints
+                    .contains(&Watchpoint::Register((i * 4 + j) as u8));
+                let value = format!("{:02X}", value);
+                write!(
+                    w,
+                    "{}: {}  ",
+                    name.cyan(),
+                    if watched {
+                        value.yellow()
+                    } else {
+                        value.normal()
+                    }
+                )?;
+            }
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the memory as a hex dump, sixteen bytes to a row.
+    /// The program counter is highlighted, along with breakpoints and watched addresses.
+    pub fn show_memory(&self, w: &mut dyn Write) -> io::Result<()> {
+        write!(w, "    ")?;
+        for col in 0..16 {
+            write!(w, " {}", format!("{:2X}", col).dimmed())?;
+        }
+        writeln!(w)?;
+        let digits = if M > MEMORY_SIZE { 4 } else { 2 };
+        for (row, values) in self.machine.ctx.memory.chunks(16).enumerate() {
+            write!(w, "{}:", format!("{:0digits$X}", row * 16).dimmed())?;
+            for (col, value) in values.iter().enumerate() {
+                let address = (row * 16 + col) as PC;
+                let value = format!("{:02X}", value);
+                let value = if address == self.machine.ctx.pc
+                    || address == self.machine.ctx.pc.wrapping_add(1)
+                {
+                    value.reversed()
+                } else if self.machine.breakpoints.contains(&address) {
+                    value.red()
+                } else if self.watchpoints.contains(&Watchpoint::Memory(address)) {
+                    value.yellow()
+                } else {
+                    value.normal()
+                };
+                write!(w, " {}", value)?;
+            }
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the address, raw value and decoded form of the next instruction.
+    pub fn show_next(&self, w: &mut dyn Write) -> io::Result<()> {
+        let hi = self.machine.ctx.memory[self.machine.ctx.pc as usize];
+        let lo = self
+            .machine
+            .ctx
+            .memory
+            .get(self.machine.ctx.pc as usize + 1)
+            .copied()
+            .unwrap_or_default();
+        let text = match self.next_instr() {
+            Ok(instr) => format_instr(instr, &BTreeSet::new()),
+            Err(_) => format!(".byte {:#04X}, {:#04X}", hi, lo),
+        };
+        let marker = if self.machine.breakpoints.contains(&self.machine.ctx.pc) {
+            "*".red()
+        } else {
+            ">".normal()
+        };
+        writeln!(
+            w,
+            "{} {:02X}: {:04X}    {}",
+            marker,
+            self.machine.ctx.pc,
+            u16::from_be_bytes([hi, lo]),
+            text
+        )
+    }
+}
+
+const HELP: &str = "Commands:
+  s, step [n]          Run n instructions (default 1)
+  back [n]             Undo n instructions (default 1)
+  c, continue          Run until a breakpoint, watchpoint or the end of the program
+  b, break <addr>      Set a breakpoint at a hex address
+  d, delete <addr>     Remove the breakpoint at a hex address
+  w, watch <loc>       Watch a register (r0-r15) or a hex memory address
+  u, unwatch <loc>     Stop watching a register or memory address
+  l, list              List breakpoints and watchpoints
+  r, registers         Show the registers and flags
+  m, memory            Show the memory
+  q, quit              Exit the debugger
+  h, help              Show this message";
+
+/// Parses a hex address in `memory_size` bytes of memory.
+fn parse_address(arg: Option<&str>, memory_size: usize) -> Result<PC, String> {
+    let arg = arg.ok_or("Expected an address")?;
+    PC::from_str_radix(arg.trim_start_matches("0x"), 16)
+        .ok()
+        .filter(|&address| (address as usize) < memory_size)
+        .ok_or_else(|| format!("Invalid address `{}`", arg))
+}
+
+/// Parses a register name or a hex address, for a machine with `memory_size` bytes of memory and `registers` registers.
+fn parse_watchpoint(
+    arg: Option<&str>,
+    memory_size: usize,
+    registers: usize,
+) -> Result<Watchpoint, String> {
+    match arg {
+        Some(reg) if reg.starts_with(['r', 'R']) => reg[1..]
+            .parse()
+            .ok()
+            .filter(|&r| (r as usize) < registers)
+            .map(Watchpoint::Register)
+            .ok_or_else(|| format!("Invalid register `{}`", reg)),
+        _ => parse_address(arg, memory_size).map(Watchpoint::Memory),
+    }
+}
+
+impl<const M: usize, const R: usize> Debugger<M, R> {
+    /// Runs a single debugger command, returning `false` once the user asks to quit.
+    pub fn command(&mut self, line: &str, w: &mut dyn Write) -> io::Result<bool> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(true);
+        };
+        let arg = words.next();
+
+        let result = match command {
+            "s" | "step" => match arg.map(str::parse).unwrap_or(Ok(1)) {
+                Ok(count) => {
+                    let logged = self.machine.ctx.warnings.len();
+                    let stop = self.step(count);
+                    self.report(stop, logged, w)?;
+                    Ok(())
+                }
+                Err(_) => Err("Expected a number of steps".to_owned()),
+            },
+            "back" => match arg.map(str::parse).unwrap_or(Ok(1)) {
+                Ok(count) => {
+                    if self.step_back(count) < count {
+                        writeln!(w, "{}", "Reached the start of the program".yellow())?;
+                    }
+                    self.show_next(w)?;
+                    Ok(())
+                }
+                Err(_) => Err("Expected a number of steps".to_owned()),
+            },
+            "c" | "continue" => {
+                let logged = self.machine.ctx.warnings.len();
+                let stop = self.resume();
+                self.report(stop, logged, w)?;
+                Ok(())
+            }
+            "b" | "break" => parse_address(arg, M).map(|addr| {
+                self.machine.breakpoints.insert(addr);
+            }),
+            "d" | "delete" => parse_address(arg, M).map(|addr| {
+                self.machine.breakpoints.remove(&addr);
+            }),
+            "w" | "watch" => parse_watchpoint(arg, M, R).map(|watch| {
+                self.watchpoints.insert(watch);
+            }),
+            "u" | "unwatch" => parse_watchpoint(arg, M, R).map(|watch| {
+                self.watchpoints.remove(&watch);
+            }),
+            "l" | "list" => {
+                for addr in &self.machine.breakpoints {
+                    writeln!(w, "Breakpoint at {:02X}", addr)?;
+                }
+                for watch in &self.watchpoints {
+                    writeln!(w, "Watchpoint on {}", watch)?;
+                }
+                Ok(())
+            }
+            "r" | "registers" => self.show_registers(w).map_err(|e| e.to_string()),
+            "m" | "memory" => self.show_memory(w).map_err(|e| e.to_string()),
+            "q" | "quit" => return Ok(false),
+            "h" | "help" => writeln!(w, "{}", HELP).map_err(|e| e.to_string()),
+            _ => Err(format!("Unknown command `{}`, try `help`", command)),
+        };
+
+        if let Err(message) = result {
+            writeln!(w, "{}", message.red())?;
+        }
+        Ok(true)
+    }
+
+    /// Writes why the debugger stopped, after the warnings logged since the first `logged`.
+    fn report(&self, stop: Stop, logged: usize, w: &mut dyn Write) -> io::Result<()> {
+        for warning in &self.machine.ctx.warnings[logged..] {
+            writeln!(w, "{}", format!("Warning: {}", warning).yellow())?;
+        }
+        if stop != Stop::Stepped {
+            writeln!(w, "{}", stop.to_string().yellow())?;
+        }
+        if self.machine.stopped().is_none() {
+            self.show_next(w)?;
+        }
+        Ok(())
+    }
+
+    /// Reads commands until the input ends or the user quits.
+    pub fn repl(&mut self, input: &mut dyn BufRead, w: &mut dyn Write) -> io::Result<()> {
+        self.show_next(w)?;
+        loop {
+            write!(w, "(bmc) ")?;
+            w.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 || !self.command(&line, w)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn debugger_stops_at_breakpoints_and_watchpoints() {
+    let mut ctx: Ctx = Ctx::new([0; 256]);
+    // load_value r1, 1; add_integer r2, r2, r1; store_memory r2, 0x80; jump 0x02
+    ctx.memory[..8].copy_from_slice(&[0x21, 0x01, 0x52, 0x21, 0x32, 0x80, 0xB0, 0x02]);
+    let mut debugger = Debugger::new(ctx);
+
+    assert_eq!(debugger.step(1), Stop::Stepped);
+    debugger.machine.breakpoints.insert(0x06);
+    assert_eq!(
+        debugger.resume(),
+        Stop::Stopped(StopReason::Breakpoint(0x06))
+    );
+    assert_eq!(debugger.machine.ctx.registers[2], 1);
+
+    debugger.watchpoints.insert(Watchpoint::Memory(0x80));
+    assert_eq!(
+        debugger.resume(),
+        Stop::Watchpoint {
+            watch: Watchpoint::Memory(0x80),
+            old: 1,
+            new: 2
+        }
+    );
+    assert_eq!(debugger.machine.cycles, 6);
+
+    assert_eq!(debugger.step_back(4), 4);
+    assert_eq!(debugger.machine.ctx.pc, 0x04);
+    assert_eq!(debugger.machine.ctx.memory[0x80], 0);
+    assert_eq!(debugger.machine.ctx.registers[2], 1);
+
+    let mut w = Vec::new();
+    debugger.show_registers(&mut w).unwrap();
+    assert!(!String::from_utf8(w).unwrap().contains("Flags:"));
+    debugger.machine.ctx.isa = crate::instructions::IsaProfile::Arithmetic;
+    let mut w = Vec::new();
+    debugger.show_registers(&mut w).unwrap();
+    assert!(String::from_utf8(w).unwrap().contains("Flags: ----"));
+}
+
+#[cfg(test)]
+#[test]
+fn debugger_shows_new_warnings() {
+    use crate::instructions::DecodePolicy;
+
+    let mut ctx: Ctx = Ctx::new([0; 256]);
+    ctx.decode_policy = DecodePolicy::Warn;
+    // bitwise_rotate r2, 3 with a reserved nibble set; halt
+    ctx.memory[..4].copy_from_slice(&[0xA2, 0xF3, 0xC0, 0x00]);
+    let mut debugger = Debugger::new(ctx);
+
+    let mut w = Vec::new();
+    debugger.command("s", &mut w).unwrap();
+    assert!(String::from_utf8(w).unwrap().contains("at 0x00"));
+    let mut w = Vec::new();
+    debugger.command("s", &mut w).unwrap();
+    assert!(!String::from_utf8(w).unwrap().contains("Warning"));
+
+    assert_eq!(debugger.step_back(2), 2);
+    assert!(debugger.machine.ctx.warnings.is_empty());
+}
@@ -0,0 +1,221 @@
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+use crate::machine_code::PC;
+
+/// The address of the standard tick counter.
+pub const TICK_COUNTER_ADDRESS: PC = 0xFD;
+/// The address of the standard character input device.
+pub const KEYBOARD_IN_ADDRESS: PC = 0xFE;
+/// The address of the standard character output device.
+pub const CONSOLE_OUT_ADDRESS: PC = 0xFF;
+
+/// A device which is mapped to a single memory address.
+pub trait Device {
+    /// Called when a program loads from the device's address.
+    fn read(&mut self) -> u8;
+    /// Called when a program stores to the device's address.
+    fn write(&mut self, value: u8);
+    /// Called once after every instruction is executed.
+    fn tick(&mut self) {}
+    /// Undoes the most recent `read`, which returned `value`, when an instruction is stepped back.
+    fn unread(&mut self, _value: u8) {}
+    /// Undoes the most recent `write`, when an instruction is stepped back.
+    fn unwrite(&mut self) {}
+    /// Undoes the most recent `tick`, when an instruction is stepped back.
+    fn untick(&mut self) {}
+}
+
+/// Writes every byte stored to it. Reads as 0.
+/// Output which has been written can not be taken back, so stepping back over a store leaves it in place.
+pub struct ConsoleOut<W: Write> {
+    pub output: W,
+}
+
+impl<W: Write> Device for ConsoleOut<W> {
+    fn read(&mut self) -> u8 {
+        0
+    }
+
+    fn write(&mut self, value: u8) {
+        let _ = self.output.write_all(&[value]);
+        let _ = self.output.flush();
+    }
+}
+
+/// Reads the next byte of its input every time it is loaded from, or 0 once the input has ended. Stores are ignored.
+pub struct KeyboardIn<R: Read> {
+    pub input: R,
+    /// Bytes which were read by instructions that have been stepped back, to be read again first.
+    pub unread: Vec<u8>,
+}
+
+impl<R: Read> KeyboardIn<R> {
+    pub fn new(input: R) -> Self {
+        Self {
+            input,
+            unread: vec![],
+        }
+    }
+}
+
+impl<R: Read> Device for KeyboardIn<R> {
+    fn read(&mut self) -> u8 {
+        if let Some(byte) = self.unread.pop() {
+            return byte;
+        }
+        let mut byte = [0];
+        match self.input.read(&mut byte) {
+            Ok(1) => byte[0],
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, _value: u8) {}
+
+    fn unread(&mut self, value: u8) {
+        self.unread.push(value);
+    }
+}
+
+/// Counts the instructions executed, wrapping at 256. Storing to it sets the count.
+#[derive(Debug, Default)]
+pub struct TickCounter {
+    pub ticks: u8,
+    /// The counts overwritten by stores, so that they can be undone.
+    overwritten: Vec<u8>,
+}
+
+impl Device for TickCounter {
+    fn read(&mut self) -> u8 {
+        self.ticks
+    }
+
+    fn write(&mut self, value: u8) {
+        self.overwritten.push(self.ticks);
+        self.ticks = value;
+    }
+
+    fn tick(&mut self) {
+        self.ticks = self.ticks.wrapping_add(1);
+    }
+
+    fn unwrite(&mut self) {
+        if let Some(ticks) = self.overwritten.pop() {
+            self.ticks = ticks;
+        }
+    }
+
+    fn untick(&mut self) {
+        self.ticks = self.ticks.wrapping_sub(1);
+    }
+}
+
+/// Routes memory accesses at mapped addresses to devices instead of memory.
+/// Cloning the bus shares the devices between the clones.
+#[derive(Clone, Default)]
+pub struct DeviceBus {
+    mappings: Vec<(PC, Rc<RefCell<dyn Device>>)>,
+}
+
+impl Debug for DeviceBus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.mappings.iter().map(|(address, _)| address))
+            .finish()
+    }
+}
+
+impl DeviceBus {
+    /// Maps the standard tick counter, keyboard and console devices to the top of the first 256 bytes of memory.
+    pub fn standard(input: impl Read + 'static, output: impl Write + 'static) -> Self {
+        let mut bus = Self::default();
+        bus.map(TICK_COUNTER_ADDRESS, TickCounter::default());
+        bus.map(KEYBOARD_IN_ADDRESS, KeyboardIn::new(input));
+        bus.map(CONSOLE_OUT_ADDRESS, ConsoleOut { output });
+        bus
+    }
+
+    /// Maps a device to an address, replacing any device already there.
+    /// The returned handle can be used to inspect the device later.
+    pub fn map<D: Device + 'static>(&mut self, address: PC, device: D) -> Rc<RefCell<D>> {
+        let device = Rc::new(RefCell::new(device));
+        self.mappings.retain(|(mapped, _)| *mapped != address);
+        self.mappings.push((address, device.clone()));
+        device
+    }
+
+    fn find(&self, address: PC) -> Option<&Rc<RefCell<dyn Device>>> {
+        self.mappings
+            .iter()
+            .find(|(mapped, _)| *mapped == address)
+            .map(|(_, device)| device)
+    }
+
+    /// Reads from the device at the address, if there is one.
+    pub fn read(&mut self, address: PC) -> Option<u8> {
+        self.find(address).map(|device| device.borrow_mut().read())
+    }
+
+    /// Writes to the device at the address, returning `false` if there is none.
+    pub fn write(&mut self, address: PC, value: u8) -> bool {
+        self.find(address)
+            .map(|device| device.borrow_mut().write(value))
+            .is_some()
+    }
+
+    pub fn tick(&mut self) {
+        for (_, device) in &self.mappings {
+            device.borrow_mut().tick();
+        }
+    }
+
+    /// Undoes a read of the device at the address, which returned `value`.
+    pub fn unread(&mut self, address: PC, value: u8) {
+        if let Some(device) = self.find(address) {
+            device.borrow_mut().unread(value);
+        }
+    }
+
+    /// Undoes the most recent write to the device at the address.
+    pub fn unwrite(&mut self, address: PC) {
+        if let Some(device) = self.find(address) {
+            device.borrow_mut().unwrite();
+        }
+    }
+
+    /// Undoes the most recent tick of every device.
+    pub fn untick(&mut self) {
+        for (_, device) in &self.mappings {
+            device.borrow_mut().untick();
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn devices_work() {
+    use crate::execute;
+    use crate::machine_code::{Ctx, Err};
+
+    let mut ctx: Ctx = Ctx::new([0; 256]);
+    let console = ctx
+        .devices
+        .map(CONSOLE_OUT_ADDRESS, ConsoleOut { output: vec![] });
+    ctx.devices
+        .map(KEYBOARD_IN_ADDRESS, KeyboardIn::new(&b"hi"[..]));
+    ctx.devices
+        .map(TICK_COUNTER_ADDRESS, TickCounter::default());
+    // Echo the input until it ends, then store the tick count at 0x80
+    ctx.memory[..12].copy_from_slice(&[
+        0x11, 0xFE, 0xB1, 0x08, 0x31, 0xFF, 0xB0, 0x00, 0x12, 0xFD, 0x32, 0x80,
+    ]);
+    ctx.memory[12..14].copy_from_slice(&[0xC0, 0x00]);
+
+    assert_eq!(execute(&mut ctx, 64).1, Err(Err::HaltExecution));
+    assert_eq!(console.borrow().output, b"hi");
+    assert_eq!(ctx.memory[0xFF], 0);
+    assert_eq!(ctx.memory[0x80], 10);
+}
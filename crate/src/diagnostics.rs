@@ -0,0 +1,240 @@
+use std::io::{self, Write};
+use std::ops::Range;
+
+use ariadne::{Label, Report, ReportKind};
+use serde::Serialize;
+
+pub type Span = Range<usize>;
+pub type SourceId = usize;
+
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub name: String,
+    pub text: String,
+}
+
+/// Every file read while processing a program, so that diagnostics can be shown in the right one.
+#[derive(Debug, Clone, Default)]
+pub struct Sources {
+    pub files: Vec<SourceFile>,
+}
+
+impl Sources {
+    pub fn add(&mut self, name: impl Into<String>, text: impl Into<String>) -> SourceId {
+        self.files.push(SourceFile {
+            name: name.into(),
+            text: text.into(),
+        });
+        self.files.len() - 1
+    }
+
+    pub fn get(&self, id: SourceId) -> &SourceFile {
+        &self.files[id]
+    }
+
+    /// The 1-based line and column of a byte offset in a source, counting columns in characters.
+    pub fn position(&self, id: SourceId, offset: usize) -> Position {
+        let text = &self.get(id).text;
+        let before = &text[..offset.min(text.len())];
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        Position {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+/// A span in one of the sources.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub source: SourceId,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiagnosticLabel {
+    pub location: Location,
+    pub message: Option<String>,
+}
+
+/// A problem found in one of the sources, which can be shown to the user or written as JSON for editors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// The location the diagnostic is about, followed by any related locations.
+    pub labels: Vec<DiagnosticLabel>,
+    pub note: Option<String>,
+    pub help: Option<String>,
+}
+
+#[derive(Serialize)]
+struct JsonLabel<'a> {
+    file: &'a str,
+    start: Position,
+    end: Position,
+    /// The span in bytes from the start of the file.
+    span: &'a Span,
+    message: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct JsonDiagnostic<'a> {
+    severity: Severity,
+    message: &'a str,
+    labels: Vec<JsonLabel<'a>>,
+    note: Option<&'a str>,
+    help: Option<&'a str>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl ToString, location: Location) -> Self {
+        Self {
+            severity,
+            message: message.to_string(),
+            labels: vec![DiagnosticLabel {
+                location,
+                message: None,
+            }],
+            note: None,
+            help: None,
+        }
+    }
+
+    pub fn error(message: impl ToString, location: Location) -> Self {
+        Self::new(Severity::Error, message, location)
+    }
+
+    pub fn with_label(mut self, location: Location, message: impl ToString) -> Self {
+        self.labels.push(DiagnosticLabel {
+            location,
+            message: Some(message.to_string()),
+        });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl ToString) -> Self {
+        self.note = Some(note.to_string());
+        self
+    }
+
+    pub fn with_help(mut self, help: impl ToString) -> Self {
+        self.help = Some(help.to_string());
+        self
+    }
+
+    /// The location the diagnostic is about.
+    pub fn location(&self) -> &Location {
+        &self.labels[0].location
+    }
+
+    /// Writes the diagnostic as an ariadne report, quoting the sources it refers to.
+    pub fn write(&self, sources: &Sources, colored: bool, w: &mut dyn Write) -> io::Result<()> {
+        let name = |id: SourceId| sources.get(id).name.clone();
+        let kind = match self.severity {
+            Severity::Error => ReportKind::Error,
+            Severity::Warning => ReportKind::Warning,
+        };
+        let location = self.location();
+        let mut report = Report::build(kind, name(location.source), location.span.start)
+            .with_config(ariadne::Config::default().with_color(colored))
+            .with_message(&self.message);
+        for (order, label) in self.labels.iter().enumerate() {
+            let mut ariadne_label =
+                Label::new((name(label.location.source), label.location.span.clone()))
+                    .with_order(order as i32);
+            // Repeat the message on an unlabelled primary span, since ariadne only underlines labels with messages.
+            let message = label
+                .message
+                .as_ref()
+                .or((order == 0).then_some(&self.message));
+            if let Some(message) = message {
+                ariadne_label = ariadne_label.with_message(message);
+            }
+            report = report.with_label(ariadne_label);
+        }
+        if let Some(note) = &self.note {
+            report = report.with_note(note);
+        }
+        if let Some(help) = &self.help {
+            report = report.with_help(help);
+        }
+        let cache = ariadne::sources(
+            sources
+                .files
+                .iter()
+                .map(|file| (file.name.clone(), file.text.clone())),
+        );
+        report.finish().write(cache, w)
+    }
+
+    /// Writes the diagnostic as a single line of JSON, with 1-based line and column positions.
+    pub fn write_json(&self, sources: &Sources, w: &mut dyn Write) -> io::Result<()> {
+        let labels = self
+            .labels
+            .iter()
+            .map(|label| {
+                let Location { source, span } = &label.location;
+                JsonLabel {
+                    file: &sources.get(*source).name,
+                    start: sources.position(*source, span.start),
+                    end: sources.position(*source, span.end),
+                    span,
+                    message: label.message.as_deref(),
+                }
+            })
+            .collect();
+        let json = JsonDiagnostic {
+            severity: self.severity,
+            message: &self.message,
+            labels,
+            note: self.note.as_deref(),
+            help: self.help.as_deref(),
+        };
+        serde_json::to_writer(&mut *w, &json)?;
+        writeln!(w)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn diagnostics_write_json() {
+    let mut sources = Sources::default();
+    let id = sources.add("main.s", "halt\n  jump nowhere\n");
+    let diagnostic = Diagnostic::error(
+        "Label `nowhere` is not defined",
+        Location {
+            source: id,
+            span: 12..19,
+        },
+    )
+    .with_help("Define it with `nowhere:`");
+
+    let mut w = Vec::new();
+    diagnostic.write_json(&sources, &mut w).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&w).unwrap();
+    assert_eq!(json["severity"], "error");
+    assert_eq!(json["labels"][0]["file"], "main.s");
+    assert_eq!(json["labels"][0]["start"]["line"], 2);
+    assert_eq!(json["labels"][0]["start"]["column"], 8);
+    assert_eq!(json["labels"][0]["end"]["column"], 15);
+
+    let mut w = Vec::new();
+    diagnostic.write(&sources, false, &mut w).unwrap();
+    let text = String::from_utf8(w).unwrap();
+    assert!(text.contains("main.s:2:8"));
+    assert!(text.contains("Define it with `nowhere:`"));
+}
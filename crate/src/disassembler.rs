@@ -0,0 +1,92 @@
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+
+use crate::instructions::{Instr, IsaProfile, OperandKind};
+use crate::machine_code::MachineMemory;
+
+const INSTRUCTION_SIZE: usize = 2;
+
+/// Finds every jump target which lies on an instruction boundary within the first `len` bytes.
+fn jump_targets(memory: &MachineMemory, len: usize, isa: &IsaProfile) -> BTreeSet<u8> {
+    memory[..len]
+        .chunks(INSTRUCTION_SIZE)
+        .filter_map(|word| isa.decode(u16::from_be_bytes([word[0], word[1]])).ok())
+        .filter_map(|instr| match instr {
+            Instr::Jump(xy) | Instr::JumpIfEq(_, xy) => Some(xy),
+            _ => None,
+        })
+        .filter(|&xy| (xy as usize) < len && (xy as usize).is_multiple_of(INSTRUCTION_SIZE))
+        .collect()
+}
+
+fn label_name(address: u8) -> String {
+    format!("label_{:02X}", address)
+}
+
+/// Renders a single instruction as assembly, using a label for jump targets where one exists.
+pub fn format_instr(instr: Instr, labels: &BTreeSet<u8>) -> String {
+    let is_jump = matches!(instr, Instr::Jump(..) | Instr::JumpIfEq(..));
+    let operands: Vec<String> = instr
+        .spec()
+        .operands
+        .iter()
+        .zip(instr.operands())
+        .map(|(spec, value)| match spec.kind {
+            OperandKind::Register => format!("r{}", value),
+            OperandKind::Address if is_jump && labels.contains(&value) => label_name(value),
+            OperandKind::Address | OperandKind::Immediate => format!("{:#04X}", value),
+        })
+        .collect();
+    if operands.is_empty() {
+        instr.mnemonic().to_owned()
+    } else {
+        format!("{} {}", instr.mnemonic(), operands.join(", "))
+    }
+}
+
+/// Writes a listing of the memory, with the address, raw value and decoded instruction of each word.
+/// Words which do not decode to an instruction of `isa` are shown as `.byte` data, and trailing zeroed memory is omitted.
+pub fn disassemble(memory: &MachineMemory, isa: &IsaProfile, w: &mut dyn Write) -> io::Result<()> {
+    let len = memory
+        .iter()
+        .rposition(|&b| b != 0)
+        .map_or(0, |last| (last / INSTRUCTION_SIZE + 1) * INSTRUCTION_SIZE);
+    let labels = jump_targets(memory, len, isa);
+
+    for (i, word) in memory[..len].chunks(INSTRUCTION_SIZE).enumerate() {
+        let address = (i * INSTRUCTION_SIZE) as u8;
+        if labels.contains(&address) {
+            writeln!(w, "{}:", label_name(address))?;
+        }
+        let raw = u16::from_be_bytes([word[0], word[1]]);
+        let text = match isa.decode(raw) {
+            Ok(instr) => format_instr(instr, &labels),
+            Err(_) => format!(".byte {:#04X}, {:#04X}", word[0], word[1]),
+        };
+        writeln!(w, "{:02X}: {:04X}    {}", address, raw, text)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn disassemble_works() {
+    let mut memory: MachineMemory = [0; 256];
+    memory[..12].copy_from_slice(&[
+        0x21, 0x01, 0x52, 0x21, 0xB2, 0x0A, 0xB0, 0x02, 0x00, 0x42, 0xC0, 0x00,
+    ]);
+    let mut w = Vec::new();
+    disassemble(&memory, &IsaProfile::Classic, &mut w).unwrap();
+    assert_eq!(
+        String::from_utf8(w).unwrap(),
+        "00: 2101    load_value r1, 0x01
+label_02:
+02: 5221    add_integer r2, r2, r1
+04: B20A    jump_if_eq r2, label_0A
+06: B002    jump label_02
+08: 0042    .byte 0x00, 0x42
+label_0A:
+0A: C000    halt
+"
+    );
+}
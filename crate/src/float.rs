@@ -0,0 +1,153 @@
+use serde::Deserialize;
+
+use crate::machine_code::Err;
+
+/// The largest magnitude, 7.5 in excess-4 notation.
+pub const MAX: u8 = 0x7F;
+/// The excess of the exponent in the textbook.
+pub const EXCESS: u8 = 4;
+/// The largest excess which a 3 bit exponent can have.
+pub const MAX_EXCESS: u8 = 7;
+const SIGN_BIT: u8 = 0x80;
+const MANTISSA_BITS: i32 = 4;
+
+/// Converts an 8-bit float to an `f32`. Every value can be represented exactly.
+/// The format is `SEEEMMMM`: a sign bit with 1 as negative, a 3 bit exponent in excess notation, which is excess-4 in the textbook,
+/// and a 4 bit mantissa with the radix point to its left, so the value is `±0.MMMM × 2^(EEE - excess)`.
+pub fn decode(bits: u8, excess: u8) -> f32 {
+    let exponent = ((bits >> 4) & 0x7) as i32;
+    let mantissa = (bits & 0xF) as f32;
+    let magnitude = mantissa * 2f32.powi(exponent - excess as i32 - MANTISSA_BITS);
+    if bits & SIGN_BIT != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+/// How a value which falls between two 8-bit floats is converted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rounding {
+    /// To the nearest value, with ties going to the even mantissa.
+    Nearest,
+    /// Towards zero, dropping the bits which do not fit in the mantissa, as in the textbook's truncation errors.
+    Truncate,
+}
+
+/// Converts an `f32` to an 8-bit float, rounding it if it falls between two values.
+/// Values too small to represent become zero.
+/// Values too large to represent, and NaN, give [Err::FloatingPointSaturated].
+pub fn encode(value: f32, rounding: Rounding, excess: u8) -> Result<u8, Err> {
+    if value.is_nan() {
+        return Err(Err::FloatingPointSaturated);
+    }
+    let sign = if value.is_sign_negative() {
+        SIGN_BIT
+    } else {
+        0
+    };
+    let magnitude = value.abs();
+    // Use the smallest exponent which fits the rounded mantissa, which keeps it normalised.
+    // Only the smallest exponent can have a mantissa without its top bit set.
+    for exponent in 0..8 {
+        let scaled = magnitude * 2f32.powi(excess as i32 + MANTISSA_BITS - exponent);
+        let mantissa = match rounding {
+            Rounding::Nearest => scaled.round_ties_even(),
+            Rounding::Truncate => scaled.trunc(),
+        };
+        if mantissa < 16.0 {
+            if mantissa == 0.0 {
+                return Ok(0);
+            }
+            return Ok(sign | (exponent as u8) << 4 | mantissa as u8);
+        }
+    }
+    Err(Err::FloatingPointSaturated)
+}
+
+/// Adds two 8-bit floats, rounding the exact sum.
+/// Saturated results are reported along with the largest value of the right sign.
+pub fn add(s: u8, t: u8, rounding: Rounding, excess: u8) -> Result<u8, (Err, u8)> {
+    let sum = decode(s, excess) + decode(t, excess);
+    encode(sum, rounding, excess).map_err(|e| {
+        let sign = if sum.is_sign_negative() { SIGN_BIT } else { 0 };
+        (e, sign | MAX)
+    })
+}
+
+#[cfg(test)]
+#[test]
+fn encode_round_trips() {
+    for bits in 0..=u8::MAX {
+        let value = decode(bits, EXCESS);
+        assert_eq!(
+            decode(encode(value, Rounding::Nearest, EXCESS).unwrap(), EXCESS),
+            value,
+            "{:08b}",
+            bits
+        );
+    }
+    assert_eq!(encode(1.0, Rounding::Nearest, EXCESS), Ok(0b0101_1000));
+    assert_eq!(encode(-2.25, Rounding::Nearest, EXCESS), Ok(0b1110_1001));
+    assert_eq!(
+        encode(7.75, Rounding::Nearest, EXCESS),
+        Err(Err::FloatingPointSaturated)
+    );
+    assert_eq!(encode(1.0 / 512.0, Rounding::Nearest, EXCESS), Ok(0));
+    assert_eq!(encode(2.625, Rounding::Truncate, EXCESS), Ok(0b0110_1010));
+    assert_eq!(encode(7.75, Rounding::Truncate, EXCESS), Ok(MAX));
+    assert_eq!(encode(1.0, Rounding::Nearest, 3), Ok(0b0100_1000));
+    assert_eq!(decode(MAX, 3), 15.0);
+}
+
+#[cfg(test)]
+#[test]
+fn add_matches_reference() {
+    // Every magnitude in its most precise encoding, plus the first value past the largest, which rounds to saturation.
+    let mut candidates: Vec<(f64, u32)> = (0..8)
+        .flat_map(|exponent| {
+            let mantissas = if exponent == 0 { 0..16 } else { 8..16 };
+            mantissas.map(move |mantissa| (mantissa as f64 * 2f64.powi(exponent - 8), mantissa))
+        })
+        .collect();
+    candidates.push((8.0, 16));
+
+    for s in 0..=u8::MAX {
+        for t in 0..=u8::MAX {
+            let exact = decode(s, EXCESS) as f64 + decode(t, EXCESS) as f64;
+            let (nearest, _) = candidates
+                .iter()
+                .copied()
+                .min_by(|(a, a_mantissa), (b, b_mantissa)| {
+                    let (a_distance, b_distance) =
+                        ((a - exact.abs()).abs(), (b - exact.abs()).abs());
+                    a_distance
+                        .total_cmp(&b_distance)
+                        .then((a_mantissa % 2).cmp(&(b_mantissa % 2)))
+                })
+                .unwrap();
+
+            match add(s, t, Rounding::Nearest, EXCESS) {
+                Ok(bits) => {
+                    assert!(nearest < 8.0, "{:08b} + {:08b} should saturate", s, t);
+                    assert_eq!(
+                        decode(bits, EXCESS).abs() as f64,
+                        nearest,
+                        "{:08b} + {:08b}",
+                        s,
+                        t
+                    );
+                    if nearest != 0.0 {
+                        assert_eq!(decode(bits, EXCESS) < 0.0, exact < 0.0);
+                    }
+                }
+                Err((e, bits)) => {
+                    assert_eq!(e, Err::FloatingPointSaturated);
+                    assert_eq!(nearest, 8.0, "{:08b} + {:08b} should not saturate", s, t);
+                    assert_eq!(decode(bits, EXCESS), 7.5f32.copysign(exact as f32));
+                }
+            }
+        }
+    }
+}
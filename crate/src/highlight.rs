@@ -1,36 +1,583 @@
-use colored::*;
-use std::io::Write;
+use std::collections::BTreeSet;
+use std::io::{self, Write};
 
+use crate::diagnostics::{Diagnostic, Location, SourceId, Sources};
+use crate::disassembler::format_instr;
+use crate::instructions::IsaProfile;
 use crate::lexer::Token;
-use ariadne::{Label, Report, ReportKind, Source};
+use crate::memory::{memory_file_tokens, read_memory_file, MemoryFileOptions, MemoryTokenKind};
 use logos::Logos;
 
-pub fn highlight(source: &str, mut w: &mut dyn std::io::Write) {
-    let lex = Token::lexer(source).spanned();
-    for (token, span) in lex {
-        let _ = match token {
-            Ok(Token::LineComment) => write!(&mut w, "{}", source[span].dimmed()),
-            Ok(Token::BlockComment) => write!(&mut w, "{}", source[span].dimmed()),
-            Ok(Token::Integer) => write!(&mut w, "{}", source[span].blue()),
-            Ok(Token::HexInteger) => write!(&mut w, "{}", source[span].blue()),
-            Ok(Token::OctalInteger) => write!(&mut w, "{}", source[span].blue()),
-            Ok(Token::BinaryInteger) => write!(&mut w, "{}", source[span].blue()),
-            Ok(Token::Float) => write!(&mut w, "{}", source[span].blue()),
-            Ok(Token::Directive) => write!(&mut w, "{}", source[span].yellow()),
-            Ok(Token::Label) => write!(&mut w, "{}", source[span].green()),
-            Ok(Token::Identifier) => write!(&mut w, "{}", source[span].cyan()),
-            Ok(Token::DotSymbol) => write!(&mut w, "{}", source[span].cyan()),
-            Ok(_) => write!(&mut w, "{}", &source[span]),
+/// The kinds of token which are highlighted differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenClass {
+    Comment,
+    Number,
+    Directive,
+    Label,
+    Identifier,
+    String,
+    /// Text which could not be lexed.
+    Unknown,
+    /// The address at the start of a line of a memory file.
+    Address,
+    /// The first nibble of an instruction in a memory file, which selects the instruction.
+    Opcode,
+    /// The other nibbles of an instruction in a memory file.
+    Operand,
+}
+
+impl TokenClass {
+    /// The name used for the class in HTML and LaTeX output.
+    pub fn name(self) -> &'static str {
+        match self {
+            TokenClass::Comment => "comment",
+            TokenClass::Number => "number",
+            TokenClass::Directive => "directive",
+            TokenClass::Label => "label",
+            TokenClass::Identifier => "identifier",
+            TokenClass::String => "string",
+            TokenClass::Unknown => "unknown",
+            TokenClass::Address => "address",
+            TokenClass::Opcode => "opcode",
+            TokenClass::Operand => "operand",
+        }
+    }
+}
+
+/// How a token is highlighted, or `None` for whitespace and punctuation.
+pub fn classify(token: Token) -> Option<TokenClass> {
+    match token {
+        Token::LineComment | Token::BlockComment => Some(TokenClass::Comment),
+        Token::Integer
+        | Token::HexInteger
+        | Token::OctalInteger
+        | Token::BinaryInteger
+        | Token::Float => Some(TokenClass::Number),
+        Token::Directive => Some(TokenClass::Directive),
+        Token::Label => Some(TokenClass::Label),
+        Token::Identifier | Token::DotSymbol => Some(TokenClass::Identifier),
+        Token::String => Some(TokenClass::String),
+        Token::NewLine | Token::WhiteSpace | Token::Comma => None,
+    }
+}
+
+/// A colour as red, green and blue components.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+impl Rgb {
+    /// The nearest colour in the 6×6×6 cube of the ANSI 256 colour palette.
+    pub fn ansi256(self) -> u8 {
+        let level = |c: u8| ((c as u16 * 5 + 127) / 255) as u8;
+        16 + 36 * level(self.0) + 6 * level(self.1) + level(self.2)
+    }
+
+    /// The colour as six hex digits, such as `1E90FF`.
+    pub fn hex(self) -> String {
+        format!("{:02X}{:02X}{:02X}", self.0, self.1, self.2)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Style {
+    pub color: Option<Rgb>,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+}
+
+impl Style {
+    pub const fn color(color: Rgb) -> Self {
+        Self {
+            color: Some(color),
+            bold: false,
+            italic: false,
+            underline: false,
+        }
+    }
+}
+
+/// The style of each class of token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Theme {
+    pub comment: Style,
+    pub number: Style,
+    pub directive: Style,
+    pub label: Style,
+    pub identifier: Style,
+    pub string: Style,
+    pub unknown: Style,
+    pub address: Style,
+    pub opcode: Style,
+    pub operand: Style,
+}
+
+impl Theme {
+    pub fn style(&self, class: TokenClass) -> Style {
+        match class {
+            TokenClass::Comment => self.comment,
+            TokenClass::Number => self.number,
+            TokenClass::Directive => self.directive,
+            TokenClass::Label => self.label,
+            TokenClass::Identifier => self.identifier,
+            TokenClass::String => self.string,
+            TokenClass::Unknown => self.unknown,
+            TokenClass::Address => self.address,
+            TokenClass::Opcode => self.opcode,
+            TokenClass::Operand => self.operand,
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            comment: Style {
+                italic: true,
+                ..Style::color(Rgb(0x80, 0x80, 0x80))
+            },
+            number: Style::color(Rgb(0x1E, 0x66, 0xF5)),
+            directive: Style::color(Rgb(0xB0, 0x8A, 0x00)),
+            label: Style {
+                bold: true,
+                ..Style::color(Rgb(0x2E, 0x9E, 0x44))
+            },
+            identifier: Style::color(Rgb(0x00, 0x97, 0xA7)),
+            string: Style::color(Rgb(0xB0, 0x3A, 0xB5)),
+            unknown: Style {
+                underline: true,
+                ..Style::color(Rgb(0xD2, 0x0F, 0x39))
+            },
+            address: Style::color(Rgb(0x2E, 0x9E, 0x44)),
+            opcode: Style {
+                bold: true,
+                ..Style::color(Rgb(0x00, 0x97, 0xA7))
+            },
+            operand: Style::color(Rgb(0x1E, 0x66, 0xF5)),
+        }
+    }
+}
+
+/// Writes highlighted source in some output format.
+pub trait Renderer {
+    /// Writes anything needed before the first token.
+    fn begin(&mut self, _w: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Writes a token, which is highlighted if it has a class.
+    fn token(&mut self, class: Option<TokenClass>, text: &str, w: &mut dyn Write)
+        -> io::Result<()>;
+
+    /// Writes anything needed after the last token.
+    fn end(&mut self, _w: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes ANSI escape codes for terminals, using the 256 colour palette.
+#[derive(Debug, Clone, Default)]
+pub struct AnsiRenderer {
+    pub theme: Theme,
+}
+
+impl Renderer for AnsiRenderer {
+    fn token(
+        &mut self,
+        class: Option<TokenClass>,
+        text: &str,
+        w: &mut dyn Write,
+    ) -> io::Result<()> {
+        let Some(class) = class else {
+            return write!(w, "{}", text);
+        };
+        let style = self.theme.style(class);
+        let mut codes = vec![];
+        if style.bold {
+            codes.push("1".to_owned());
+        }
+        if style.italic {
+            codes.push("3".to_owned());
+        }
+        if style.underline {
+            codes.push("4".to_owned());
+        }
+        if let Some(color) = style.color {
+            codes.push(format!("38;5;{}", color.ansi256()));
+        }
+        if codes.is_empty() {
+            write!(w, "{}", text)
+        } else {
+            write!(w, "\x1b[{}m{}\x1b[0m", codes.join(";"), text)
+        }
+    }
+}
+
+/// Writes a `<pre>` element with a `<span>` for each highlighted token, whose class is `bmc-` followed by the [TokenClass::name].
+#[derive(Debug, Clone, Default)]
+pub struct HtmlRenderer {
+    pub theme: Theme,
+    /// Whether to write a `<style>` element with the theme before the listing.
+    pub stylesheet: bool,
+}
+
+impl HtmlRenderer {
+    /// CSS rules styling the classes of the listing with the theme.
+    pub fn stylesheet(theme: &Theme) -> String {
+        let mut css = String::new();
+        for class in CLASSES {
+            let style = theme.style(class);
+            let mut rules = vec![];
+            if let Some(color) = style.color {
+                rules.push(format!("color: #{}", color.hex()));
+            }
+            if style.bold {
+                rules.push("font-weight: bold".to_owned());
+            }
+            if style.italic {
+                rules.push("font-style: italic".to_owned());
+            }
+            if style.underline {
+                rules.push("text-decoration: underline wavy".to_owned());
+            }
+            css += &format!(".bmc .bmc-{} {{ {}; }}\n", class.name(), rules.join("; "));
+        }
+        css
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl Renderer for HtmlRenderer {
+    fn begin(&mut self, w: &mut dyn Write) -> io::Result<()> {
+        if self.stylesheet {
+            writeln!(
+                w,
+                "<style>\n{}</style>",
+                HtmlRenderer::stylesheet(&self.theme)
+            )?;
+        }
+        write!(w, "<pre class=\"bmc\"><code>")
+    }
+
+    fn token(
+        &mut self,
+        class: Option<TokenClass>,
+        text: &str,
+        w: &mut dyn Write,
+    ) -> io::Result<()> {
+        match class {
+            Some(class) => write!(
+                w,
+                "<span class=\"bmc-{}\">{}</span>",
+                class.name(),
+                escape_html(text)
+            ),
+            None => write!(w, "{}", escape_html(text)),
+        }
+    }
+
+    fn end(&mut self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "</code></pre>")
+    }
+}
+
+/// Writes a `Verbatim` environment from `fancyvrb`, in the style of `minted`, with a command for each class of token.
+/// The commands are named `\BMC` followed by the [TokenClass::name], and use colours from `xcolor`.
+#[derive(Debug, Clone, Default)]
+pub struct LatexRenderer {
+    pub theme: Theme,
+    /// Whether to define the commands before the listing.
+    pub preamble: bool,
+    /// Whether the last text written was in the middle of a line.
+    mid_line: bool,
+}
+
+impl LatexRenderer {
+    pub fn new(theme: Theme, preamble: bool) -> Self {
+        Self {
+            theme,
+            preamble,
+            mid_line: false,
+        }
+    }
+
+    /// Definitions of the commands used in the listing, and of `\BMCbs`, `\BMCob` and `\BMCcb` for literal `\`, `{` and `}`.
+    pub fn preamble(theme: &Theme) -> String {
+        let mut tex = String::from(
+            "\\def\\BMCbs{\\char`\\\\}\n\\def\\BMCob{\\char`\\{}\n\\def\\BMCcb{\\char`\\}}\n",
+        );
+        for class in CLASSES {
+            let style = theme.style(class);
+            let mut body = "#1".to_owned();
+            if let Some(color) = style.color {
+                body = format!("\\textcolor[HTML]{{{}}}{{{}}}", color.hex(), body);
+            }
+            if style.bold {
+                body = format!("\\textbf{{{}}}", body);
+            }
+            if style.italic {
+                body = format!("\\textit{{{}}}", body);
+            }
+            if style.underline {
+                body = format!("\\underline{{{}}}", body);
+            }
+            tex += &format!("\\newcommand{{\\BMC{}}}[1]{{{}}}\n", class.name(), body);
+        }
+        tex
+    }
+}
+
+fn escape_latex(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\\' => "\\BMCbs{}".to_owned(),
+            '{' => "\\BMCob{}".to_owned(),
+            '}' => "\\BMCcb{}".to_owned(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+impl Renderer for LatexRenderer {
+    fn begin(&mut self, w: &mut dyn Write) -> io::Result<()> {
+        if self.preamble {
+            write!(w, "{}", LatexRenderer::preamble(&self.theme))?;
+        }
+        writeln!(w, "\\begin{{Verbatim}}[commandchars=\\\\\\{{\\}}]")
+    }
+
+    fn token(
+        &mut self,
+        class: Option<TokenClass>,
+        text: &str,
+        w: &mut dyn Write,
+    ) -> io::Result<()> {
+        if !text.is_empty() {
+            self.mid_line = !text.ends_with('\n');
+        }
+        let Some(class) = class else {
+            return write!(w, "{}", escape_latex(text));
+        };
+        // Commands can not span lines in a Verbatim environment, so each line gets its own.
+        let lines: Vec<&str> = text.split('\n').collect();
+        for (i, line) in lines.iter().enumerate() {
+            if i > 0 {
+                writeln!(w)?;
+            }
+            if !line.is_empty() {
+                write!(w, "\\BMC{}{{{}}}", class.name(), escape_latex(line))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn end(&mut self, w: &mut dyn Write) -> io::Result<()> {
+        if self.mid_line {
+            writeln!(w)?;
+        }
+        writeln!(w, "\\end{{Verbatim}}")
+    }
+}
+
+const CLASSES: [TokenClass; 10] = [
+    TokenClass::Comment,
+    TokenClass::Number,
+    TokenClass::Directive,
+    TokenClass::Label,
+    TokenClass::Identifier,
+    TokenClass::String,
+    TokenClass::Unknown,
+    TokenClass::Address,
+    TokenClass::Opcode,
+    TokenClass::Operand,
+];
+
+/// Writes a source with its tokens highlighted by the renderer, returning a diagnostic for every unknown token.
+pub fn highlight(
+    sources: &Sources,
+    id: SourceId,
+    renderer: &mut dyn Renderer,
+    w: &mut dyn Write,
+) -> io::Result<Vec<Diagnostic>> {
+    let source = sources.get(id).text.as_str();
+    let mut diagnostics = vec![];
+    renderer.begin(w)?;
+    for (token, span) in Token::lexer(source).spanned() {
+        let text = &source[span.clone()];
+        let class = match token {
+            Ok(token) => classify(token),
             Err(_) => {
-                // let source_file = file.map(|f| f.as_str()).unwrap_or("input");
-                Report::build(ReportKind::Error, (), span.start)
-                    .with_message("Unknown token!")
-                    .with_label(Label::new(span))
-                    .finish()
-                    .print(Source::from(&source))
-                    .unwrap();
-                Ok(())
+                diagnostics.push(Diagnostic::error(
+                    "Unknown token",
+                    Location { source: id, span },
+                ));
+                Some(TokenClass::Unknown)
             }
         };
+        renderer.token(class, text, w)?;
+    }
+    renderer.end(w)?;
+    Ok(diagnostics)
+}
+
+/// Writes a memory file with its addresses, opcode nibbles and operand nibbles highlighted by the renderer, returning a diagnostic for every error in the file.
+/// Every instruction which starts and ends on the same line is decoded with `isa`, and shown in a comment at the end of the line.
+pub fn highlight_memory(
+    sources: &Sources,
+    id: SourceId,
+    isa: &IsaProfile,
+    renderer: &mut dyn Renderer,
+    w: &mut dyn Write,
+) -> io::Result<Vec<Diagnostic>> {
+    let source = sources.get(id).text.as_str();
+    let (tokens, _) = memory_file_tokens(source);
+    let mut tokens = tokens.into_iter().peekable();
+
+    renderer.begin(w)?;
+    let mut written = 0;
+    for line in source.split_inclusive('\n') {
+        let content_end = written + line.trim_end_matches(['\r', '\n']).len();
+        let line_end = written + line.len();
+        let mut line_bytes: Option<(usize, Vec<u8>)> = None;
+        let mut annotated = false;
+        while let Some(token) = tokens.next_if(|token| token.span.start < content_end) {
+            if token.kind == MemoryTokenKind::Comment {
+                annotate(line_bytes.take(), isa, renderer, w)?;
+                annotated = true;
+            }
+            renderer.token(None, &source[written..token.span.start], w)?;
+            let text = &source[token.span.clone()];
+            match &token.kind {
+                MemoryTokenKind::Address(_) => {
+                    renderer.token(Some(TokenClass::Address), text, w)?;
+                }
+                MemoryTokenKind::Bytes { address, bytes } => {
+                    let (prefix, digits) = text.split_at(text.len() - 2 * bytes.len());
+                    renderer.token(None, prefix, w)?;
+                    for (i, pair) in digits.as_bytes().chunks(2).enumerate() {
+                        let pair = std::str::from_utf8(pair).unwrap();
+                        if (address + i).is_multiple_of(2) {
+                            renderer.token(Some(TokenClass::Opcode), &pair[..1], w)?;
+                            renderer.token(Some(TokenClass::Operand), &pair[1..], w)?;
+                        } else {
+                            renderer.token(Some(TokenClass::Operand), pair, w)?;
+                        }
+                    }
+                    line_bytes
+                        .get_or_insert_with(|| (*address, vec![]))
+                        .1
+                        .extend(bytes);
+                }
+                MemoryTokenKind::Comment => renderer.token(Some(TokenClass::Comment), text, w)?,
+                MemoryTokenKind::Invalid => renderer.token(Some(TokenClass::Unknown), text, w)?,
+            }
+            written = token.span.end;
+        }
+        if !annotated {
+            renderer.token(None, &source[written..content_end], w)?;
+            written = content_end;
+            annotate(line_bytes, isa, renderer, w)?;
+        }
+        renderer.token(None, &source[written..line_end], w)?;
+        written = line_end;
+    }
+    renderer.end(w)?;
+
+    let options = MemoryFileOptions {
+        memory_size: isa.memory_size(),
+        ..Default::default()
+    };
+    Ok(read_memory_file(source, options)
+        .err()
+        .unwrap_or_default()
+        .iter()
+        .map(|error| error.diagnostic(id))
+        .collect())
+}
+
+/// Writes a comment with the instructions in the bytes written by a line of a memory file, starting from an address.
+fn annotate(
+    line_bytes: Option<(usize, Vec<u8>)>,
+    isa: &IsaProfile,
+    renderer: &mut dyn Renderer,
+    w: &mut dyn Write,
+) -> io::Result<()> {
+    let Some((address, bytes)) = line_bytes else {
+        return Ok(());
+    };
+    let annotations: Vec<String> = bytes[(address % 2).min(bytes.len())..]
+        .chunks_exact(2)
+        .filter_map(|word| isa.decode(u16::from_be_bytes([word[0], word[1]])).ok())
+        .map(|instr| format_instr(instr, &BTreeSet::new()))
+        .collect();
+    if !annotations.is_empty() {
+        renderer.token(None, " ", w)?;
+        let annotation = format!("// {}", annotations.join("; "));
+        renderer.token(Some(TokenClass::Comment), &annotation, w)?;
     }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn renderers_escape_and_classify() {
+    let mut sources = Sources::default();
+    let id = sources.add("", "loop: jump loop // a<b\n$");
+
+    let mut w = Vec::new();
+    let diagnostics = highlight(&sources, id, &mut HtmlRenderer::default(), &mut w).unwrap();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+        String::from_utf8(w).unwrap(),
+        "<pre class=\"bmc\"><code><span class=\"bmc-label\">loop:</span> \
+         <span class=\"bmc-identifier\">jump</span> <span class=\"bmc-identifier\">loop</span> \
+         <span class=\"bmc-comment\">// a&lt;b</span>\n<span class=\"bmc-unknown\">$</span></code></pre>\n"
+    );
+
+    let id = sources.add("", "/* {x}\n\\ */");
+    let mut w = Vec::new();
+    highlight(&sources, id, &mut LatexRenderer::default(), &mut w).unwrap();
+    assert_eq!(
+        String::from_utf8(w).unwrap(),
+        "\\begin{Verbatim}[commandchars=\\\\\\{\\}]\n\\BMCcomment{/* \\BMCob{}x\\BMCcb{}}\n\\BMCcomment{\\BMCbs{} */}\n\\end{Verbatim}\n"
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn highlight_memory_annotates_instructions() {
+    let mut w = Vec::new();
+    let mut sources = Sources::default();
+    let id = sources.add("", "00 2103\n02 C0 0x00 ; end\n05 zz 11\n");
+    let diagnostics = highlight_memory(
+        &sources,
+        id,
+        &IsaProfile::Classic,
+        &mut HtmlRenderer::default(),
+        &mut w,
+    )
+    .unwrap();
+    let html = String::from_utf8(w).unwrap();
+    let lines: Vec<&str> = html.lines().collect();
+    assert_eq!(
+        lines[0],
+        "<pre class=\"bmc\"><code><span class=\"bmc-address\">00</span> \
+         <span class=\"bmc-opcode\">2</span><span class=\"bmc-operand\">1</span><span class=\"bmc-operand\">03</span> \
+         <span class=\"bmc-comment\">// load_value r1, 0x03</span>"
+    );
+    assert!(lines[1].contains("0x<span class=\"bmc-operand\">00</span>"));
+    assert!(lines[1].ends_with(
+        "<span class=\"bmc-comment\">// halt</span> <span class=\"bmc-comment\">; end</span>"
+    ));
+    assert!(lines[2].contains("<span class=\"bmc-unknown\">zz</span>"));
+    assert!(lines[2].contains("<span class=\"bmc-operand\">11</span>"));
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].labels[0].location.span, 28..30);
 }
@@ -0,0 +1,93 @@
+use crate::machine_code::{Ctx, Overwrite, Res, PC};
+use crate::step;
+
+/// The values overwritten by a single instruction, which restore the state from before it ran.
+#[derive(Debug, Clone)]
+pub struct UndoEntry {
+    pub pc: PC,
+    pub overwritten: Vec<Overwrite>,
+}
+
+/// An undo log of executed instructions, allowing a program to be stepped backwards.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    pub entries: Vec<UndoEntry>,
+}
+
+impl History {
+    /// Executes the instruction at the program counter, recording the values it overwrites.
+    pub fn step<const M: usize, const R: usize>(&mut self, ctx: &mut Ctx<M, R>) -> Res {
+        let pc = ctx.pc;
+        let res = step(ctx);
+        // A fault while fetching leaves the state untouched, so there is nothing to undo.
+        if ctx.pc != pc || res.is_ok() {
+            self.record(pc, ctx);
+        }
+        res
+    }
+
+    /// Records the values overwritten by an instruction which started at `pc`, from the journal it left in `ctx`.
+    pub fn record<const M: usize, const R: usize>(&mut self, pc: PC, ctx: &Ctx<M, R>) {
+        self.entries.push(UndoEntry {
+            pc,
+            overwritten: ctx.journal.clone(),
+        });
+    }
+
+    /// Undoes the most recently executed instruction, returning `false` if there is nothing to undo.
+    pub fn step_back<const M: usize, const R: usize>(&mut self, ctx: &mut Ctx<M, R>) -> bool {
+        let Some(entry) = self.entries.pop() else {
+            return false;
+        };
+        ctx.devices.untick();
+        for overwrite in entry.overwritten.into_iter().rev() {
+            match overwrite {
+                Overwrite::Register { register, old, .. } => ctx.registers[register] = old,
+                Overwrite::Memory { address, old, .. } => ctx.memory[address] = old,
+                Overwrite::Flags { old, .. } => ctx.flags = old,
+                Overwrite::Page { old, .. } => ctx.page = old,
+                Overwrite::DeviceRead { address, value } => ctx.devices.unread(address, value),
+                Overwrite::DeviceWrite { address } => ctx.devices.unwrite(address),
+                Overwrite::Warning => {
+                    ctx.warnings.pop();
+                }
+            }
+        }
+        ctx.pc = entry.pc;
+        ctx.journal.clear();
+        true
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn step_back_restores_state() {
+    use crate::devices::{TickCounter, TICK_COUNTER_ADDRESS};
+
+    let mut ctx: Ctx = Ctx::new([0; 256]);
+    let ticks = ctx
+        .devices
+        .map(TICK_COUNTER_ADDRESS, TickCounter::default());
+    // load_value r1, 3; store_memory r1, 0x80; jump 0x00
+    ctx.memory[..6].copy_from_slice(&[0x21, 0x03, 0x31, 0x80, 0xB0, 0x00]);
+    let initial = ctx.clone();
+
+    let mut history = History::default();
+    for _ in 0..4 {
+        history.step(&mut ctx).unwrap();
+    }
+    assert_eq!((ctx.pc, ticks.borrow().ticks), (2, 4));
+
+    assert!(history.step_back(&mut ctx));
+    assert_eq!(ctx.pc, 0);
+    assert!(history.step_back(&mut ctx));
+    assert!(history.step_back(&mut ctx));
+    assert_eq!((ctx.pc, ctx.memory[0x80]), (2, 0));
+    assert!(history.step_back(&mut ctx));
+    assert!(!history.step_back(&mut ctx));
+    assert_eq!(ctx.registers, initial.registers);
+    assert_eq!(ctx.memory, initial.memory);
+    assert_eq!(ctx.pc, initial.pc);
+    assert_eq!(ctx.flags, initial.flags);
+    assert_eq!(ticks.borrow().ticks, 0);
+}
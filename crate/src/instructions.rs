@@ -10,18 +10,29 @@
 //     )
 // }
 
+use std::borrow::Cow;
 use std::fmt::Debug;
 use std::num::NonZeroU16;
+use std::sync::Arc;
 
+use crate::custom_isa::{CustomInstr, CustomIsa};
+use crate::float::{self, Rounding};
+use crate::machine_code::{Test, MAX_MEMORY_SIZE, MEMORY_SIZE};
 use crate::Ctx;
 
 type Register = u8;
 type DirectAddress = u8;
 type ImmediateValue = u8;
 
+#[derive(Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(kani, derive(kani::Arbitrary))]
 pub enum DecodeError {
     NullInstruction,
     InvalidInstruction(NonZeroU16),
+    /// The word only decodes if bits which must be zero or F are ignored.
+    ReservedBits(NonZeroU16),
+    /// The word is a jump with a test which the profile does not have.
+    UnknownTest(NonZeroU16),
 }
 
 impl Debug for DecodeError {
@@ -31,76 +42,976 @@ impl Debug for DecodeError {
             DecodeError::InvalidInstruction(i) => {
                 write!(f, "Failed to decode instruction {:#04x}", i)
             }
+            DecodeError::ReservedBits(i) => {
+                write!(f, "Instruction {:#04x} has reserved bits set", i)
+            }
+            DecodeError::UnknownTest(i) => {
+                write!(f, "Instruction {:#04x} has an unknown test", i)
+            }
+        }
+    }
+}
+
+/// The kind of value an instruction operand holds, which decides how it is written in assembly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperandKind {
+    /// A register number, written `r0` to `r15` (or `rA` to `rF`).
+    Register,
+    /// A memory address, written as an integer or a label.
+    Address,
+    /// Any other value, written as an integer or a label.
+    Immediate,
+}
+
+macro_rules! operand_kind {
+    (Register) => {
+        OperandKind::Register
+    };
+    (DirectAddress) => {
+        OperandKind::Address
+    };
+    ($other:ident) => {
+        OperandKind::Immediate
+    };
+}
+
+/// Describes one operand field of an instruction encoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperandSpec {
+    pub name: Cow<'static, str>,
+    pub kind: OperandKind,
+    pub shift: u8,
+    pub mask: u16,
+}
+
+/// Describes the mnemonic and encoding of a single instruction.
+/// The built-in instructions borrow their text from the instruction table, and custom instructions own theirs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstrSpec {
+    pub mnemonic: Cow<'static, str>,
+    /// The doc comment of the instruction's row in the instruction table, with a line break after each line.
+    pub doc: Cow<'static, str>,
+    pub pattern: u16,
+    pub mask: u16,
+    pub operands: Cow<'static, [OperandSpec]>,
+}
+
+impl InstrSpec {
+    /// The description of the instruction, as Markdown.
+    pub fn description(&self) -> String {
+        self.doc
+            .lines()
+            .map(str::trim)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// How the instruction is written in assembly, such as `load_value r, xy`.
+    pub fn syntax(&self) -> String {
+        let names: Vec<&str> = self.operands.iter().map(|operand| &*operand.name).collect();
+        format!("{} {}", self.mnemonic, names.join(", "))
+            .trim_end()
+            .to_owned()
+    }
+
+    /// Encodes the instruction with the given operand values, which must already be in range.
+    pub fn encode(&self, operands: &[u16]) -> u16 {
+        self.operands
+            .iter()
+            .zip(operands)
+            .fold(self.pattern, |acc, (spec, value)| {
+                acc | (value & spec.mask) << spec.shift
+            })
+    }
+
+    /// The bits which must be zero or F, meaning every fixed bit apart from the opcode.
+    pub fn reserved(&self) -> u16 {
+        self.mask & 0x0FFF
+    }
+}
+
+/// How decoding treats sloppy instruction words: those which only decode if reserved bits are ignored,
+/// and jumps with a test which the profile does not have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DecodePolicy {
+    /// Fault on either.
+    Strict,
+    /// Fault on reserved bits, and treat unknown tests as never jumping.
+    #[default]
+    Lenient,
+    /// Execute either, ignoring the reserved bits, and record a warning.
+    Warn,
+}
+
+/// A sloppy instruction word which was executed under [DecodePolicy::Warn].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecodeWarning {
+    pub address: crate::machine_code::PC,
+    pub error: DecodeError,
+}
+
+impl std::fmt::Display for DecodeWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} at {:#04x}", self.error, self.address)
+    }
+}
+
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+// `Cow` can not be dereferenced in const functions, so these read the fields of a spec by matching instead.
+
+const fn mnemonic_of(spec: &InstrSpec) -> &str {
+    match &spec.mnemonic {
+        Cow::Borrowed(mnemonic) => mnemonic,
+        Cow::Owned(mnemonic) => mnemonic.as_str(),
+    }
+}
+
+const fn doc_of(spec: &InstrSpec) -> &str {
+    match &spec.doc {
+        Cow::Borrowed(doc) => doc,
+        Cow::Owned(doc) => doc.as_str(),
+    }
+}
+
+const fn operands_of(spec: &InstrSpec) -> &[OperandSpec] {
+    match &spec.operands {
+        Cow::Borrowed(operands) => operands,
+        Cow::Owned(operands) => operands.as_slice(),
+    }
+}
+
+const fn find_spec(specs: &[InstrSpec], mnemonic: &str) -> usize {
+    let mut i = 0;
+    while i < specs.len() {
+        if str_eq(mnemonic_of(&specs[i]), mnemonic) {
+            return i;
         }
+        i += 1;
     }
+    panic!("Instruction tables can only name instructions which are in the table")
+}
+
+/// Whether some instruction word matches both encodings.
+pub(crate) const fn encodings_overlap(a: &InstrSpec, b: &InstrSpec) -> bool {
+    (a.pattern ^ b.pattern) & a.mask & b.mask == 0
 }
 
+/// Whether the pattern only sets bits in the mask, and every field lies in the bits outside it.
+pub(crate) const fn fields_fit(spec: &InstrSpec) -> bool {
+    if spec.pattern & !spec.mask != 0 {
+        return false;
+    }
+    let operands = operands_of(spec);
+    let mut i = 0;
+    while i < operands.len() {
+        let field = (operands[i].mask as u32) << operands[i].shift;
+        if field > 0xFFFF || field as u16 & spec.mask != 0 {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+pub(crate) const fn fields_disjoint(spec: &InstrSpec) -> bool {
+    let operands = operands_of(spec);
+    let mut i = 0;
+    while i < operands.len() {
+        let mut j = i + 1;
+        while j < operands.len() {
+            let (a, b) = (&operands[i], &operands[j]);
+            if ((a.mask as u32) << a.shift) & ((b.mask as u32) << b.shift) != 0 {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+const fn has_precedence(precedence: &[(&str, &str)], first: &str, second: &str) -> bool {
+    let mut i = 0;
+    while i < precedence.len() {
+        if str_eq(precedence[i].0, first) && str_eq(precedence[i].1, second) {
+            return true;
+        }
+        i += 1;
+    }
+    false
+}
+
+/// Whether every overlap between an instruction and the others is annotated on the earlier of the two,
+/// and every annotation on the instruction names a later instruction which it overlaps.
+const fn overlaps_annotated(
+    specs: &[InstrSpec],
+    precedence: &[(&str, &str)],
+    mnemonic: &str,
+) -> bool {
+    let i = find_spec(specs, mnemonic);
+    let mut j = 0;
+    while j < specs.len() {
+        if j != i && encodings_overlap(&specs[i], &specs[j]) {
+            let (first, second) = if i < j { (i, j) } else { (j, i) };
+            if !has_precedence(
+                precedence,
+                mnemonic_of(&specs[first]),
+                mnemonic_of(&specs[second]),
+            ) {
+                return false;
+            }
+        }
+        j += 1;
+    }
+    let mut k = 0;
+    while k < precedence.len() {
+        if str_eq(precedence[k].0, mnemonic) {
+            let j = find_spec(specs, precedence[k].1);
+            if j <= i || !encodings_overlap(&specs[i], &specs[j]) {
+                return false;
+            }
+        }
+        k += 1;
+    }
+    true
+}
+
+/// Checks one row of an instruction table at compile time, naming its mnemonic in any error.
+macro_rules! validate_row {
+    ($specs:expr, $precedence:expr, $code:ident) => {
+        const _: () = {
+            let spec = &$specs[find_spec($specs, stringify!($code))];
+            assert!(
+                fields_fit(spec),
+                concat!("The pattern or fields of `", stringify!($code), "` lie outside the bits left free by its mask")
+            );
+            assert!(fields_disjoint(spec), concat!("The fields of `", stringify!($code), "` overlap each other"));
+            assert!(
+                overlaps_annotated($specs, $precedence, stringify!($code)),
+                concat!(
+                    "`", stringify!($code), "` shares encodings with another instruction without a `before` annotation on the first of them, ",
+                    "or is annotated `before` an instruction which it does not precede and overlap"
+                )
+            );
+        };
+    };
+}
+
+/// Defines an instruction set from a table of rows `(Variant (operand Type: shift & mask, ...), mnemonic, pattern, mask)`, each documented by a doc comment.
+/// Instructions are decoded by the first row whose masked bits match the pattern.
+/// Where an encoding deliberately overlaps a later row, the earlier row must say so with `before [mnemonic, ...]`, and the table is checked at compile time
+/// for unannotated overlaps, fields outside the bits left free by the mask, and fields which overlap each other.
 macro_rules! instructions {
-    ($instructions_name:ident, $(($variant:ident ($($param:ident $type:ident: $shift:literal & $mask:literal),*), $code:ident, $bitpattern:literal, $bitmask:literal)),* $(,)*) => {
+    ($instructions_name:ident, $($(#[doc = $doc:literal])* ($variant:ident ($($param:ident $type:ident: $shift:literal & $mask:literal),*), $code:ident, $bitpattern:literal, $bitmask:literal $(, before [$($shadowed:ident),*])?)),* $(,)*) => {
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub enum $instructions_name {
         $(
+            $(#[doc = $doc])*
             $variant ($( $type, )*),
         )*
+    /// An instruction described in a [CustomIsa].
+    #[serde(skip_deserializing)]
+    Custom(CustomInstr),
 }
 
 impl $instructions_name {
-    pub fn decode(instr: u16) -> Result<Self, DecodeError> {
-        match instr {
+    /// The mnemonic and encoding of every instruction, in decoding order.
+    pub const SPECS: &'static [InstrSpec] = &[
+        $(
+            InstrSpec {
+                mnemonic: Cow::Borrowed(stringify!($code)),
+                doc: Cow::Borrowed(concat!($($doc, "\n",)*)),
+                pattern: $bitpattern,
+                mask: $bitmask,
+                operands: Cow::Borrowed(&[$(
+                    OperandSpec { name: Cow::Borrowed(stringify!($param)), kind: operand_kind!($type), shift: $shift, mask: $mask },
+                )*]),
+            },
+        )*
+    ];
+
+    /// Pairs of mnemonics whose encodings overlap, where the first takes precedence because it is decoded first.
+    pub const PRECEDENCE: &'static [(&'static str, &'static str)] = &[
+        $($($(
+            (stringify!($code), stringify!($shadowed)),
+        )*)?)*
+    ];
+
+    /// Builds the instruction with the given mnemonic from its operand values, in the order they are written in assembly.
+    pub fn new(mnemonic: &str, operands: &[u8]) -> Option<Self> {
+        let mut values = operands.iter().copied();
+        let instr = match mnemonic {
+            $(
+                stringify!($code) => $instructions_name::$variant($({
+                    let $param: $type = values.next()?;
+                    $param
+                }, )*),
+            )*
+            _ => return None,
+        };
+        values.next().is_none().then_some(instr)
+    }
+
+    /// The mnemonic used for this instruction in assembly.
+    pub fn mnemonic(&self) -> &str {
+        match self {
             $(
-            _i if $bitpattern == (instr & $bitmask) => Ok($instructions_name::$variant ($( ( instr >> $shift) as u8 & $mask , )*)),
+                $instructions_name::$variant(..) => stringify!($code),
             )*
-            0x0000 => Err(DecodeError::NullInstruction),
-            _ => Err(DecodeError::InvalidInstruction (instr.try_into().unwrap())),
+            $instructions_name::Custom(instr) => &instr.spec().mnemonic,
         }
     }
 
+    /// The mnemonic and operands of this instruction, with its encoding in the wide profile.
+    /// Use [IsaProfile::encode] for its encoding in another profile.
+    pub fn spec(&self) -> &InstrSpec {
+        if let $instructions_name::Custom(instr) = self {
+            return instr.spec();
+        }
+        let mnemonic = self.mnemonic();
+        Self::SPECS.iter().find(|spec| spec.mnemonic == mnemonic).unwrap()
+    }
 
-    pub fn encode(self) -> u16 {
+    /// The operand values of this instruction, in the order they are written in assembly.
+    pub fn operands(&self) -> Vec<u8> {
         match self {
             $(
-                $instructions_name::$variant ($( $param, )*) => { return $bitpattern $( | ($param as u16) << $shift )*;},
+                $instructions_name::$variant ($( $param, )*) => vec![$( *$param, )*],
             )*
+            $instructions_name::Custom(instr) => instr.operands(),
         }
     }
 
-    pub fn execute(self, ctx: &mut Ctx) -> crate::machine_code::Res {
+    pub fn execute<const M: usize, const R: usize>(&self, ctx: &mut Ctx<M, R>) -> crate::machine_code::Res {
         match self {
             $(
-                $instructions_name::$variant($($param, )*) => crate::machine_code::$code(ctx, $($param, )*),
+                $instructions_name::$variant($($param, )*) => crate::machine_code::$code(ctx, $(*$param, )*),
             )*
+            $instructions_name::Custom(instr) => instr.execute(ctx),
         }
     }
 }
+
+$(
+validate_row!($instructions_name::SPECS, $instructions_name::PRECEDENCE, $code);
+)*
     }
 }
 
+// Every instruction, in the encodings of the wide profile. Each [IsaProfile] picks which of them exist, and how they are encoded.
 instructions!(
     Instr,
+    /// No operation. Carry on to the next instruction. The data fields must all be F.
     (NoOp (), no_op, 0x0FFF, 0xFFFF),
+    /// Load from memory (direct addressing). Copy the data at memory address xy into register r.
+    /// The address is in the page selected by set_page, like every direct and register indirect address.
     (LoadMemory (r Register: 8 & 0xf, xy DirectAddress: 0 & 0xff), load_memory, 0x1000, 0xF000),
+    /// Load value (immediate addressing). Copy xy into register r.
     (LoadValue (r Register: 8 & 0xf, xy ImmediateValue: 0 & 0xff), load_value, 0x2000, 0xF000),
+    /// Load from memory (register indirect addressing). Copy the data from the memory location whose address is in register s. Place it in register r.
+    /// For example, if register s contains the value 86, and memory location 86 contains the value 7B, register r will be given the value 7B.
     (LoadIndirect (r Register: 4 & 0xf, s Register: 0 & 0xf), load_indirect, 0xD000, 0xFF00),
+    /// Store (direct addressing). Copy the contents of register r into memory at address xy.
     (StoreMemory (r Register: 8 & 0xf, xy DirectAddress: 0 & 0xff), store_memory, 0x3000, 0xF000),
+    /// Store in memory (register indirect addressing). Copy the data from register r. Place it in the memory location whose address is in register s.
+    /// For example, if register r contains the value EC, and register s contains the value 41, the value EC will be placed in memory at address 41.
     (StoreIndirect (r Register: 4 & 0xf, s Register: 0 & 0xf), store_indirect, 0xE000, 0xFF00),
+    /// Move. Copy the contents of register r into register s.
     (MoveRegister (r Register: 4 & 0xf, s Register: 0 & 0xf), move_register, 0x4000, 0xFF00),
+    /// Add as integers. Add the contents of register s to the contents of register t as twos complement integers. Put the result into register r.
+    /// The add is wrapping, and sets the carry and overflow flags if the unsigned or signed sum did not fit.
     (AddInteger (r Register: 8 & 0xf, s Register: 4 & 0xf, t Register: 0 & 0xf), add_integer, 0x5000, 0xF000),
+    /// Add the contents of register s to the contents of register t as floating point values. Put the result into register r. The format is 1 sign bit, 3 exponent bits and 4 mantissa bits, SEEEMMMM, with 1 as negative.
+    /// The exponent is in excess-4 notation, so the maximum value is 7.5 and the minimum is -7.5. The sum is rounded to the nearest value, or truncated by the textbook instruction set.
+    /// If the sum is too large to represent, register r is set to the largest value of the same sign, the overflow flag is set, and the add fails with [Err::FloatingPointSaturated].
     (AddFloat (r Register: 8 & 0xf, s Register: 4 & 0xf, t Register: 0 & 0xf), add_float, 0x6000, 0xF000),
+    /// OR. Carry out the bitwise OR operation on the contents of register s and the contents of register t. Put the result into register r.
     (BitwiseOr (r Register: 8 & 0xf, s Register: 4 & 0xf, t Register: 0 & 0xf), bitwise_or, 0x7000, 0xF000),
+    /// AND. Carry out the bitwise AND operation on the contents of register s and the contents of register t. Put the result into register r.
     (BitwiseAnd (r Register: 8 & 0xf, s Register: 4 & 0xf, t Register: 0 & 0xf), bitwise_and, 0x8000, 0xF000),
+    /// XOR. Carry out the bitwise exclusive or operation on the contents of register s and the contents of register t. Put the result into register r.
     (BitwiseXor (r Register: 8 & 0xf, s Register: 4 & 0xf, t Register: 0 & 0xf), bitwise_xor, 0x9000, 0xF000),
-    (BitwiseRotate (r Register: 8 & 0xf, x ImmediateValue: 0 & 0xf), bitwise_rotate, 0xA000, 0xF0F0), // ImmediateValue is 2 nibbles, but we're masking out one
-    (Jump (xy DirectAddress: 0 & 0xff), jump, 0xB000, 0xFF00),
-    (JumpIndirect (t Register: 0 & 0xf), jump_indirect, 0xF000, 0xFFF0),
+    /// Rotate the contents of register r by x bits to the right. Update register r with the result.
+    /// Bits shifted out of the low end are shifted back in at the high end, so rotating by 8 leaves the register unchanged.
+    (BitwiseRotate (r Register: 8 & 0xf, x ImmediateValue: 0 & 0xf), bitwise_rotate, 0xA000, 0xF0F0),
+    /// Rotate the contents of register r by x bits to the left. Update register r with the result.
+    /// Only available in the extended instruction set.
+    (RotateLeft (r Register: 8 & 0xf, x ImmediateValue: 0 & 0xf), rotate_left, 0xA010, 0xF0F0),
+    /// Shift the contents of register r by x bits to the left, filling with zeros. Update register r with the result.
+    /// Only available in the extended instruction set.
+    (ShiftLeft (r Register: 8 & 0xf, x ImmediateValue: 0 & 0xf), shift_left, 0xA020, 0xF0F0),
+    /// Shift the contents of register r by x bits to the right, filling with zeros. Update register r with the result.
+    /// Only available in the extended instruction set.
+    (ShiftRightLogical (r Register: 8 & 0xf, x ImmediateValue: 0 & 0xf), shift_right_logical, 0xA030, 0xF0F0),
+    /// Shift the contents of register r by x bits to the right as a twos complement integer, filling with copies of the sign bit.
+    /// Update register r with the result.
+    /// Only available in the extended instruction set.
+    (ShiftRightArithmetic (r Register: 8 & 0xf, x ImmediateValue: 0 & 0xf), shift_right_arithmetic, 0xA040, 0xF0F0),
+    /// Jump to memory location xy. That is, the program counter is set to xy just before the next instruction is executed.
+    /// The address is in the page of the next instruction, like the addresses of every jump apart from far_jump.
+    (Jump (xy DirectAddress: 0 & 0xff), jump, 0xB000, 0xFF00, before [jump_if_eq]),
+    /// Jump to register address. Jump to the memory address stored in register t. That is, the contents of register t are copied to the program counter.
+    (JumpIndirect (t Register: 0 & 0xf), jump_indirect, 0xF000, 0xFFF0, before [jump_with_test]),
+    /// Jump if equal. If the contents of register r equal the contents of register 0, jump to memory location xy.
     (JumpIfEq (r Register: 8 & 0xf, xy DirectAddress: 0 & 0xff), jump_if_eq, 0xB000, 0xF000),
+    /// Jump to register address with test. The contents of register r are compared to the contents of register 0 using a test which depends on x. If the result of the test is true, a jump is made to the memory address stored in register t.
+    /// The register values are treated as unsigned integers for the comparisons.
+    /// The arithmetic instruction set adds tests 6 to 9, which are tests 2 to 5 with the values treated as twos complement integers.
     (JumpWithTest (r Register: 8 & 0xf, x u8: 4 & 0xf, t Register: 0 & 0xf), jump_with_test, 0xF000, 0xF000),
+    /// Stop execution.
     (Halt (), halt, 0xC000, 0xFFFF),
+    /// Subtract. Subtract the contents of register s from the contents of register r as twos complement integers. Put the result into register r.
+    /// The subtraction is wrapping, and sets the carry flag if it borrowed and the overflow flag if the signed difference did not fit.
+    /// Only available in the arithmetic instruction set.
+    (Subtract (r Register: 4 & 0xf, s Register: 0 & 0xf), subtract, 0x0100, 0xFF00),
+    /// Compare. Subtract the contents of register s from the contents of register r, setting the flags like subtract, but leave register r unchanged.
+    /// Only available in the arithmetic instruction set.
+    (Compare (r Register: 4 & 0xf, s Register: 0 & 0xf), compare, 0x0200, 0xFF00),
+    /// Add with carry. Add the contents of register s and the carry flag to the contents of register r as twos complement integers. Put the result into register r.
+    /// The flags are set like add_integer, so that a chain of adds with carry can add numbers of several bytes.
+    /// Only available in the arithmetic instruction set.
+    (AddWithCarry (r Register: 4 & 0xf, s Register: 0 & 0xf), add_with_carry, 0x0300, 0xFF00),
+    /// Set page. Copy the contents of register r into the page register, which is the high byte of direct and register indirect addresses.
+    /// Only available in the wide instruction set.
+    (SetPage (r Register: 0 & 0xf), set_page, 0x0400, 0xFFF0),
+    /// Far jump. Jump to the memory address whose high byte is in register r and whose low byte is in register s.
+    /// Only available in the wide instruction set.
+    (FarJump (r Register: 4 & 0xf, s Register: 0 & 0xf), far_jump, 0x0500, 0xFF00),
+    /// Load register (register indirect addressing). Copy the contents of the register whose number is in register s into register r.
+    /// Register numbers wrap around the number of registers, so every register can be reached.
+    /// Only available in the wide instruction set.
+    (LoadRegister (r Register: 4 & 0xf, s Register: 0 & 0xf), load_register, 0x0600, 0xFF00),
+    /// Store register (register indirect addressing). Copy the contents of register r into the register whose number is in register s.
+    /// Register numbers wrap around the number of registers, so every register can be reached.
+    /// Only available in the wide instruction set.
+    (StoreRegister (r Register: 4 & 0xf, s Register: 0 & 0xf), store_register, 0x0700, 0xFF00),
 );
 
+/// Defines the instruction table of an [IsaProfile] from rows `(mnemonic, pattern, mask)`, taking the operands of each instruction from [Instr].
+/// The rows are checked at compile time in the same way as those of `instructions!`.
+macro_rules! profile {
+    ($specs:ident, $precedence:ident, $(($code:ident, $bitpattern:literal, $bitmask:literal $(, before [$($shadowed:ident),*])?)),* $(,)*) => {
+        const $specs: &[InstrSpec] = &[
+            $(
+                InstrSpec {
+                    mnemonic: Cow::Borrowed(stringify!($code)),
+                    doc: Cow::Borrowed(doc_of(&Instr::SPECS[find_spec(Instr::SPECS, stringify!($code))])),
+                    pattern: $bitpattern,
+                    mask: $bitmask,
+                    operands: Cow::Borrowed(operands_of(&Instr::SPECS[find_spec(Instr::SPECS, stringify!($code))])),
+                },
+            )*
+        ];
+
+        const $precedence: &[(&str, &str)] = &[
+            $($($(
+                (stringify!($code), stringify!($shadowed)),
+            )*)?)*
+        ];
+
+        $(
+            validate_row!($specs, $precedence, $code);
+        )*
+    };
+}
+
+profile!(
+    TEXTBOOK_SPECS,
+    TEXTBOOK_PRECEDENCE,
+    (load_memory, 0x1000, 0xF000),
+    (load_value, 0x2000, 0xF000),
+    (store_memory, 0x3000, 0xF000),
+    (move_register, 0x4000, 0xFF00),
+    (add_integer, 0x5000, 0xF000),
+    (add_float, 0x6000, 0xF000),
+    (bitwise_or, 0x7000, 0xF000),
+    (bitwise_and, 0x8000, 0xF000),
+    (bitwise_xor, 0x9000, 0xF000),
+    (bitwise_rotate, 0xA000, 0xF0F0),
+    (jump, 0xB000, 0xFF00, before[jump_if_eq]),
+    (jump_if_eq, 0xB000, 0xF000),
+    (halt, 0xC000, 0xFFFF),
+);
+
+profile!(
+    CLASSIC_SPECS,
+    CLASSIC_PRECEDENCE,
+    (no_op, 0x0FFF, 0xFFFF),
+    (load_memory, 0x1000, 0xF000),
+    (load_value, 0x2000, 0xF000),
+    (load_indirect, 0xD000, 0xFF00),
+    (store_memory, 0x3000, 0xF000),
+    (store_indirect, 0xE000, 0xFF00),
+    (move_register, 0x4000, 0xFF00),
+    (add_integer, 0x5000, 0xF000),
+    (add_float, 0x6000, 0xF000),
+    (bitwise_or, 0x7000, 0xF000),
+    (bitwise_and, 0x8000, 0xF000),
+    (bitwise_xor, 0x9000, 0xF000),
+    (bitwise_rotate, 0xA000, 0xF0F0),
+    (jump, 0xB000, 0xFF00, before[jump_if_eq]),
+    (jump_indirect, 0xF000, 0xFFF0, before[jump_with_test]),
+    (jump_if_eq, 0xB000, 0xF000),
+    (jump_with_test, 0xF000, 0xF000),
+    (halt, 0xC000, 0xFFFF),
+);
+
+profile!(
+    EXTENDED_SPECS,
+    EXTENDED_PRECEDENCE,
+    (no_op, 0x0FFF, 0xFFFF),
+    (load_memory, 0x1000, 0xF000),
+    (load_value, 0x2000, 0xF000),
+    (load_indirect, 0xD000, 0xFF00),
+    (store_memory, 0x3000, 0xF000),
+    (store_indirect, 0xE000, 0xFF00),
+    (move_register, 0x4000, 0xFF00),
+    (add_integer, 0x5000, 0xF000),
+    (add_float, 0x6000, 0xF000),
+    (bitwise_or, 0x7000, 0xF000),
+    (bitwise_and, 0x8000, 0xF000),
+    (bitwise_xor, 0x9000, 0xF000),
+    (bitwise_rotate, 0xA000, 0xF0F0),
+    (rotate_left, 0xA010, 0xF0F0),
+    (shift_left, 0xA020, 0xF0F0),
+    (shift_right_logical, 0xA030, 0xF0F0),
+    (shift_right_arithmetic, 0xA040, 0xF0F0),
+    (jump, 0xB000, 0xFF00, before[jump_if_eq]),
+    (jump_indirect, 0xF000, 0xFFF0, before[jump_with_test]),
+    (jump_if_eq, 0xB000, 0xF000),
+    (jump_with_test, 0xF000, 0xF000),
+    (halt, 0xC000, 0xFFFF),
+);
+
+profile!(
+    ARITHMETIC_SPECS,
+    ARITHMETIC_PRECEDENCE,
+    (no_op, 0x0FFF, 0xFFFF),
+    (load_memory, 0x1000, 0xF000),
+    (load_value, 0x2000, 0xF000),
+    (load_indirect, 0xD000, 0xFF00),
+    (store_memory, 0x3000, 0xF000),
+    (store_indirect, 0xE000, 0xFF00),
+    (move_register, 0x4000, 0xFF00),
+    (add_integer, 0x5000, 0xF000),
+    (add_float, 0x6000, 0xF000),
+    (bitwise_or, 0x7000, 0xF000),
+    (bitwise_and, 0x8000, 0xF000),
+    (bitwise_xor, 0x9000, 0xF000),
+    (bitwise_rotate, 0xA000, 0xF0F0),
+    (rotate_left, 0xA010, 0xF0F0),
+    (shift_left, 0xA020, 0xF0F0),
+    (shift_right_logical, 0xA030, 0xF0F0),
+    (shift_right_arithmetic, 0xA040, 0xF0F0),
+    (jump, 0xB000, 0xFF00, before[jump_if_eq]),
+    (jump_indirect, 0xF000, 0xFFF0, before[jump_with_test]),
+    (jump_if_eq, 0xB000, 0xF000),
+    (jump_with_test, 0xF000, 0xF000),
+    (halt, 0xC000, 0xFFFF),
+    (subtract, 0x0100, 0xFF00),
+    (compare, 0x0200, 0xFF00),
+    (add_with_carry, 0x0300, 0xFF00),
+);
+
+/// A variant of the instruction set, deciding which instructions exist, how they are encoded, and how some of them execute.
+/// Instructions are only decoded and encoded through a profile, since the same instruction can have a different encoding in each.
+/// The built-in profiles all put rotate in opcode A and use excess-4 floats; a [CustomIsa] can choose another excess.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum IsaProfile {
+    /// The twelve instructions of the textbook, with opcodes 1 to C, whose floating point sums are truncated.
+    Textbook,
+    /// The textbook instructions, plus no-op, the indirect loads and stores of D and E, and the indirect and tested jumps of F.
+    #[default]
+    Classic,
+    /// The classic instructions, plus rotates and shifts selected by the unused second nibble of A.
+    Extended,
+    /// The extended instructions, plus a status register, subtract, compare and add with carry in opcode 0, and signed tests for jumps with tests.
+    Arithmetic,
+    /// The arithmetic instructions, plus a page register, far jumps and access to every register, for machines with more than 256 bytes of memory or 16 registers.
+    Wide,
+    /// Instructions loaded from a file, added to those of a built-in profile.
+    Custom(Arc<CustomIsa>),
+}
+
+impl IsaProfile {
+    pub const ALL: [IsaProfile; 5] = [
+        IsaProfile::Textbook,
+        IsaProfile::Classic,
+        IsaProfile::Extended,
+        IsaProfile::Arithmetic,
+        IsaProfile::Wide,
+    ];
+
+    /// The mnemonic and encoding of every instruction in the profile, in decoding order.
+    pub fn specs(&self) -> &[InstrSpec] {
+        match self {
+            IsaProfile::Textbook => TEXTBOOK_SPECS,
+            IsaProfile::Classic => CLASSIC_SPECS,
+            IsaProfile::Extended => EXTENDED_SPECS,
+            IsaProfile::Arithmetic => ARITHMETIC_SPECS,
+            IsaProfile::Wide => Instr::SPECS,
+            IsaProfile::Custom(isa) => isa.specs(),
+        }
+    }
+
+    /// Pairs of mnemonics whose encodings overlap, where the first takes precedence because it is decoded first.
+    pub fn precedence(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            IsaProfile::Textbook => TEXTBOOK_PRECEDENCE,
+            IsaProfile::Classic => CLASSIC_PRECEDENCE,
+            IsaProfile::Extended => EXTENDED_PRECEDENCE,
+            IsaProfile::Arithmetic => ARITHMETIC_PRECEDENCE,
+            IsaProfile::Wide => Instr::PRECEDENCE,
+            // Custom instructions can not overlap any other instruction.
+            IsaProfile::Custom(isa) => isa.base.precedence(),
+        }
+    }
+
+    /// The bytes of memory a machine running the profile has, which is every address the program counter can hold if it can select pages.
+    pub fn memory_size(&self) -> usize {
+        match self {
+            IsaProfile::Wide => MAX_MEMORY_SIZE,
+            IsaProfile::Custom(isa) => isa.base.memory_size(),
+            _ => MEMORY_SIZE,
+        }
+    }
+
+    /// Whether arithmetic and bitwise instructions set the status flags.
+    pub fn has_flags(&self) -> bool {
+        match self {
+            IsaProfile::Arithmetic | IsaProfile::Wide => true,
+            IsaProfile::Custom(isa) => isa.base.has_flags(),
+            _ => false,
+        }
+    }
+
+    /// Whether jumps with tests can compare values as twos complement integers.
+    pub fn has_signed_tests(&self) -> bool {
+        match self {
+            IsaProfile::Arithmetic | IsaProfile::Wide => true,
+            IsaProfile::Custom(isa) => isa.base.has_signed_tests(),
+            _ => false,
+        }
+    }
+
+    /// How floating point sums which fall between two values are rounded.
+    pub fn float_rounding(&self) -> Rounding {
+        match self {
+            IsaProfile::Textbook => Rounding::Truncate,
+            IsaProfile::Custom(isa) => isa.float_rounding,
+            _ => Rounding::Nearest,
+        }
+    }
+
+    /// The excess of the exponent of floating point values.
+    pub fn float_excess(&self) -> u8 {
+        match self {
+            IsaProfile::Custom(isa) => isa.float_excess,
+            _ => float::EXCESS,
+        }
+    }
+
+    /// Looks up an instruction of the profile by its mnemonic, ignoring case.
+    pub fn spec(&self, mnemonic: &str) -> Option<&InstrSpec> {
+        self.specs()
+            .iter()
+            .find(|spec| spec.mnemonic.eq_ignore_ascii_case(mnemonic))
+    }
+
+    /// Builds the instruction of the profile with the given mnemonic from its operand values, in the order they are written in assembly.
+    /// Returns `None` if the profile does not have the instruction.
+    pub fn instr(&self, mnemonic: &str, operands: &[u8]) -> Option<Instr> {
+        match self {
+            IsaProfile::Custom(isa) => match isa.op(mnemonic) {
+                Some(op) => (operands.len() == op.spec.operands.len())
+                    .then(|| Instr::Custom(CustomInstr::new(op.clone(), operands))),
+                None => isa.base.instr(mnemonic, operands),
+            },
+            _ => self
+                .spec(mnemonic)
+                .and_then(|_| Instr::new(mnemonic, operands)),
+        }
+    }
+
+    /// Decodes an instruction word using the first instruction of the profile whose masked bits match its pattern.
+    pub fn decode(&self, instr: u16) -> Result<Instr, DecodeError> {
+        match self
+            .specs()
+            .iter()
+            .find(|spec| instr & spec.mask == spec.pattern)
+        {
+            Some(spec) => {
+                let operands: Vec<u8> = spec
+                    .operands
+                    .iter()
+                    .map(|operand| (instr >> operand.shift & operand.mask) as u8)
+                    .collect();
+                Ok(self.instr(&spec.mnemonic, &operands).unwrap())
+            }
+            None if instr == 0 => Err(DecodeError::NullInstruction),
+            None => Err(DecodeError::InvalidInstruction(instr.try_into().unwrap())),
+        }
+    }
+
+    /// Decodes an instruction word, applying the policy to sloppy words.
+    /// Returns the error the word would have faulted with if it was executed anyway.
+    pub fn decode_with(
+        &self,
+        instr: u16,
+        policy: DecodePolicy,
+    ) -> Result<(Instr, Option<DecodeError>), DecodeError> {
+        let decoded = match self.decode(instr) {
+            Err(DecodeError::InvalidInstruction(word)) => {
+                let Some(decoded) = self.decode_ignoring_reserved(instr) else {
+                    return Err(DecodeError::InvalidInstruction(word));
+                };
+                return match policy {
+                    DecodePolicy::Warn => Ok((decoded, Some(DecodeError::ReservedBits(word)))),
+                    DecodePolicy::Strict => Err(DecodeError::ReservedBits(word)),
+                    DecodePolicy::Lenient => Err(DecodeError::InvalidInstruction(word)),
+                };
+            }
+            decoded => decoded?,
+        };
+        let tests = if self.has_signed_tests() {
+            Test::Never
+        } else {
+            Test::SignedGte
+        } as u8;
+        match decoded {
+            Instr::JumpWithTest(_, test, _) if test >= tests => {
+                let error = DecodeError::UnknownTest(instr.try_into().unwrap());
+                match policy {
+                    DecodePolicy::Strict => Err(error),
+                    DecodePolicy::Lenient => Ok((decoded, None)),
+                    DecodePolicy::Warn => Ok((decoded, Some(error))),
+                }
+            }
+            _ => Ok((decoded, None)),
+        }
+    }
+
+    /// Decodes a word as the only instruction which matches it once reserved bits are ignored, if there is one.
+    fn decode_ignoring_reserved(&self, instr: u16) -> Option<Instr> {
+        let mut matching = self.specs().iter().filter(|spec| {
+            let mask = spec.mask & !spec.reserved();
+            instr & mask == spec.pattern & mask
+        });
+        let spec = matching.next()?;
+        if matching.next().is_some() {
+            return None;
+        }
+        self.decode(instr & !spec.reserved() | spec.pattern).ok()
+    }
+
+    /// Encodes the instruction, or returns `None` if the profile does not have it.
+    pub fn encode(&self, instr: &Instr) -> Option<u16> {
+        let operands: Vec<u16> = instr.operands().into_iter().map(u16::from).collect();
+        self.spec(instr.mnemonic())
+            .map(|spec| spec.encode(&operands))
+    }
+}
+
 #[cfg(kani)]
 #[kani::proof]
 fn decode_does_not_panic() {
     let instr: u16 = kani::any();
-    let _ = Instr::decode(instr);
+    let _ = IsaProfile::Wide.decode(instr);
+}
+
+#[cfg(kani)]
+#[kani::proof]
+fn decode_encode_round_trips() {
+    let instr: u16 = kani::any();
+    if let Ok(decoded) = IsaProfile::Wide.decode(instr) {
+        assert_eq!(IsaProfile::Wide.encode(&decoded), Some(instr));
+    }
+}
+
+/// Checks that every word the profile decodes encodes back to itself, and that the given words decode to the given mnemonics.
+#[cfg(test)]
+fn assert_conforms(isa: &IsaProfile, words: &[(u16, Option<&str>)]) {
+    for word in 0..=u16::MAX {
+        if let Ok(instr) = isa.decode(word) {
+            assert_eq!(isa.encode(&instr), Some(word), "{:04X}", word);
+        }
+    }
+    for &(word, mnemonic) in words {
+        let decoded = isa
+            .decode(word)
+            .ok()
+            .map(|instr| instr.mnemonic().to_owned());
+        assert_eq!(decoded.as_deref(), mnemonic, "{:04X}", word);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn textbook_profile_conforms() {
+    assert_conforms(
+        &IsaProfile::Textbook,
+        &[
+            (0x2101, Some("load_value")),
+            (0x4012, Some("move_register")),
+            (0xA203, Some("bitwise_rotate")),
+            (0xB00A, Some("jump")),
+            (0xB20A, Some("jump_if_eq")),
+            (0xC000, Some("halt")),
+            (0x0FFF, None),
+            (0xD012, None),
+            (0xE012, None),
+            (0xF003, None),
+            (0xA213, None),
+        ],
+    );
+    assert_eq!(IsaProfile::Textbook.specs().len(), 13);
+
+    // 1.875 + 0.5 lies between 2.25 and 2.5.
+    let mut ctx: crate::Ctx = crate::Ctx::new([0; crate::machine_code::MEMORY_SIZE]);
+    ctx.registers[..2].copy_from_slice(&[0x5F, 0x48]);
+    for (isa, sum) in [(IsaProfile::Textbook, 0x69), (IsaProfile::Classic, 0x6A)] {
+        ctx.isa = isa;
+        Instr::AddFloat(2, 0, 1).execute(&mut ctx).unwrap();
+        assert_eq!(ctx.registers[2], sum);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn classic_profile_conforms() {
+    assert_conforms(
+        &IsaProfile::Classic,
+        &[
+            (0x0FFF, Some("no_op")),
+            (0xD012, Some("load_indirect")),
+            (0xE012, Some("store_indirect")),
+            (0xF003, Some("jump_indirect")),
+            (0xF123, Some("jump_with_test")),
+            (0xA203, Some("bitwise_rotate")),
+            (0xA213, None),
+            (0x0000, None),
+        ],
+    );
+    for word in 0..=u16::MAX {
+        if let Ok(instr) = IsaProfile::Textbook.decode(word) {
+            let classic = IsaProfile::Classic.decode(word).unwrap();
+            assert_eq!(classic.mnemonic(), instr.mnemonic());
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn extended_profile_conforms() {
+    assert_conforms(
+        &IsaProfile::Extended,
+        &[
+            (0xA203, Some("bitwise_rotate")),
+            (0xA213, Some("rotate_left")),
+            (0xA223, Some("shift_left")),
+            (0xA233, Some("shift_right_logical")),
+            (0xA143, Some("shift_right_arithmetic")),
+            (0xA253, None),
+        ],
+    );
+    for word in 0..=u16::MAX {
+        let extended = IsaProfile::Extended.decode(word);
+        match IsaProfile::Classic.decode(word) {
+            Ok(instr) => assert_eq!(instr.mnemonic(), extended.unwrap().mnemonic()),
+            Err(_) if word & 0xF000 == 0xA000 && word & 0x00F0 <= 0x0040 => {
+                assert!(extended.is_ok())
+            }
+            Err(_) => assert!(extended.is_err()),
+        }
+    }
+    assert!(matches!(
+        IsaProfile::Extended.decode(0xA143),
+        Ok(Instr::ShiftRightArithmetic(1, 3))
+    ));
+}
+
+#[cfg(test)]
+#[test]
+fn arithmetic_profile_conforms() {
+    assert_conforms(
+        &IsaProfile::Arithmetic,
+        &[
+            (0x0112, Some("subtract")),
+            (0x0212, Some("compare")),
+            (0x0312, Some("add_with_carry")),
+            (0x0412, None),
+            (0x0FFF, Some("no_op")),
+            (0xA213, Some("rotate_left")),
+        ],
+    );
+    for word in 0..=u16::MAX {
+        let arithmetic = IsaProfile::Arithmetic.decode(word);
+        match IsaProfile::Extended.decode(word) {
+            Ok(instr) => assert_eq!(instr.mnemonic(), arithmetic.unwrap().mnemonic()),
+            Err(_) => assert_eq!(arithmetic.is_ok(), (0x0100..0x0400).contains(&word)),
+        }
+    }
+    assert!(IsaProfile::Arithmetic.has_signed_tests());
+    assert!(!IsaProfile::Extended.has_signed_tests());
+    assert!(IsaProfile::Arithmetic.has_flags());
+    assert!(!IsaProfile::Extended.has_flags());
+}
+
+#[cfg(test)]
+#[test]
+fn wide_profile_conforms() {
+    assert_conforms(
+        &IsaProfile::Wide,
+        &[
+            (0x0403, Some("set_page")),
+            (0x0413, None),
+            (0x0512, Some("far_jump")),
+            (0x0612, Some("load_register")),
+            (0x0712, Some("store_register")),
+            (0x0812, None),
+        ],
+    );
+    for word in 0..=u16::MAX {
+        let wide = IsaProfile::Wide.decode(word);
+        match IsaProfile::Arithmetic.decode(word) {
+            Ok(instr) => assert_eq!(instr.mnemonic(), wide.unwrap().mnemonic()),
+            Err(_) => assert_eq!(
+                wide.is_ok(),
+                (0x0400..0x0410).contains(&word) || (0x0500..0x0800).contains(&word)
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn precedence_decides_overlaps() {
+    for isa in IsaProfile::ALL {
+        assert_eq!(isa.precedence()[0], ("jump", "jump_if_eq"));
+        for word in 0..=u16::MAX {
+            for (first, second) in isa.precedence() {
+                let (first, second) = (isa.spec(first).unwrap(), isa.spec(second).unwrap());
+                if word & first.mask == first.pattern {
+                    assert!(word & second.mask == second.pattern);
+                    assert_eq!(isa.decode(word).unwrap().mnemonic(), first.mnemonic);
+                }
+            }
+        }
+    }
 }
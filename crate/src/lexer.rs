@@ -1,6 +1,6 @@
 use logos::Logos;
 
-#[derive(Logos, Debug, PartialEq)]
+#[derive(Logos, Debug, Clone, Copy, PartialEq)]
 // #[logos(skip r"\p{Default_Ignorable_Code_Point}+")]
 #[logos(subpattern decimal = r"[0-9][_0-9]*")]
 #[logos(subpattern hex = r"[0-9a-fA-F][_0-9a-fA-F]*")]
@@ -38,4 +38,8 @@ pub enum Token {
     Identifier,
     #[token(".")]
     DotSymbol,
+    #[token(",")]
+    Comma,
+    #[regex(r#""(?:[^"\\\n]|\\.)*""#)]
+    String,
 }
@@ -1,27 +1,207 @@
-use machine_code::{Ctx, Res};
+use instructions::{DecodeWarning, Instr};
+use machine_code::{Ctx, Err, Overwrite, Res, PC};
 
+pub mod assembler;
+pub mod custom_isa;
+pub mod debugger;
+pub mod devices;
+pub mod diagnostics;
+pub mod disassembler;
+pub mod float;
 pub mod highlight;
+pub mod history;
 pub mod instructions;
 pub mod lexer;
+pub mod lsp;
+pub mod machine;
 pub mod machine_code;
 pub mod memory;
+pub mod preprocessor;
+pub mod report;
+pub mod rtl;
+pub mod trace;
 // mod interpreter;
 
-pub fn execute(ctx: &mut Ctx, mut fuel: usize) -> (usize, Res) {
+/// Decodes the instruction at the program counter, using the machine's instruction set and decoding policy
+pub fn fetch<const M: usize, const R: usize>(ctx: &Ctx<M, R>) -> Result<Instr, Err> {
+    fetch_checked(ctx).map(|(instr, _)| instr)
+}
+
+/// Like [fetch], but also returns a warning if the instruction word is sloppy and the policy lets it run.
+fn fetch_checked<const M: usize, const R: usize>(
+    ctx: &Ctx<M, R>,
+) -> Result<(Instr, Option<DecodeWarning>), Err> {
+    let pc = ctx.pc as usize;
+    let bytes = ctx
+        .memory
+        .get(pc..=pc + 1)
+        .ok_or(Err::PcOutOfRange(ctx.pc))?;
+    let instr = u16::from_be_bytes([bytes[0], bytes[1]]);
+    let (decoded, error) = ctx
+        .isa
+        .decode_with(instr, ctx.decode_policy)
+        .map_err(|error| Err::DecodeFailed {
+            address: ctx.pc,
+            error,
+        })?;
+    let warning = error.map(|error| DecodeWarning {
+        address: ctx.pc,
+        error,
+    });
+    Ok((decoded, warning))
+}
+
+/// Executes the instruction at the program counter.
+/// The program counter wraps around to 0 after the last instruction in memory.
+/// The values the instruction overwrites are left in [Ctx::journal].
+pub fn step<const M: usize, const R: usize>(ctx: &mut Ctx<M, R>) -> Res {
+    step_instr(ctx).1
+}
+
+/// Like [step], but also returns the instruction which ran, or `None` if it could not be fetched.
+pub fn step_instr<const M: usize, const R: usize>(ctx: &mut Ctx<M, R>) -> (Option<Instr>, Res) {
+    ctx.journal.clear();
+    let (instr_dec, warning) = match fetch_checked(ctx) {
+        Ok(fetched) => fetched,
+        Err(e) => return (None, Err(e)),
+    };
+    if let Some(warning) = warning {
+        if !ctx
+            .warnings
+            .iter()
+            .any(|logged| logged.address == warning.address)
+        {
+            ctx.warnings.push(warning);
+            ctx.journal.push(Overwrite::Warning);
+        }
+    }
+    // dbg!(&instr, &ctx.pc);
+    // println!("{:#04x} {:#04x}", ctx.pc, &instr);
+    ctx.pc = ((ctx.pc as usize + 2) % M) as PC;
+    let res = instr_dec.execute(ctx);
+    ctx.devices.tick();
+    (Some(instr_dec), res)
+}
+
+pub fn execute<const M: usize, const R: usize>(ctx: &mut Ctx<M, R>, fuel: usize) -> (usize, Res) {
+    run(ctx, fuel, step)
+}
+
+/// Like [execute], but records every instruction executed in the trace.
+pub fn execute_traced<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    fuel: usize,
+    trace: &mut trace::Trace,
+) -> (usize, Res) {
+    run(ctx, fuel, |ctx| trace.step(ctx))
+}
+
+fn run<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    mut fuel: usize,
+    mut step: impl FnMut(&mut Ctx<M, R>) -> Res,
+) -> (usize, Res) {
     while let Some(remaining) = fuel.checked_sub(1) {
         fuel = remaining;
-        let instr = u16::from_be_bytes(
-            <[u8; 2]>::try_from(&ctx.memory[ctx.pc as usize..=ctx.pc as usize + 1]).unwrap(),
-        );
-        let instr_dec = instructions::Instr::decode(instr).unwrap();
-        // assert_eq!(instr, instr_dec.encode());
-        // dbg!(&instr, &ctx.pc);
-        // println!("{:#04x} {:#04x}", ctx.pc, &instr);
-        ctx.pc += 2;
-        let res = instr_dec.execute(ctx);
+        let res = step(ctx);
         if res.is_err() {
             return (fuel, res);
         };
     }
     (fuel, Res::Ok(()))
 }
+
+#[cfg(test)]
+#[test]
+fn execute_reports_faults() {
+    use instructions::DecodeError;
+
+    let mut ctx: Ctx = Ctx::new([0; 256]);
+    // jump 0xFF
+    ctx.memory[..2].copy_from_slice(&[0xB0, 0xFF]);
+    assert_eq!(execute(&mut ctx, 8), (6, Err(Err::PcOutOfRange(0xFF))));
+
+    ctx.pc = 0x10;
+    assert_eq!(
+        execute(&mut ctx, 8),
+        (
+            7,
+            Err(Err::DecodeFailed {
+                address: 0x10,
+                error: DecodeError::NullInstruction
+            })
+        )
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn execute_runs_past_256_bytes() {
+    let mut ctx = machine_code::WideCtx::new([0; machine_code::MAX_MEMORY_SIZE]);
+    // Fill the first page with no_op, and halt just after it
+    for word in ctx.memory[..0x100].chunks_mut(2) {
+        word.copy_from_slice(&[0x0F, 0xFF]);
+    }
+    ctx.memory[0x100..0x102].copy_from_slice(&[0xC0, 0x00]);
+
+    assert_eq!(execute(&mut ctx, 256), (127, Err(Err::HaltExecution)));
+    assert_eq!(ctx.pc, 0x102);
+}
+
+#[cfg(test)]
+#[test]
+fn wide_profile_reaches_every_page_and_register() {
+    let mut ctx: Ctx<{ machine_code::MAX_MEMORY_SIZE }, 32> =
+        Ctx::new([0; machine_code::MAX_MEMORY_SIZE]);
+    ctx.isa = instructions::IsaProfile::Wide;
+    // load_value r1, 0x12; load_value r2, 0x34; far_jump r1, r2
+    ctx.memory[..6].copy_from_slice(&[0x21, 0x12, 0x22, 0x34, 0x05, 0x12]);
+    // load_value r3, 0x1F; load_value r4, 0xAB; store_register r4, r3; set_page r1; store_memory r4, 0x80;
+    // load_register r5, r3; jump 0x44; halt
+    ctx.memory[0x1234..0x1246].copy_from_slice(&[
+        0x23, 0x1F, 0x24, 0xAB, 0x07, 0x43, 0x04, 0x01, 0x34, 0x80, 0x06, 0x53, 0xB0, 0x44, 0x00,
+        0x00, 0xC0, 0x00,
+    ]);
+
+    assert_eq!(execute(&mut ctx, 16), (5, Err(Err::HaltExecution)));
+    assert_eq!(ctx.pc, 0x1246);
+    assert_eq!((ctx.registers[5], ctx.registers[31]), (0xAB, 0xAB));
+    assert_eq!(ctx.memory[0x1280], 0xAB);
+}
+
+#[cfg(test)]
+#[test]
+fn decode_policy_decides_sloppy_words() {
+    use instructions::{DecodeError, DecodePolicy};
+
+    let mut memory = [0; 256];
+    // bitwise_rotate r2, 3 with a reserved nibble set, a jump_with_test with test 6, and halt
+    memory[..6].copy_from_slice(&[0xA2, 0xF3, 0xF1, 0x60, 0xC0, 0x00]);
+    let sloppy = |policy, pc| {
+        let mut ctx: Ctx = Ctx::new(memory);
+        ctx.pc = pc;
+        ctx.decode_policy = policy;
+        let res = execute(&mut ctx, 8).1;
+        (res, ctx.warnings.len())
+    };
+    let failed = |address, error| Err(Err::DecodeFailed { address, error });
+    let word = |word: u16| word.try_into().unwrap();
+
+    assert_eq!(
+        sloppy(DecodePolicy::Lenient, 0),
+        (failed(0, DecodeError::InvalidInstruction(word(0xA2F3))), 0)
+    );
+    assert_eq!(
+        sloppy(DecodePolicy::Lenient, 2),
+        (Err(Err::HaltExecution), 0)
+    );
+    assert_eq!(
+        sloppy(DecodePolicy::Strict, 0),
+        (failed(0, DecodeError::ReservedBits(word(0xA2F3))), 0)
+    );
+    assert_eq!(
+        sloppy(DecodePolicy::Strict, 2),
+        (failed(2, DecodeError::UnknownTest(word(0xF160))), 0)
+    );
+    assert_eq!(sloppy(DecodePolicy::Warn, 0), (Err(Err::HaltExecution), 2));
+}
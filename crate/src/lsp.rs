@@ -0,0 +1,631 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+use logos::Logos;
+use lsp_server::{Connection, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+    Notification as NotificationTrait, PublishDiagnostics,
+};
+use lsp_types::request::{
+    Completion, GotoDefinition, HoverRequest, References, Request as RequestTrait,
+    SemanticTokensFullRequest,
+};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+    DiagnosticRelatedInformation, DiagnosticSeverity, Documentation, GotoDefinitionParams,
+    GotoDefinitionResponse, Hover, HoverContents, HoverParams, HoverProviderCapability, Location,
+    MarkupContent, MarkupKind, OneOf, Position, PublishDiagnosticsParams, ReferenceParams,
+    SemanticToken, SemanticTokenType, SemanticTokens, SemanticTokensFullOptions,
+    SemanticTokensLegend, SemanticTokensOptions, SemanticTokensParams, SemanticTokensResult,
+    SemanticTokensServerCapabilities, ServerCapabilities, TextDocumentPositionParams,
+    TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+
+use crate::assembler::{assemble_sources, parse_register};
+use crate::diagnostics::{Diagnostic, Severity, SourceId, Sources};
+use crate::highlight::{classify, TokenClass};
+use crate::instructions::IsaProfile;
+use crate::lexer::Token;
+use crate::preprocessor::{preprocess, Origin};
+
+/// The semantic token types, indexed by the `token_type` of each [SemanticToken].
+const TOKEN_TYPES: &[SemanticTokenType] = &[
+    SemanticTokenType::COMMENT,
+    SemanticTokenType::NUMBER,
+    SemanticTokenType::MACRO,
+    SemanticTokenType::FUNCTION,
+    SemanticTokenType::STRING,
+    SemanticTokenType::KEYWORD,
+    SemanticTokenType::VARIABLE,
+];
+
+fn token_type(class: TokenClass, text: &str, isa: &IsaProfile) -> Option<u32> {
+    match class {
+        TokenClass::Comment => Some(0),
+        TokenClass::Number => Some(1),
+        TokenClass::Directive => Some(2),
+        TokenClass::Label => Some(3),
+        TokenClass::String => Some(4),
+        TokenClass::Identifier if isa.spec(text).is_some() => Some(5),
+        TokenClass::Identifier if parse_register(text).is_some() => Some(6),
+        // Anything else is a label or constant.
+        TokenClass::Identifier => Some(3),
+        TokenClass::Unknown | TokenClass::Address | TokenClass::Opcode | TokenClass::Operand => {
+            None
+        }
+    }
+}
+
+/// The LSP position of a byte offset, counting columns in UTF-16 code units.
+fn position(text: &str, offset: usize) -> Position {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    Position::new(
+        before.matches('\n').count() as u32,
+        before[line_start..].encode_utf16().count() as u32,
+    )
+}
+
+/// The byte offset of an LSP position, clamped to the end of its line.
+fn offset(text: &str, position: Position) -> usize {
+    let line_start: usize = text
+        .split_inclusive('\n')
+        .take(position.line as usize)
+        .map(str::len)
+        .sum();
+    let mut units = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if units >= position.character as usize || c == '\n' {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+fn range(text: &str, span: &Range<usize>) -> lsp_types::Range {
+    lsp_types::Range::new(position(text, span.start), position(text, span.end))
+}
+
+/// Every token in a source except those which fail to lex.
+fn tokens(text: &str) -> impl Iterator<Item = (Token, Range<usize>)> + '_ {
+    Token::lexer(text)
+        .spanned()
+        .filter_map(|(token, span)| Some((token.ok()?, span)))
+}
+
+/// The name of the label or constant used or defined at an offset, and its span.
+fn symbol_at(text: &str, offset: usize) -> Option<(&str, Range<usize>)> {
+    tokens(text)
+        .find(|(token, span)| {
+            matches!(token, Token::Identifier | Token::Label) && span.contains(&offset)
+                || span.end == offset && *token == Token::Identifier
+        })
+        .map(|(_, span)| {
+            let name = text[span.clone()].trim_end_matches(':');
+            (name, span.start..span.start + name.len())
+        })
+}
+
+/// A label or constant defined or used in a program.
+struct Symbol {
+    /// The name after expanding macros, so a label local to a macro is named `name@N`.
+    name: String,
+    /// Where the name is written, without the `@N`.
+    origin: Origin,
+    definition: bool,
+}
+
+/// Every label and `.equ` constant defined or used in a program, including those in the files it includes and the macros it expands.
+/// Included files are read with `load`.
+fn symbols(
+    sources: &mut Sources,
+    root: SourceId,
+    load: &mut dyn FnMut(&Path) -> io::Result<String>,
+) -> Vec<Symbol> {
+    let mut symbols = vec![];
+    for line in preprocess(sources, root, load, &mut vec![]) {
+        let mut after_equ = false;
+        for tok in line {
+            let definition = match tok.token {
+                Token::Label => true,
+                Token::Identifier => after_equ,
+                _ => {
+                    after_equ =
+                        tok.token == Token::Directive && tok.text.eq_ignore_ascii_case(".equ");
+                    continue;
+                }
+            };
+            after_equ = false;
+            let name = tok.text.trim_end_matches(':');
+            let written = name.split('@').next().unwrap_or_default().len();
+            let mut origin = tok.origin;
+            origin.span = origin.span.start..origin.span.start + written;
+            symbols.push(Symbol {
+                name: name.to_owned(),
+                origin,
+                definition,
+            });
+        }
+    }
+    symbols
+}
+
+/// The names of the symbols written at an offset in the root source.
+/// A label in a macro has a different name in each expansion of the macro.
+fn names_at(symbols: &[Symbol], root: SourceId, offset: usize) -> Vec<&str> {
+    symbols
+        .iter()
+        .filter(|symbol| {
+            let span = &symbol.origin.span;
+            symbol.origin.source == root && (span.contains(&offset) || span.end == offset)
+        })
+        .map(|symbol| symbol.name.as_str())
+        .collect()
+}
+
+fn semantic_tokens(text: &str, isa: &IsaProfile) -> Vec<SemanticToken> {
+    let mut data = vec![];
+    let mut previous = Position::new(0, 0);
+    for (token, span) in tokens(text) {
+        let Some(token_type) =
+            classify(token).and_then(|class| token_type(class, &text[span.clone()], isa))
+        else {
+            continue;
+        };
+        // Tokens can not span lines, so block comments are split into one token per line.
+        let mut start = span.start;
+        for piece in text[span.clone()].split('\n') {
+            let length = piece.trim_end_matches('\r').encode_utf16().count() as u32;
+            let current = position(text, start);
+            if length > 0 {
+                let delta_line = current.line - previous.line;
+                data.push(SemanticToken {
+                    delta_line,
+                    delta_start: if delta_line == 0 {
+                        current.character - previous.character
+                    } else {
+                        current.character
+                    },
+                    length,
+                    token_type,
+                    token_modifiers_bitset: 0,
+                });
+                previous = current;
+            }
+            start += piece.len() + 1;
+        }
+    }
+    data
+}
+
+/// The URI of a source, which is `uri` for the root source and the canonical path of an included file.
+fn source_uri(sources: &Sources, source: SourceId, root: SourceId, uri: &Url) -> Option<Url> {
+    if source == root {
+        Some(uri.clone())
+    } else {
+        Url::from_file_path(fs::canonicalize(&sources.get(source).name).ok()?).ok()
+    }
+}
+
+/// The sources of a document, which only contain the document until its includes are read.
+fn document_sources(uri: &Url, text: &str) -> (Sources, SourceId) {
+    let name = uri
+        .to_file_path()
+        .map_or_else(|_| uri.to_string(), |path| path.display().to_string());
+    let mut sources = Sources::default();
+    let root = sources.add(name, text);
+    (sources, root)
+}
+
+/// Converts a diagnostic for LSP, which can only show it in a single file.
+/// Errors in included files are shown at the first location in the root file which led to them.
+fn lsp_diagnostic(
+    diagnostic: &Diagnostic,
+    sources: &Sources,
+    root: SourceId,
+    uri: &Url,
+) -> lsp_types::Diagnostic {
+    let text = &sources.get(root).text;
+    let primary = diagnostic
+        .labels
+        .iter()
+        .position(|label| label.location.source == root);
+    let related = diagnostic
+        .labels
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| Some(i) != primary)
+        .filter_map(|(_, label)| {
+            let source = label.location.source;
+            let uri = source_uri(sources, source, root, uri)?;
+            Some(DiagnosticRelatedInformation {
+                location: Location::new(
+                    uri,
+                    range(&sources.get(source).text, &label.location.span),
+                ),
+                message: label
+                    .message
+                    .clone()
+                    .unwrap_or_else(|| diagnostic.message.clone()),
+            })
+        })
+        .collect();
+
+    let mut message = diagnostic.message.clone();
+    for extra in [&diagnostic.note, &diagnostic.help].into_iter().flatten() {
+        message.push('\n');
+        message.push_str(extra);
+    }
+    lsp_types::Diagnostic {
+        range: primary.map_or_else(Default::default, |i| {
+            range(text, &diagnostic.labels[i].location.span)
+        }),
+        severity: Some(match diagnostic.severity {
+            Severity::Error => DiagnosticSeverity::ERROR,
+            Severity::Warning => DiagnosticSeverity::WARNING,
+        }),
+        source: Some("bmc".to_owned()),
+        message,
+        related_information: Some(related),
+        ..Default::default()
+    }
+}
+
+/// Assembles a document, reading any files it includes from disk.
+fn diagnostics(uri: &Url, text: &str, isa: &IsaProfile) -> Vec<lsp_types::Diagnostic> {
+    let (mut sources, root) = document_sources(uri, text);
+    match assemble_sources(&mut sources, root, isa, &mut |path| {
+        fs::read_to_string(path)
+    }) {
+        Ok(_) => vec![],
+        Err(errors) => errors
+            .iter()
+            .map(|error| lsp_diagnostic(&error.diagnostic(), &sources, root, uri))
+            .collect(),
+    }
+}
+
+fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        semantic_tokens_provider: Some(SemanticTokensServerCapabilities::SemanticTokensOptions(
+            SemanticTokensOptions {
+                legend: SemanticTokensLegend {
+                    token_types: TOKEN_TYPES.to_vec(),
+                    token_modifiers: vec![],
+                },
+                full: Some(SemanticTokensFullOptions::Bool(true)),
+                ..Default::default()
+            },
+        )),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        ..Default::default()
+    }
+}
+
+/// The open documents, which are kept in sync with the editor.
+struct Server {
+    documents: HashMap<Url, String>,
+    isa: IsaProfile,
+}
+
+impl Server {
+    fn notification(&mut self, notification: Notification) -> Option<Notification> {
+        let uri = match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params = notification
+                    .extract::<lsp_types::DidOpenTextDocumentParams>(DidOpenTextDocument::METHOD)
+                    .ok()?;
+                let uri = params.text_document.uri;
+                self.documents
+                    .insert(uri.clone(), params.text_document.text);
+                uri
+            }
+            DidChangeTextDocument::METHOD => {
+                let params = notification
+                    .extract::<lsp_types::DidChangeTextDocumentParams>(
+                        DidChangeTextDocument::METHOD,
+                    )
+                    .ok()?;
+                let uri = params.text_document.uri;
+                // Changes are always the whole document, since that is the only sync kind supported.
+                let change = params.content_changes.into_iter().last()?;
+                self.documents.insert(uri.clone(), change.text);
+                uri
+            }
+            DidCloseTextDocument::METHOD => {
+                let params = notification
+                    .extract::<lsp_types::DidCloseTextDocumentParams>(DidCloseTextDocument::METHOD)
+                    .ok()?;
+                self.documents.remove(&params.text_document.uri);
+                params.text_document.uri
+            }
+            _ => return None,
+        };
+        let diagnostics = match self.documents.get(&uri) {
+            Some(text) => diagnostics(&uri, text, &self.isa),
+            None => vec![],
+        };
+        Some(Notification::new(
+            PublishDiagnostics::METHOD.to_owned(),
+            PublishDiagnosticsParams::new(uri, diagnostics, None),
+        ))
+    }
+
+    fn request(&self, request: Request) -> Response {
+        let id = request.id.clone();
+        let result = match request.method.as_str() {
+            SemanticTokensFullRequest::METHOD => self
+                .handle::<SemanticTokensFullRequest>(request, |server, params| {
+                    server.semantic_tokens(params)
+                }),
+            GotoDefinition::METHOD => {
+                self.handle::<GotoDefinition>(request, |server, params| server.definition(params))
+            }
+            References::METHOD => {
+                self.handle::<References>(request, |server, params| server.references(params))
+            }
+            HoverRequest::METHOD => {
+                self.handle::<HoverRequest>(request, |server, params| server.hover(params))
+            }
+            Completion::METHOD => {
+                self.handle::<Completion>(request, |server, params| server.completion(params))
+            }
+            _ => {
+                return Response::new_err(
+                    id,
+                    lsp_server::ErrorCode::MethodNotFound as i32,
+                    format!("Unsupported request {}", request.method),
+                )
+            }
+        };
+        match result {
+            Ok(value) => Response::new_ok(id, value),
+            Err(message) => {
+                Response::new_err(id, lsp_server::ErrorCode::InvalidParams as i32, message)
+            }
+        }
+    }
+
+    fn handle<R: RequestTrait>(
+        &self,
+        request: Request,
+        f: impl FnOnce(&Self, R::Params) -> R::Result,
+    ) -> Result<serde_json::Value, String> {
+        let (_, params) = request
+            .extract::<R::Params>(R::METHOD)
+            .map_err(|e| format!("{:?}", e))?;
+        serde_json::to_value(f(self, params)).map_err(|e| e.to_string())
+    }
+
+    fn semantic_tokens(&self, params: SemanticTokensParams) -> Option<SemanticTokensResult> {
+        let text = self.documents.get(&params.text_document.uri)?;
+        Some(SemanticTokensResult::Tokens(SemanticTokens {
+            result_id: None,
+            data: semantic_tokens(text, &self.isa),
+        }))
+    }
+
+    /// The symbols of an open document and the sources they are written in.
+    /// Included files are read from the open documents if they are open, and from disk otherwise.
+    fn symbols(&self, uri: &Url) -> Option<(Sources, SourceId, Vec<Symbol>)> {
+        let text = self.documents.get(uri)?;
+        let (mut sources, root) = document_sources(uri, text);
+        let symbols = symbols(&mut sources, root, &mut |path| {
+            let open = fs::canonicalize(path)
+                .ok()
+                .and_then(|path| Url::from_file_path(path).ok())
+                .and_then(|uri| self.documents.get(&uri));
+            match open {
+                Some(text) => Ok(text.clone()),
+                None => fs::read_to_string(path),
+            }
+        });
+        Some((sources, root, symbols))
+    }
+
+    /// The locations of the symbols named at a position in a document which match `filter`, in the order they are written.
+    fn locations(
+        &self,
+        params: TextDocumentPositionParams,
+        filter: impl Fn(&Symbol) -> bool,
+    ) -> Option<Vec<Location>> {
+        let uri = params.text_document.uri;
+        let (sources, root, symbols) = self.symbols(&uri)?;
+        let names = names_at(
+            &symbols,
+            root,
+            offset(&sources.get(root).text, params.position),
+        );
+        let mut locations: Vec<Location> = vec![];
+        for symbol in symbols
+            .iter()
+            .filter(|symbol| names.contains(&symbol.name.as_str()))
+        {
+            let source = symbol.origin.source;
+            let location = Location::new(
+                source_uri(&sources, source, root, &uri)?,
+                range(&sources.get(source).text, &symbol.origin.span),
+            );
+            // Each expansion of a macro repeats the symbols written in it.
+            if filter(symbol) && !locations.contains(&location) {
+                locations.push(location);
+            }
+        }
+        Some(locations)
+    }
+
+    fn definition(&self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+        let locations = self.locations(params.text_document_position_params, |symbol| {
+            symbol.definition
+        })?;
+        Some(GotoDefinitionResponse::Scalar(
+            locations.into_iter().next()?,
+        ))
+    }
+
+    fn references(&self, params: ReferenceParams) -> Option<Vec<Location>> {
+        let include_declaration = params.context.include_declaration;
+        self.locations(params.text_document_position, |symbol| {
+            include_declaration || !symbol.definition
+        })
+    }
+
+    fn hover(&self, params: HoverParams) -> Option<Hover> {
+        let params = params.text_document_position_params;
+        let text = self.documents.get(&params.text_document.uri)?;
+        let (name, span) = symbol_at(text, offset(text, params.position))?;
+        let spec = self.isa.spec(name)?;
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: format!("```\n{}\n```\n\n{}", spec.syntax(), spec.description()),
+            }),
+            range: Some(range(text, &span)),
+        })
+    }
+
+    fn completion(&self, params: CompletionParams) -> Option<CompletionResponse> {
+        let mnemonics = self.isa.specs().iter().map(|spec| CompletionItem {
+            label: spec.mnemonic.to_string(),
+            kind: Some(CompletionItemKind::KEYWORD),
+            detail: Some(spec.syntax()),
+            documentation: Some(Documentation::MarkupContent(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: spec.description(),
+            })),
+            ..Default::default()
+        });
+        let registers = (0..16).map(|r| CompletionItem {
+            label: format!("r{}", r),
+            kind: Some(CompletionItemKind::VARIABLE),
+            ..Default::default()
+        });
+        let (_, _, symbols) = self.symbols(&params.text_document_position.text_document.uri)?;
+        let mut names: Vec<String> = symbols
+            .into_iter()
+            .filter(|symbol| symbol.definition && !symbol.name.contains('@'))
+            .map(|symbol| symbol.name)
+            .collect();
+        names.sort();
+        names.dedup();
+        let labels = names.into_iter().map(|name| CompletionItem {
+            label: name,
+            kind: Some(CompletionItemKind::REFERENCE),
+            ..Default::default()
+        });
+        Some(CompletionResponse::Array(
+            mnemonics.chain(registers).chain(labels).collect(),
+        ))
+    }
+}
+
+/// Runs a language server for assembly files over stdin and stdout, until the editor shuts it down.
+/// Documents are checked against the mnemonics of `isa`.
+pub fn run(isa: IsaProfile) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let (connection, io_threads) = Connection::stdio();
+    connection.initialize(serde_json::to_value(capabilities())?)?;
+
+    let mut server = Server {
+        documents: HashMap::new(),
+        isa,
+    };
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    break;
+                }
+                let response = server.request(request);
+                connection.sender.send(Message::Response(response))?;
+            }
+            Message::Notification(notification) => {
+                if let Some(publish) = server.notification(notification) {
+                    connection.sender.send(Message::Notification(publish))?;
+                }
+            }
+            Message::Response(_) => {}
+        }
+    }
+    // The writer thread only finishes once the connection is dropped.
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn lsp_finds_labels_and_tokens() {
+    let text = "loop: jump loop // forever\n.equ LIMIT, 3\nload_value r1, LIMIT\n";
+    let start = offset(text, Position::new(0, 12));
+    assert_eq!(symbol_at(text, start), Some(("loop", 11..15)));
+    assert_eq!(position(text, 56), Position::new(2, 15));
+
+    let types: Vec<(u32, u32, u32)> = semantic_tokens(text, &IsaProfile::Classic)
+        .iter()
+        .map(|token| (token.delta_line, token.delta_start, token.token_type))
+        .collect();
+    assert_eq!(
+        types,
+        [
+            (0, 0, 3),
+            (0, 6, 5),
+            (0, 5, 3),
+            (0, 5, 0),
+            (1, 0, 2),
+            (0, 5, 3),
+            (0, 7, 1),
+            (1, 0, 5),
+            (0, 11, 6),
+            (0, 4, 3)
+        ]
+    );
+
+    let spec = IsaProfile::Classic.spec("load_value").unwrap();
+    assert!(spec
+        .description()
+        .starts_with("Load value (immediate addressing)."));
+}
+
+#[cfg(test)]
+#[test]
+fn lsp_resolves_labels_through_includes_and_macros() {
+    let library = ".equ LIMIT, 3\n.macro spin\nloop: jump loop\n.endm\n";
+    let program = ".include \"lib.s\"\nspin\nspin\nload_value r1, LIMIT\n";
+    let mut load = |path: &Path| match path.to_str() {
+        Some("lib/lib.s") => Ok(library.to_owned()),
+        _ => Err(io::Error::from(io::ErrorKind::NotFound)),
+    };
+    let mut sources = Sources::default();
+    let root = sources.add("lib/main.s", program);
+    let symbols = symbols(&mut sources, root, &mut load);
+    let library: SourceId = 1;
+
+    let names = names_at(&symbols, root, program.find("LIMIT").unwrap());
+    assert_eq!(names, ["LIMIT"]);
+    let definition = symbols
+        .iter()
+        .find(|symbol| symbol.definition && symbol.name == "LIMIT")
+        .unwrap();
+    assert_eq!(definition.origin.location().source, library);
+    assert_eq!(definition.origin.span, 5..10);
+
+    // Each expansion of the macro has its own label, written in the same place.
+    let names = names_at(&symbols, library, 27);
+    assert_eq!(names, ["loop@1", "loop@2"]);
+    let loops: Vec<_> = symbols
+        .iter()
+        .filter(|symbol| symbol.name == "loop@2")
+        .map(|symbol| (symbol.origin.span.clone(), symbol.definition))
+        .collect();
+    assert_eq!(loops, [(26..30, true), (37..41, false)]);
+}
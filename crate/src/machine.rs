@@ -0,0 +1,169 @@
+use std::collections::BTreeSet;
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+use crate::history::History;
+use crate::machine_code::{Ctx, Err, MEMORY_SIZE, PC, REGISTER_COUNT};
+use crate::step_instr;
+use crate::trace::Trace;
+
+/// Why a [Machine] stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum StopReason {
+    /// The program ran a halt instruction.
+    Halted,
+    /// The fuel ran out before the program stopped.
+    OutOfFuel,
+    /// The program stopped with an error.
+    Fault(Err),
+    /// The program counter reached a breakpoint.
+    Breakpoint(PC),
+    /// The predicate passed to [Machine::run_until] was satisfied.
+    Condition,
+}
+
+impl Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StopReason::Halted => write!(f, "Program halted"),
+            StopReason::OutOfFuel => write!(f, "Ran out of fuel"),
+            StopReason::Fault(e) => write!(f, "Encountered error: {}", e),
+            StopReason::Breakpoint(pc) => write!(f, "Hit breakpoint at {:02X}", pc),
+            StopReason::Condition => write!(f, "Stopped on condition"),
+        }
+    }
+}
+
+/// Drives a [Ctx], counting the instructions it runs and stopping at breakpoints.
+/// Once the program halts or faults, the machine stays stopped until it is stepped back.
+#[derive(Debug, Clone)]
+pub struct Machine<const M: usize = MEMORY_SIZE, const R: usize = REGISTER_COUNT> {
+    pub ctx: Ctx<M, R>,
+    pub breakpoints: BTreeSet<PC>,
+    /// The number of instructions executed.
+    pub cycles: usize,
+    /// Records every instruction executed, if set.
+    pub trace: Option<Trace>,
+    /// Records the values overwritten by every instruction, if set, so that they can be undone.
+    pub history: Option<History>,
+    stopped: Option<StopReason>,
+}
+
+impl<const M: usize, const R: usize> Machine<M, R> {
+    pub fn new(ctx: Ctx<M, R>) -> Self {
+        Self {
+            ctx,
+            breakpoints: BTreeSet::new(),
+            cycles: 0,
+            trace: None,
+            history: None,
+            stopped: None,
+        }
+    }
+
+    /// Why the program stopped, if it has halted or faulted.
+    pub fn stopped(&self) -> Option<StopReason> {
+        self.stopped
+    }
+
+    /// Executes the instruction at the program counter, returning why the program stopped if it did.
+    pub fn step(&mut self) -> Option<StopReason> {
+        if self.stopped.is_some() {
+            return self.stopped;
+        }
+        let pc = self.ctx.pc;
+        let res = match step_instr(&mut self.ctx) {
+            (Some(instr), res) => {
+                self.cycles += 1;
+                if let Some(trace) = &mut self.trace {
+                    trace.record(instr, pc, &self.ctx);
+                }
+                if let Some(history) = &mut self.history {
+                    history.record(pc, &self.ctx);
+                }
+                res
+            }
+            (None, res) => res,
+        };
+        self.stopped = match res {
+            Ok(()) => None,
+            Err(Err::HaltExecution) => Some(StopReason::Halted),
+            Err(e) => Some(StopReason::Fault(e)),
+        };
+        self.stopped
+    }
+
+    /// Undoes the most recently executed instruction.
+    /// Returns `false` if there is nothing to undo, or no history is being kept.
+    pub fn step_back(&mut self) -> bool {
+        let undone = self
+            .history
+            .as_mut()
+            .is_some_and(|history| history.step_back(&mut self.ctx));
+        if undone {
+            self.cycles -= 1;
+            self.stopped = None;
+        }
+        undone
+    }
+
+    /// Runs up to `fuel` instructions, stopping early if the program stops or reaches a breakpoint.
+    pub fn run(&mut self, fuel: usize) -> StopReason {
+        self.run_until(fuel, |_| false)
+    }
+
+    /// Like [Machine::run], but also stops once `predicate` holds after an instruction.
+    /// A breakpoint at the starting program counter is ignored, so that a stopped machine can be resumed.
+    pub fn run_until(
+        &mut self,
+        fuel: usize,
+        mut predicate: impl FnMut(&Ctx<M, R>) -> bool,
+    ) -> StopReason {
+        for i in 0..fuel {
+            if i > 0 && self.breakpoints.contains(&self.ctx.pc) {
+                return StopReason::Breakpoint(self.ctx.pc);
+            }
+            if let Some(reason) = self.step() {
+                return reason;
+            }
+            if predicate(&self.ctx) {
+                return StopReason::Condition;
+            }
+        }
+        StopReason::OutOfFuel
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn machine_stops_for_each_reason() {
+    let mut ctx: Ctx = Ctx::new([0; 256]);
+    // load_value r1, 1; add_integer r2, r2, r1; jump_if_eq r2, 0x0A; jump 0x02; halt
+    ctx.memory[..8].copy_from_slice(&[0x21, 0x01, 0x52, 0x21, 0xB2, 0x0A, 0xB0, 0x02]);
+    ctx.registers[0] = 3;
+    ctx.memory[10..12].copy_from_slice(&[0xC0, 0x00]);
+    let mut machine = Machine::new(ctx);
+    machine.history = Some(History::default());
+
+    assert_eq!(machine.run(3), StopReason::OutOfFuel);
+    machine.breakpoints.insert(0x06);
+    assert_eq!(machine.run(16), StopReason::Breakpoint(0x06));
+    assert_eq!(
+        machine.run_until(16, |ctx| ctx.registers[2] == 3),
+        StopReason::Condition
+    );
+    assert_eq!(machine.run(16), StopReason::Halted);
+    assert_eq!(machine.ctx.registers[2], 3);
+    assert_eq!(machine.step(), Some(StopReason::Halted));
+
+    let cycles = machine.cycles;
+    assert!(machine.step_back());
+    assert_eq!((machine.stopped(), machine.cycles), (None, cycles - 1));
+
+    machine.ctx.memory[10..12].copy_from_slice(&[0x00, 0x42]);
+    assert!(matches!(
+        machine.run(16),
+        StopReason::Fault(Err::DecodeFailed { .. })
+    ));
+}
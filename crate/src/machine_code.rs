@@ -1,12 +1,18 @@
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 
-const MEMORY_SIZE: usize = 256;
-const REGISTER_COUNT: usize = 16;
+use crate::devices::DeviceBus;
+use crate::float;
+use crate::instructions::{DecodeError, DecodePolicy, DecodeWarning, IsaProfile};
+
+pub const MEMORY_SIZE: usize = 256;
+pub const REGISTER_COUNT: usize = 16;
+/// The largest memory which the program counter can address.
+pub const MAX_MEMORY_SIZE: usize = 1 << 16;
 
 pub type MachineMemory = [u8; MEMORY_SIZE];
-type MachineRegisters = [u8; REGISTER_COUNT];
-type PC = u8;
+pub type MachineRegisters = [u8; REGISTER_COUNT];
+pub type PC = u16;
 
 type Register = u8;
 type DirectAddress = u8;
@@ -14,30 +20,277 @@ type ImmediateValue = u8;
 
 pub type Res = Result<(), Err>;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
 #[cfg_attr(kani, derive(kani::Arbitrary))]
 pub enum Err {
     FloatingPointSaturated,
     HaltExecution,
+    /// The word at the given address is not a valid instruction.
+    DecodeFailed {
+        address: PC,
+        error: DecodeError,
+    },
+    /// The instruction at the given address runs past the end of memory.
+    PcOutOfRange(PC),
+}
+
+impl std::fmt::Display for Err {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Err::FloatingPointSaturated => write!(f, "Floating point value saturated"),
+            Err::HaltExecution => write!(f, "Execution halted"),
+            Err::DecodeFailed { address, error } => write!(f, "{:?} at {:#04x}", error, address),
+            Err::PcOutOfRange(address) => {
+                write!(
+                    f,
+                    "Instruction at {:#04x} runs past the end of memory",
+                    address
+                )
+            }
+        }
+    }
 }
 use crate::machine_code::Err::HaltExecution;
 
-#[derive(Debug)]
-pub struct Ctx {
+/// The status register, which arithmetic and bitwise instructions set from their result in instruction sets which have one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(kani, derive(kani::Arbitrary))]
+pub struct Flags {
+    /// The unsigned result did not fit, or a subtraction borrowed.
+    pub carry: bool,
+    /// The signed result did not fit, or a floating point result saturated.
+    pub overflow: bool,
+    pub zero: bool,
+    /// The top bit of the result is set.
+    pub negative: bool,
+}
+
+impl Flags {
+    /// The flags of a result which can not carry or overflow.
+    pub fn of(result: u8) -> Self {
+        Self {
+            carry: false,
+            overflow: false,
+            zero: result == 0,
+            negative: result & 0x80 != 0,
+        }
+    }
+}
+
+impl std::fmt::Display for Flags {
+    /// Writes the flags as `CVZN`, with `-` for each flag which is clear.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (set, name) in [
+            (self.carry, 'C'),
+            (self.overflow, 'V'),
+            (self.zero, 'Z'),
+            (self.negative, 'N'),
+        ] {
+            write!(f, "{}", if set { name } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+/// Adds as twos complement integers, returning the sum and its flags.
+fn add_with_flags(a: u8, b: u8, carry: bool) -> (u8, Flags) {
+    let wide = a as u16 + b as u16 + carry as u16;
+    let sum = wide as u8;
+    let flags = Flags {
+        carry: wide > 0xFF,
+        overflow: (a ^ sum) & (b ^ sum) & 0x80 != 0,
+        ..Flags::of(sum)
+    };
+    (sum, flags)
+}
+
+/// Subtracts `b` from `a` as twos complement integers, returning the difference and its flags.
+fn subtract_with_flags(a: u8, b: u8) -> (u8, Flags) {
+    let (difference, borrow) = a.overflowing_sub(b);
+    let flags = Flags {
+        carry: borrow,
+        overflow: (a ^ b) & (a ^ difference) & 0x80 != 0,
+        ..Flags::of(difference)
+    };
+    (difference, flags)
+}
+
+/// A value overwritten by an instruction, recorded as it is written so that the instruction can be traced or undone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overwrite {
+    Register {
+        register: usize,
+        old: u8,
+        new: u8,
+    },
+    Memory {
+        address: usize,
+        old: u8,
+        new: u8,
+    },
+    Flags {
+        old: Flags,
+        new: Flags,
+    },
+    Page {
+        old: u8,
+        new: u8,
+    },
+    /// A load from the device mapped at the address, which returned the value.
+    DeviceRead {
+        address: PC,
+        value: u8,
+    },
+    /// A store to the device mapped at the address.
+    DeviceWrite {
+        address: PC,
+    },
+    /// A warning pushed onto [Ctx::warnings].
+    Warning,
+}
+
+/// The state of a machine with `MEMORY` bytes of memory and `REGISTERS` registers.
+/// Instructions name the first 16 registers directly, and the rest through [load_register] and [store_register].
+/// Direct and register indirect addresses are in the page selected by [set_page], and jumps stay in the page of the program counter.
+/// It is non-exhaustive so that every machine is built by [Ctx::new], which checks the geometry.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Ctx<const MEMORY: usize = MEMORY_SIZE, const REGISTERS: usize = REGISTER_COUNT> {
     pub pc: PC,
-    pub memory: MachineMemory,
-    pub registers: MachineRegisters,
+    pub memory: [u8; MEMORY],
+    pub registers: [u8; REGISTERS],
+    /// Only set by instruction sets with a status register, see [IsaProfile::has_flags].
+    pub flags: Flags,
+    /// The high byte of direct and register indirect addresses.
+    pub page: u8,
+    /// Devices mapped over memory, which loads and stores go to instead.
+    pub devices: DeviceBus,
+    /// The instruction set which the machine decodes.
+    pub isa: IsaProfile,
+    /// How the machine treats sloppy instruction words.
+    pub decode_policy: DecodePolicy,
+    /// The sloppy instruction words executed under [DecodePolicy::Warn], once per address.
+    pub warnings: Vec<DecodeWarning>,
+    /// The values overwritten by the last instruction executed, in the order they were written.
+    pub journal: Vec<Overwrite>,
+}
+
+/// A machine with a 16-bit address space.
+pub type WideCtx = Ctx<MAX_MEMORY_SIZE, REGISTER_COUNT>;
+
+impl<const MEMORY: usize, const REGISTERS: usize> Ctx<MEMORY, REGISTERS> {
+    const GEOMETRY_IS_VALID: () = assert!(
+        MEMORY >= MEMORY_SIZE && MEMORY <= MAX_MEMORY_SIZE && REGISTERS >= REGISTER_COUNT,
+        "Machines need at least 256 bytes of memory and 16 registers, and at most 65536 bytes of memory"
+    );
+
+    /// Creates a machine with the given memory, with the program counter and registers zeroed.
+    pub fn new(memory: [u8; MEMORY]) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::GEOMETRY_IS_VALID;
+        Self {
+            pc: 0,
+            memory,
+            registers: [0; REGISTERS],
+            flags: Flags::default(),
+            page: 0,
+            devices: DeviceBus::default(),
+            isa: IsaProfile::default(),
+            decode_policy: DecodePolicy::default(),
+            warnings: Vec::new(),
+            journal: Vec::new(),
+        }
+    }
+
+    /// Loads from memory, or from the device mapped at the address.
+    pub fn read(&mut self, address: usize) -> u8 {
+        match self.devices.read(address as PC) {
+            Some(value) => {
+                self.journal.push(Overwrite::DeviceRead {
+                    address: address as PC,
+                    value,
+                });
+                value
+            }
+            None => self.memory[address],
+        }
+    }
+
+    /// Stores to memory, or to the device mapped at the address.
+    pub fn write(&mut self, address: usize, value: u8) {
+        if self.devices.write(address as PC, value) {
+            self.journal.push(Overwrite::DeviceWrite {
+                address: address as PC,
+            });
+        } else {
+            self.journal.push(Overwrite::Memory {
+                address,
+                old: self.memory[address],
+                new: value,
+            });
+            self.memory[address] = value;
+        }
+    }
+
+    /// Sets a register, recording the value it overwrites.
+    pub fn set_register(&mut self, register: usize, value: u8) {
+        self.journal.push(Overwrite::Register {
+            register,
+            old: self.registers[register],
+            new: value,
+        });
+        self.registers[register] = value;
+    }
+
+    /// Sets the page register, recording the page it overwrites.
+    pub fn set_page(&mut self, page: u8) {
+        self.journal.push(Overwrite::Page {
+            old: self.page,
+            new: page,
+        });
+        self.page = page;
+    }
+
+    /// The address of a byte in the current data page.
+    pub fn data_address(&self, low: u8) -> usize {
+        ((self.page as usize) << 8 | low as usize) % MEMORY
+    }
+
+    /// The address of a byte in the page of the program counter, which jumps go to.
+    pub fn code_address(&self, low: u8) -> PC {
+        ((self.pc as usize & 0xFF00 | low as usize) % MEMORY) as PC
+    }
+
+    /// Sets the status flags, recording the flags they overwrite.
+    /// Does nothing if the instruction set has no status register.
+    pub fn set_flags(&mut self, flags: Flags) {
+        if !self.isa.has_flags() {
+            return;
+        }
+        self.journal.push(Overwrite::Flags {
+            old: self.flags,
+            new: flags,
+        });
+        self.flags = flags;
+    }
 }
 
 #[cfg(kani)]
 impl kani::arbitrary::Arbitrary for Ctx {
     fn any() -> Self {
-        let pc = u8::any();
-        kani::assume(pc < (MEMORY_SIZE - 1) as u8);
+        let pc = PC::any();
+        kani::assume(pc < (MEMORY_SIZE - 1) as PC);
         Self {
             memory: kani::any(),
             pc,
             registers: kani::any(),
+            flags: kani::any(),
+            page: 0,
+            devices: DeviceBus::default(),
+            isa: IsaProfile::default(),
+            decode_policy: DecodePolicy::default(),
+            warnings: Vec::new(),
+            journal: Vec::new(),
         }
     }
 }
@@ -45,7 +298,7 @@ impl kani::arbitrary::Arbitrary for Ctx {
 /// No operation. Carry on to the next instruction. The data fields must all be F.
 #[cfg_attr(kani, kani::requires(true))]
 #[cfg_attr(kani, kani::ensures(result.is_ok()))]
-pub fn no_op(_ctx: &mut Ctx) -> Res {
+pub fn no_op<const M: usize, const R: usize>(_ctx: &mut Ctx<M, R>) -> Res {
     Res::Ok(())
 }
 
@@ -58,12 +311,18 @@ fn no_op_harness() {
 }
 
 /// Load from memory (direct addressing). Copy the data at memory address xy into register r.
+/// The address is in the page selected by set_page, like every direct and register indirect address.
 #[cfg_attr(kani, kani::requires(r_register < REGISTER_COUNT as u8))]
 #[cfg_attr(kani, kani::requires(xy_address < MEMORY_SIZE as u8))]
 #[cfg_attr(kani, kani::ensures(ctx.registers[r_register as usize] == ctx.memory[xy_address as usize]))]
 #[cfg_attr(kani, kani::ensures(result.is_ok()))]
-pub fn load_memory(ctx: &mut Ctx, r_register: Register, xy_address: DirectAddress) -> Res {
-    ctx.registers[r_register as usize] = ctx.memory[xy_address as usize];
+pub fn load_memory<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    r_register: Register,
+    xy_address: DirectAddress,
+) -> Res {
+    let value = ctx.read(ctx.data_address(xy_address));
+    ctx.set_register(r_register as usize, value);
     Res::Ok(())
 }
 // let mut ctx: Ctx = kani::any();
@@ -84,8 +343,12 @@ fn load_memory_harness() {
 // #[cfg_attr(kani, kani::requires(ctx.registers.len() == REGISTER_COUNT))]
 // #[cfg_attr(kani, kani::ensures(ctx.registers[r_register as usize] == xy_value))]
 // #[cfg_attr(kani, kani::ensures(result.is_ok()))]
-pub fn load_value(ctx: &mut Ctx, r_register: Register, xy_value: ImmediateValue) -> Res {
-    ctx.registers[r_register as usize] = xy_value;
+pub fn load_value<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    r_register: Register,
+    xy_value: ImmediateValue,
+) -> Res {
+    ctx.set_register(r_register as usize, xy_value);
     Res::Ok(())
 }
 
@@ -113,8 +376,13 @@ fn load_value_harness() {
 #[cfg_attr(kani, kani::requires(s_register < REGISTER_COUNT as u8))]
 #[cfg_attr(kani, kani::ensures(ctx.registers[r_register as usize] == ctx.memory[ctx.registers[s_register as usize] as usize]))]
 #[cfg_attr(kani, kani::ensures(result.is_ok()))]
-pub fn load_indirect(ctx: &mut Ctx, r_register: Register, s_register: Register) -> Res {
-    ctx.registers[r_register as usize] = ctx.memory[ctx.registers[s_register as usize] as usize];
+pub fn load_indirect<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    r_register: Register,
+    s_register: Register,
+) -> Res {
+    let value = ctx.read(ctx.data_address(ctx.registers[s_register as usize]));
+    ctx.set_register(r_register as usize, value);
     Res::Ok(())
 }
 
@@ -130,14 +398,21 @@ fn load_indirect_harness() {
 
     let val: u8 = kani::any(); // The value
 
-    let pc: u8 = kani::any();
-    kani::assume(pc < (MEMORY_SIZE - 1) as u8);
+    let pc: PC = kani::any();
+    kani::assume(pc < (MEMORY_SIZE - 1) as PC);
 
     // State with everything in the correct place
     let mut ctx: Ctx = Ctx {
         memory: kani::any_where(|mem: &MachineMemory| mem[mem_loc as usize] == val),
         pc,
         registers: kani::any_where(|reg: &MachineRegisters| reg[reg_2 as usize] == mem_loc),
+        flags: kani::any(),
+        page: 0,
+        devices: DeviceBus::default(),
+        isa: IsaProfile::default(),
+        decode_policy: DecodePolicy::default(),
+        warnings: Vec::new(),
+        journal: Vec::new(),
     };
 
     load_indirect(&mut ctx, reg_1, reg_2);
@@ -147,11 +422,7 @@ fn load_indirect_harness() {
 #[cfg(test)]
 #[test]
 fn load_indirect_works() {
-    let mut ctx = Ctx {
-        pc: 0,
-        memory: [0; MEMORY_SIZE],
-        registers: [0; REGISTER_COUNT],
-    };
+    let mut ctx: Ctx = Ctx::new([0; MEMORY_SIZE]);
 
     let reg_1 = 4;
     let reg_2 = 8;
@@ -169,8 +440,15 @@ fn load_indirect_works() {
 #[cfg_attr(kani, kani::requires(xy_address < MEMORY_SIZE as u8))]
 #[cfg_attr(kani, kani::ensures(ctx.memory[xy_address as usize] == ctx.registers[r_register as usize]))]
 #[cfg_attr(kani, kani::ensures(result.is_ok()))]
-pub fn store_memory(ctx: &mut Ctx, r_register: Register, xy_address: DirectAddress) -> Res {
-    ctx.memory[xy_address as usize] = ctx.registers[r_register as usize];
+pub fn store_memory<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    r_register: Register,
+    xy_address: DirectAddress,
+) -> Res {
+    ctx.write(
+        ctx.data_address(xy_address),
+        ctx.registers[r_register as usize],
+    );
     Res::Ok(())
 }
 
@@ -196,8 +474,15 @@ fn store_memory_harness() {
 #[cfg_attr(kani, kani::requires(s_register < REGISTER_COUNT as u8))]
 #[cfg_attr(kani, kani::ensures(ctx.memory[ctx.registers[s_register as usize] as usize] == ctx.registers[r_register as usize]))]
 #[cfg_attr(kani, kani::ensures(result.is_ok()))]
-pub fn store_indirect(ctx: &mut Ctx, r_register: Register, s_register: Register) -> Res {
-    ctx.memory[ctx.registers[s_register as usize] as usize] = ctx.registers[r_register as usize];
+pub fn store_indirect<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    r_register: Register,
+    s_register: Register,
+) -> Res {
+    ctx.write(
+        ctx.data_address(ctx.registers[s_register as usize]),
+        ctx.registers[r_register as usize],
+    );
     Res::Ok(())
 }
 
@@ -225,8 +510,12 @@ fn store_indirect_harness() {
 // #[cfg_attr(kani, kani::requires(s_register < REGISTER_COUNT as u8))]
 // #[cfg_attr(kani, kani::ensures(ctx.registers[r_register as usize] == ctx.registers[s_register as usize]))]
 // #[cfg_attr(kani, kani::ensures(result.is_ok()))]
-pub fn move_register(ctx: &mut Ctx, r_register: Register, s_register: Register) -> Res {
-    ctx.registers[s_register as usize] = ctx.registers[r_register as usize];
+pub fn move_register<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    r_register: Register,
+    s_register: Register,
+) -> Res {
+    ctx.set_register(s_register as usize, ctx.registers[r_register as usize]);
     Res::Ok(())
 }
 
@@ -252,11 +541,7 @@ fn move_register_harness() {
 #[cfg(test)]
 #[test]
 fn move_register_works() {
-    let mut ctx = Ctx {
-        pc: 0,
-        memory: [0; MEMORY_SIZE],
-        registers: [0; REGISTER_COUNT],
-    };
+    let mut ctx: Ctx = Ctx::new([0; MEMORY_SIZE]);
 
     let reg_1 = 4;
     let reg_2 = 8;
@@ -268,16 +553,20 @@ fn move_register_works() {
 }
 
 /// Add as integers. Add the contents of register s to the contents of register t as twos complement integers. Put the result into register r.
-/// The add is wrapping
-pub fn add_integer(
-    ctx: &mut Ctx,
+/// The add is wrapping, and sets the carry and overflow flags if the unsigned or signed sum did not fit.
+pub fn add_integer<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
     r_register: Register,
     s_register: Register,
     t_register: Register,
 ) -> Res {
-    ctx.registers[r_register as usize] = ((ctx.registers[t_register as usize] as i8)
-        .wrapping_add(ctx.registers[s_register as usize] as i8))
-        as u8;
+    let (sum, flags) = add_with_flags(
+        ctx.registers[t_register as usize],
+        ctx.registers[s_register as usize],
+        false,
+    );
+    ctx.set_register(r_register as usize, sum);
+    ctx.set_flags(flags);
     Res::Ok(())
 }
 
@@ -306,54 +595,28 @@ fn add_integer_harness() {
 }
 
 /// Add the contents of register s to the contents of register t as floating point values. Put the result into register r. The format is 1 sign bit, 3 exponent bits and 4 mantissa bits, SEEEMMMM, with 1 as negative.
-/// The maximum value is 7, the minimum is -8
-pub fn add_float(
-    ctx: &mut Ctx,
+/// The exponent is in excess-4 notation, so the maximum value is 7.5 and the minimum is -7.5. The sum is rounded to the nearest value, or truncated by the textbook instruction set.
+/// If the sum is too large to represent, register r is set to the largest value of the same sign, the overflow flag is set, and the add fails with [Err::FloatingPointSaturated].
+pub fn add_float<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
     r_register: Register,
     s_register: Register,
     t_register: Register,
 ) -> Res {
-    let t = ctx.registers[t_register as usize];
     let s = ctx.registers[s_register as usize];
-    let (t_sign_bit, t_exponent, t_mantissa) = (((t >> 7) & 0x1), ((t >> 4) & 0x7), (t & 0xf));
-    let (s_sign_bit, s_exponent, s_mantissa) = (((s >> 7) & 0x1), ((s >> 4) & 0x7), (s & 0xf));
-    let t_fixed = (1_i32 - 2 * (t_sign_bit as i32)) * ((t_mantissa as i32) << t_exponent);
-    let s_fixed = (1_i32 - 2 * (s_sign_bit as i32)) * ((s_mantissa as i32) << s_exponent);
-    let r = t_fixed + s_fixed;
-    let r_sign_bit: u8 = if r.is_negative() { 1 } else { 0 };
-    let r_fixed_mantissa: u32 = r.abs().try_into().unwrap();
-
-    if r_fixed_mantissa == 0 {
-        // It's just 0!
-        ctx.registers[r_register as usize] = 0;
-        return Res::Ok(());
-    }
-
-    // Attempt normalisation
-    let mut excess = 8;
-    while !((((r_fixed_mantissa >> excess) & 0xf >> 3) & 0x1) == 1 || excess == 0) {
-        excess -= 1;
-    }
-
-    if excess > 7 {
-        // Overflow
-        ctx.registers[r_register as usize] = r_sign_bit << 7 | 7 << 4 | 0xf;
-    } else {
-        ctx.registers[r_register as usize] =
-            r_sign_bit << 7 | excess << 4 | ((r_fixed_mantissa >> excess) & 0xf) as u8;
-    }
-
-    Res::Ok(())
-    //
-
-    // let t: f32 = f32::from_bits(
-    //     ((t_signbit as u32) << 31) & ((t_exponent as u32) << 23) & (t_mantissa as u32),
-    // );
-    // let s: f32 = f32::from_bits(
-    //     ((s_signbit as u32) << 31) & ((s_exponent as u32) << 23) & (s_mantissa as u32),
-    // );
-    // let r = (t + s).to_bits();
-    // let (r_signbit, r_exponent, r_mantissa) = (((r >> 31) & 0x1), ((r >> 23) & 0x7), (r & 0xf));
+    let t = ctx.registers[t_register as usize];
+    let (r, res) = match float::add(s, t, ctx.isa.float_rounding(), ctx.isa.float_excess()) {
+        Ok(r) => (r, Res::Ok(())),
+        Err((e, saturated)) => (saturated, Res::Err(e)),
+    };
+    ctx.set_register(r_register as usize, r);
+    ctx.set_flags(Flags {
+        overflow: res.is_err(),
+        // Both signs of zero are zero.
+        zero: r & 0x7F == 0,
+        ..Flags::of(r)
+    });
+    res
 }
 
 #[cfg(kani)]
@@ -382,14 +645,8 @@ fn add_float_harness() {
 
 #[cfg(test)]
 #[test]
-#[should_panic]
 fn add_float_works() {
-    // TODO!
-    let mut ctx = Ctx {
-        pc: 0,
-        memory: [0; MEMORY_SIZE],
-        registers: [0; REGISTER_COUNT],
-    };
+    let mut ctx: Ctx = Ctx::new([0; MEMORY_SIZE]);
 
     let reg_1 = 4;
     let reg_2 = 8;
@@ -398,20 +655,125 @@ fn add_float_works() {
     ctx.registers[reg_1 as usize] = 0b00010100;
     ctx.registers[reg_2 as usize] = 0b00010100;
 
-    let _ = dbg!(add_float(&mut ctx, dest, reg_1, reg_2));
-    println!("{:b}", ctx.registers[dest as usize]);
-    assert_eq!(ctx.registers[dest as usize], 0b00100100);
+    add_float(&mut ctx, dest, reg_1, reg_2).unwrap();
+    assert_eq!(ctx.registers[dest as usize], 0b00011000);
+
+    ctx.registers[reg_1 as usize] = 0b01111000;
+    assert_eq!(
+        add_float(&mut ctx, dest, reg_1, reg_1),
+        Err(Err::FloatingPointSaturated)
+    );
+    assert_eq!(ctx.registers[dest as usize], 0b01111111);
+}
+
+/// Subtract. Subtract the contents of register s from the contents of register r as twos complement integers. Put the result into register r.
+/// The subtraction is wrapping, and sets the carry flag if it borrowed and the overflow flag if the signed difference did not fit.
+/// Only available in the arithmetic instruction set.
+pub fn subtract<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    r_register: Register,
+    s_register: Register,
+) -> Res {
+    let (difference, flags) = subtract_with_flags(
+        ctx.registers[r_register as usize],
+        ctx.registers[s_register as usize],
+    );
+    ctx.set_register(r_register as usize, difference);
+    ctx.set_flags(flags);
+    Res::Ok(())
+}
+
+/// Compare. Subtract the contents of register s from the contents of register r, setting the flags like subtract, but leave register r unchanged.
+/// Only available in the arithmetic instruction set.
+pub fn compare<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    r_register: Register,
+    s_register: Register,
+) -> Res {
+    let (_, flags) = subtract_with_flags(
+        ctx.registers[r_register as usize],
+        ctx.registers[s_register as usize],
+    );
+    ctx.set_flags(flags);
+    Res::Ok(())
+}
+
+/// Add with carry. Add the contents of register s and the carry flag to the contents of register r as twos complement integers. Put the result into register r.
+/// The flags are set like add_integer, so that a chain of adds with carry can add numbers of several bytes.
+/// Only available in the arithmetic instruction set.
+pub fn add_with_carry<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    r_register: Register,
+    s_register: Register,
+) -> Res {
+    let (sum, flags) = add_with_flags(
+        ctx.registers[r_register as usize],
+        ctx.registers[s_register as usize],
+        ctx.flags.carry,
+    );
+    ctx.set_register(r_register as usize, sum);
+    ctx.set_flags(flags);
+    Res::Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn arithmetic_sets_flags() {
+    let mut ctx: Ctx = Ctx::new([0; MEMORY_SIZE]);
+    ctx.isa = IsaProfile::Arithmetic;
+    ctx.registers[..4].copy_from_slice(&[0x7F, 0x01, 0xFF, 0x80]);
+
+    add_integer(&mut ctx, 4, 0, 1).unwrap();
+    assert_eq!(
+        (ctx.registers[4], ctx.flags.to_string()),
+        (0x80, "-V-N".into())
+    );
+    add_integer(&mut ctx, 4, 1, 2).unwrap();
+    assert_eq!(
+        (ctx.registers[4], ctx.flags.to_string()),
+        (0x00, "C-Z-".into())
+    );
+
+    // 0x01FF + 0x0001, one byte at a time
+    ctx.flags = Flags::default();
+    ctx.registers[8..12].copy_from_slice(&[0x01, 0xFF, 0x00, 0x01]);
+    add_with_carry(&mut ctx, 9, 11).unwrap();
+    add_with_carry(&mut ctx, 8, 10).unwrap();
+    assert_eq!((ctx.registers[8], ctx.registers[9]), (0x02, 0x00));
+    assert!(!ctx.flags.carry);
+
+    subtract(&mut ctx, 5, 1).unwrap();
+    assert_eq!(
+        (ctx.registers[5], ctx.flags.to_string()),
+        (0xFF, "C--N".into())
+    );
+    compare(&mut ctx, 3, 0).unwrap();
+    assert_eq!(
+        (ctx.registers[3], ctx.flags.to_string()),
+        (0x80, "-V--".into())
+    );
+
+    bitwise_and(&mut ctx, 6, 3, 0).unwrap();
+    assert_eq!(ctx.flags.to_string(), "--Z-");
+    ctx.registers[7] = float::MAX;
+    assert!(add_float(&mut ctx, 6, 7, 7).is_err());
+    assert_eq!(ctx.flags.to_string(), "-V--");
+
+    ctx.isa = IsaProfile::Classic;
+    add_integer(&mut ctx, 4, 1, 2).unwrap();
+    assert_eq!(ctx.flags.to_string(), "-V--");
 }
 
 /// OR. Carry out the bitwise OR operation on the contents of register s and the contents of register t. Put the result into register r.
-pub fn bitwise_or(
-    ctx: &mut Ctx,
+pub fn bitwise_or<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
     r_register: Register,
     s_register: Register,
     t_register: Register,
 ) -> Res {
-    ctx.registers[r_register as usize] =
-        ctx.registers[t_register as usize] | ctx.registers[s_register as usize];
+    let r = ctx.registers[t_register as usize] | ctx.registers[s_register as usize];
+    ctx.set_register(r_register as usize, r);
+    ctx.set_flags(Flags::of(r));
     Res::Ok(())
 }
 
@@ -440,14 +802,15 @@ fn bitwise_or_harness() {
 }
 
 /// AND. Carry out the bitwise AND operation on the contents of register s and the contents of register t. Put the result into register r.
-pub fn bitwise_and(
-    ctx: &mut Ctx,
+pub fn bitwise_and<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
     r_register: Register,
     s_register: Register,
     t_register: Register,
 ) -> Res {
-    ctx.registers[r_register as usize] =
-        ctx.registers[t_register as usize] & ctx.registers[s_register as usize];
+    let r = ctx.registers[t_register as usize] & ctx.registers[s_register as usize];
+    ctx.set_register(r_register as usize, r);
+    ctx.set_flags(Flags::of(r));
     Res::Ok(())
 }
 
@@ -476,14 +839,15 @@ fn bitwise_and_harness() {
 }
 
 /// XOR. Carry out the bitwise exclusive or operation on the contents of register s and the contents of register t. Put the result into register r.
-pub fn bitwise_xor(
-    ctx: &mut Ctx,
+pub fn bitwise_xor<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
     r_register: Register,
     s_register: Register,
     t_register: Register,
 ) -> Res {
-    ctx.registers[r_register as usize] =
-        ctx.registers[t_register as usize] ^ ctx.registers[s_register as usize];
+    let r = ctx.registers[t_register as usize] ^ ctx.registers[s_register as usize];
+    ctx.set_register(r_register as usize, r);
+    ctx.set_flags(Flags::of(r));
     Res::Ok(())
 }
 
@@ -512,12 +876,20 @@ fn bitwise_xor_harness() {
 }
 
 /// Rotate the contents of register r by x bits to the right. Update register r with the result.
-pub fn bitwise_rotate(ctx: &mut Ctx, r_register: Register, x_amount: ImmediateValue) -> Res {
-    ctx.registers[r_register as usize] >>= x_amount;
+/// Bits shifted out of the low end are shifted back in at the high end, so rotating by 8 leaves the register unchanged.
+pub fn bitwise_rotate<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    r_register: Register,
+    x_amount: ImmediateValue,
+) -> Res {
+    let reg = ctx.registers[r_register as usize];
+    let r = reg.rotate_right(x_amount as u32);
+    ctx.set_register(r_register as usize, r);
+    ctx.set_flags(Flags::of(r));
     Res::Ok(())
 }
 
-#[cfg(never)] // Is there any way to silence kani about overflows?
+#[cfg(kani)]
 #[kani::proof]
 fn bitwise_rotate_harness() {
     let reg_1: Register = kani::any();
@@ -532,13 +904,184 @@ fn bitwise_rotate_harness() {
     };
 
     bitwise_rotate(&mut ctx, reg_1, amount);
-    use std::ops::Shr;
-    assert_eq!(ctx.registers[reg_1 as usize], val_1 >> amount)
+    let amount = amount % 8;
+    let expected = if amount == 0 {
+        val_1
+    } else {
+        (val_1 >> amount) | (val_1 << (8 - amount))
+    };
+    assert_eq!(ctx.registers[reg_1 as usize], expected)
+}
+
+/// Rotate the contents of register r by x bits to the left. Update register r with the result.
+/// Only available in the extended instruction set.
+pub fn rotate_left<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    r_register: Register,
+    x_amount: ImmediateValue,
+) -> Res {
+    let reg = ctx.registers[r_register as usize];
+    let r = reg.rotate_left(x_amount as u32);
+    ctx.set_register(r_register as usize, r);
+    ctx.set_flags(Flags::of(r));
+    Res::Ok(())
+}
+
+#[cfg(kani)]
+#[kani::proof]
+fn rotate_left_harness() {
+    let reg_1: Register = kani::any();
+    let amount: ImmediateValue = kani::any_where(|&v| v <= 0xF);
+    kani::assume(reg_1 < REGISTER_COUNT as u8);
+
+    let val_1: u8 = kani::any();
+
+    let mut ctx: Ctx = Ctx {
+        registers: kani::any_where(|reg: &MachineRegisters| reg[reg_1 as usize] == val_1),
+        ..kani::any()
+    };
+
+    rotate_left(&mut ctx, reg_1, amount);
+    bitwise_rotate(&mut ctx, reg_1, amount);
+    assert_eq!(ctx.registers[reg_1 as usize], val_1)
+}
+
+/// Shift the contents of register r by x bits to the left, filling with zeros. Update register r with the result.
+/// Only available in the extended instruction set.
+pub fn shift_left<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    r_register: Register,
+    x_amount: ImmediateValue,
+) -> Res {
+    let reg = ctx.registers[r_register as usize];
+    let r = reg.checked_shl(x_amount as u32).unwrap_or(0);
+    ctx.set_register(r_register as usize, r);
+    ctx.set_flags(Flags::of(r));
+    Res::Ok(())
+}
+
+#[cfg(kani)]
+#[kani::proof]
+fn shift_left_harness() {
+    let reg_1: Register = kani::any();
+    let amount: ImmediateValue = kani::any_where(|&v| v <= 0xF);
+    kani::assume(reg_1 < REGISTER_COUNT as u8);
+
+    let val_1: u8 = kani::any();
+
+    let mut ctx: Ctx = Ctx {
+        registers: kani::any_where(|reg: &MachineRegisters| reg[reg_1 as usize] == val_1),
+        ..kani::any()
+    };
+
+    shift_left(&mut ctx, reg_1, amount);
+    assert_eq!(
+        ctx.registers[reg_1 as usize],
+        ((val_1 as u32) << amount) as u8
+    )
+}
+
+/// Shift the contents of register r by x bits to the right, filling with zeros. Update register r with the result.
+/// Only available in the extended instruction set.
+pub fn shift_right_logical<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    r_register: Register,
+    x_amount: ImmediateValue,
+) -> Res {
+    let reg = ctx.registers[r_register as usize];
+    let r = reg.checked_shr(x_amount as u32).unwrap_or(0);
+    ctx.set_register(r_register as usize, r);
+    ctx.set_flags(Flags::of(r));
+    Res::Ok(())
+}
+
+#[cfg(kani)]
+#[kani::proof]
+fn shift_right_logical_harness() {
+    let reg_1: Register = kani::any();
+    let amount: ImmediateValue = kani::any_where(|&v| v <= 0xF);
+    kani::assume(reg_1 < REGISTER_COUNT as u8);
+
+    let val_1: u8 = kani::any();
+
+    let mut ctx: Ctx = Ctx {
+        registers: kani::any_where(|reg: &MachineRegisters| reg[reg_1 as usize] == val_1),
+        ..kani::any()
+    };
+
+    shift_right_logical(&mut ctx, reg_1, amount);
+    assert_eq!(
+        ctx.registers[reg_1 as usize],
+        ((val_1 as u32) >> amount) as u8
+    )
+}
+
+/// Shift the contents of register r by x bits to the right as a twos complement integer, filling with copies of the sign bit.
+/// Update register r with the result.
+/// Only available in the extended instruction set.
+pub fn shift_right_arithmetic<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    r_register: Register,
+    x_amount: ImmediateValue,
+) -> Res {
+    let reg = ctx.registers[r_register as usize];
+    let r = ((reg as i8) >> x_amount.min(7)) as u8;
+    ctx.set_register(r_register as usize, r);
+    ctx.set_flags(Flags::of(r));
+    Res::Ok(())
+}
+
+#[cfg(kani)]
+#[kani::proof]
+fn shift_right_arithmetic_harness() {
+    let reg_1: Register = kani::any();
+    let amount: ImmediateValue = kani::any_where(|&v| v <= 0xF);
+    kani::assume(reg_1 < REGISTER_COUNT as u8);
+
+    let val_1: u8 = kani::any();
+
+    let mut ctx: Ctx = Ctx {
+        registers: kani::any_where(|reg: &MachineRegisters| reg[reg_1 as usize] == val_1),
+        ..kani::any()
+    };
+
+    shift_right_arithmetic(&mut ctx, reg_1, amount);
+    assert_eq!(
+        ctx.registers[reg_1 as usize] as i8,
+        ((val_1 as i8 as i32) >> amount) as i8
+    )
+}
+
+#[cfg(test)]
+#[test]
+fn bitwise_rotate_works() {
+    let mut ctx: Ctx = Ctx::new([0; MEMORY_SIZE]);
+
+    ctx.registers[1] = 0b1000_0001;
+    bitwise_rotate(&mut ctx, 1, 1).unwrap();
+    assert_eq!(ctx.registers[1], 0b1100_0000);
+    bitwise_rotate(&mut ctx, 1, 8).unwrap();
+    assert_eq!(ctx.registers[1], 0b1100_0000);
+    rotate_left(&mut ctx, 1, 3).unwrap();
+    assert_eq!(ctx.registers[1], 0b0000_0110);
+
+    ctx.registers[2] = 0b1001_0110;
+    shift_right_arithmetic(&mut ctx, 2, 2).unwrap();
+    assert_eq!(ctx.registers[2], 0b1110_0101);
+    shift_right_arithmetic(&mut ctx, 2, 15).unwrap();
+    assert_eq!(ctx.registers[2], 0xFF);
+    shift_right_logical(&mut ctx, 2, 4).unwrap();
+    assert_eq!(ctx.registers[2], 0x0F);
+    shift_left(&mut ctx, 2, 3).unwrap();
+    assert_eq!(ctx.registers[2], 0x78);
+    shift_left(&mut ctx, 2, 8).unwrap();
+    assert_eq!(ctx.registers[2], 0);
 }
 
 /// Jump to memory location xy. That is, the program counter is set to xy just before the next instruction is executed.
-pub fn jump(ctx: &mut Ctx, xy_loc: DirectAddress) -> Res {
-    ctx.pc = xy_loc;
+/// The address is in the page of the next instruction, like the addresses of every jump apart from far_jump.
+pub fn jump<const M: usize, const R: usize>(ctx: &mut Ctx<M, R>, xy_loc: DirectAddress) -> Res {
+    ctx.pc = ctx.code_address(xy_loc);
     Res::Ok(())
 }
 
@@ -551,12 +1094,15 @@ fn jump_harness() {
     let mut ctx: Ctx = kani::any();
 
     jump(&mut ctx, dest);
-    assert_eq!(ctx.pc, dest)
+    assert_eq!(ctx.pc, dest as PC)
 }
 
 /// Jump to register address. Jump to the memory address stored in register t. That is, the contents of register t are copied to the program counter.
-pub fn jump_indirect(ctx: &mut Ctx, t_register: Register) -> Res {
-    ctx.pc = ctx.registers[t_register as usize];
+pub fn jump_indirect<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    t_register: Register,
+) -> Res {
+    ctx.pc = ctx.code_address(ctx.registers[t_register as usize]);
     Res::Ok(())
 }
 
@@ -574,15 +1120,19 @@ fn jump_indirect_harness() {
     };
 
     jump_indirect(&mut ctx, reg_1);
-    assert_eq!(ctx.pc, dest)
+    assert_eq!(ctx.pc, dest as PC)
 }
 
 /// Jump if equal. If the contents of register r equal the contents of register 0, jump to memory location xy.
-pub fn jump_if_eq(ctx: &mut Ctx, r_register: Register, xy_loc: DirectAddress) -> Res {
+pub fn jump_if_eq<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    r_register: Register,
+    xy_loc: DirectAddress,
+) -> Res {
     // dbg!(ctx.registers[r_register as usize], ctx.registers[0], ctx.registers[r_register as usize] == ctx.registers[0]);
     if ctx.registers[r_register as usize] == ctx.registers[0] {
         // dbg!(xy_loc);
-        ctx.pc = xy_loc;
+        ctx.pc = ctx.code_address(xy_loc);
     };
     Res::Ok(())
 }
@@ -604,7 +1154,7 @@ fn jump_if_eq_harness_jump() {
     };
 
     jump_if_eq(&mut ctx, reg_1, dest);
-    assert_eq!(ctx.pc, dest)
+    assert_eq!(ctx.pc, dest as PC)
 }
 
 #[cfg(kani)]
@@ -624,7 +1174,7 @@ fn jump_if_eq_harness_nojump() {
     };
 
     jump_if_eq(&mut ctx, reg_1, dest);
-    assert_ne!(ctx.pc, dest)
+    assert_ne!(ctx.pc, dest as PC)
 }
 
 #[repr(u8)]
@@ -636,34 +1186,113 @@ pub enum Test {
     Lte = 3,
     Gt = 4,
     Lt = 5,
+    SignedGte = 6,
+    SignedLte = 7,
+    SignedGt = 8,
+    SignedLt = 9,
     #[default]
     Never,
 }
 
 /// Jump to register address with test. The contents of register r are compared to the contents of register 0 using a test which depends on x. If the result of the test is true, a jump is made to the memory address stored in register t.
 /// The register values are treated as unsigned integers for the comparisons.
-pub fn jump_with_test(
-    ctx: &mut Ctx,
+/// The arithmetic instruction set adds tests 6 to 9, which are tests 2 to 5 with the values treated as twos complement integers.
+pub fn jump_with_test<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
     r_register: Register,
     x_test: u8,
     t_register: Register,
 ) -> Res {
+    let (r, r0) = (ctx.registers[r_register as usize], ctx.registers[0]);
     if match FromPrimitive::from_u8(x_test).unwrap_or_default() {
-        Test::Eq => ctx.registers[r_register as usize] == ctx.registers[0],
-        Test::Neq => ctx.registers[r_register as usize] != ctx.registers[0],
-        Test::Gte => ctx.registers[r_register as usize] >= ctx.registers[0],
-        Test::Lte => ctx.registers[r_register as usize] <= ctx.registers[0],
-        Test::Gt => ctx.registers[r_register as usize] > ctx.registers[0],
-        Test::Lt => ctx.registers[r_register as usize] < ctx.registers[0],
+        Test::Eq => r == r0,
+        Test::Neq => r != r0,
+        Test::Gte => r >= r0,
+        Test::Lte => r <= r0,
+        Test::Gt => r > r0,
+        Test::Lt => r < r0,
+        Test::SignedGte | Test::SignedLte | Test::SignedGt | Test::SignedLt
+            if !ctx.isa.has_signed_tests() =>
+        {
+            false
+        }
+        Test::SignedGte => r as i8 >= r0 as i8,
+        Test::SignedLte => r as i8 <= r0 as i8,
+        Test::SignedGt => r as i8 > r0 as i8,
+        Test::SignedLt => (r as i8) < r0 as i8,
         Test::Never => false,
     } {
-        ctx.pc = ctx.registers[t_register as usize];
+        ctx.pc = ctx.code_address(ctx.registers[t_register as usize]);
     };
     Res::Ok(())
 }
 
+#[cfg(test)]
+#[test]
+fn jump_with_test_compares_signed_values() {
+    let mut ctx: Ctx = Ctx::new([0; MEMORY_SIZE]);
+    // r1 is -1, which is less than r0 when signed
+    ctx.registers[..3].copy_from_slice(&[0x01, 0xFF, 0x40]);
+    jump_with_test(&mut ctx, 1, 9, 2).unwrap();
+    assert_eq!(ctx.pc, 0);
+
+    ctx.isa = IsaProfile::Arithmetic;
+    jump_with_test(&mut ctx, 1, 8, 2).unwrap();
+    assert_eq!(ctx.pc, 0);
+    jump_with_test(&mut ctx, 1, 9, 2).unwrap();
+    assert_eq!(ctx.pc, 0x40);
+}
+
+/// Set page. Copy the contents of register r into the page register, which is the high byte of direct and register indirect addresses.
+/// Only available in the wide instruction set.
+pub fn set_page<const M: usize, const R: usize>(ctx: &mut Ctx<M, R>, r_register: Register) -> Res {
+    ctx.set_page(ctx.registers[r_register as usize]);
+    Res::Ok(())
+}
+
+/// Far jump. Jump to the memory address whose high byte is in register r and whose low byte is in register s.
+/// Only available in the wide instruction set.
+pub fn far_jump<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    r_register: Register,
+    s_register: Register,
+) -> Res {
+    let address = u16::from_be_bytes([
+        ctx.registers[r_register as usize],
+        ctx.registers[s_register as usize],
+    ]);
+    ctx.pc = (address as usize % M) as PC;
+    Res::Ok(())
+}
+
+/// Load register (register indirect addressing). Copy the contents of the register whose number is in register s into register r.
+/// Register numbers wrap around the number of registers, so every register can be reached.
+/// Only available in the wide instruction set.
+pub fn load_register<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    r_register: Register,
+    s_register: Register,
+) -> Res {
+    let source = ctx.registers[s_register as usize] as usize % R;
+    ctx.set_register(r_register as usize, ctx.registers[source]);
+    Res::Ok(())
+}
+
+/// Store register (register indirect addressing). Copy the contents of register r into the register whose number is in register s.
+/// Register numbers wrap around the number of registers, so every register can be reached.
+/// Only available in the wide instruction set.
+pub fn store_register<const M: usize, const R: usize>(
+    ctx: &mut Ctx<M, R>,
+    r_register: Register,
+    s_register: Register,
+) -> Res {
+    let destination = ctx.registers[s_register as usize] as usize % R;
+    ctx.set_register(destination, ctx.registers[r_register as usize]);
+    Res::Ok(())
+}
+
 /// Stop execution.
-pub fn halt(_ctx: &mut Ctx) -> Res {
+pub fn halt<const M: usize, const R: usize>(_ctx: &mut Ctx<M, R>) -> Res {
     Res::Err(HaltExecution)
 }
 
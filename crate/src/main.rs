@@ -1,24 +1,80 @@
-use bmc::execute;
-use bmc::highlight::highlight;
-use bmc::machine_code::Ctx;
-use bmc::memory::read_memory_file;
-use clap::{Parser, Subcommand};
+use bmc::assembler::assemble_sources;
+use bmc::custom_isa::CustomIsa;
+use bmc::debugger::Debugger;
+use bmc::devices::DeviceBus;
+use bmc::diagnostics::{Diagnostic, Sources};
+use bmc::disassembler::disassemble;
+use bmc::highlight::{
+    highlight, highlight_memory, AnsiRenderer, HtmlRenderer, LatexRenderer, Renderer, Theme,
+};
+use bmc::instructions::{DecodePolicy, IsaProfile};
+use bmc::machine::Machine;
+use bmc::machine_code::{Ctx, MachineMemory, MAX_MEMORY_SIZE, MEMORY_SIZE, REGISTER_COUNT};
+use bmc::memory::{read_memory, write_memory, write_memory_file, MemoryFileOptions, MemoryFormat};
+use bmc::report::ExecutionReport;
+use bmc::trace::{Trace, TraceFormat};
+use clap::{Parser, Subcommand, ValueEnum};
 // use std::alloc::System;
 // use std::fmt::Display;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, Write};
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::sync::Arc;
+
+/// The number of instructions `execute` runs by default, so that programs which never halt still finish.
+const DEFAULT_FUEL: usize = 1 << 20;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     #[command(subcommand)]
     command: Commands,
+    /// The variant of the instruction set to decode, execute and assemble
+    #[arg(long, global = true, value_enum, default_value_t = Isa::Classic)]
+    isa: Isa,
+    /// Adds the instructions described in this TOML file to the instruction set
+    #[arg(long, global = true)]
+    isa_file: Option<String>,
+    /// How to treat instructions with reserved bits set, or jumps with unknown tests
+    #[arg(long, global = true, value_enum, default_value_t = Decode::Lenient)]
+    decode: Decode,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Executes the given machine code
-    Execute {
+    Execute(ExecuteArgs),
+    /// Rebuilds the state of the given machine code from a trace recorded by `execute` with the same `--isa` and `--isa-file`
+    Replay {
+        /// The path of the memory file the trace was recorded from
+        #[arg(short, long)]
+        file: String,
+        /// The path of the trace, as CSV if it ends in `.csv` and JSON Lines otherwise
+        #[arg(long)]
+        trace: String,
+        /// The number of instructions to replay, replaying the whole trace if not given
+        #[arg(long)]
+        steps: Option<usize>,
+    },
+    /// Steps through the given machine code interactively
+    Debug {
+        /// The path of the memory file to debug
+        #[arg(short, long)]
+        file: String,
+    },
+    /// Assembles the given assembly into a memory file
+    Assemble {
+        /// Indicates that the input is a file path
+        #[arg(short, long)]
+        file: Option<String>,
+        /// The path to write the memory file to, instead of stdout
+        #[arg(short, long)]
+        output: Option<String>,
+        /// How to write errors to stderr
+        #[arg(long, value_enum, default_value_t = DiagnosticFormat::Human)]
+        diagnostics: DiagnosticFormat,
+    },
+    /// Disassembles the given machine code
+    Disassemble {
         /// Indicates that the input is a file path
         #[arg(short, long)]
         file: Option<String>,
@@ -28,15 +84,364 @@ enum Commands {
         /// Indicates that the input is a file path
         #[arg(short, long)]
         file: Option<String>,
+        /// Highlights a memory file instead of assembly, showing the instruction each word decodes to
+        #[arg(long)]
+        memory: bool,
+        /// How to write the highlighted source
+        #[arg(long, value_enum, default_value_t = HighlightFormat::Ansi)]
+        format: HighlightFormat,
+        /// How to write errors to stderr
+        #[arg(long, value_enum, default_value_t = DiagnosticFormat::Human)]
+        diagnostics: DiagnosticFormat,
     },
+    /// Runs a language server for assembly files, speaking LSP over stdin and stdout
+    Lsp,
+}
+
+#[derive(clap::Args, Debug)]
+struct ExecuteArgs {
+    /// Indicates that the input is a file path
+    #[arg(short, long)]
+    file: Option<String>,
+    /// Records every instruction executed to this file, as CSV if it ends in `.csv` and JSON Lines otherwise
+    #[arg(long)]
+    trace: Option<String>,
+    /// Maps the tick counter, keyboard and console devices to addresses FD, FE and FF
+    #[arg(long)]
+    devices: bool,
+    /// The file the keyboard device reads from, instead of stdin
+    #[arg(long, requires = "devices")]
+    input: Option<String>,
+    /// The file the console device writes to, instead of stdout for the text format and stderr otherwise
+    #[arg(long, requires = "devices")]
+    console: Option<String>,
+    /// The maximum number of instructions to run, after which the program is reported as out of fuel
+    #[arg(long, default_value_t = DEFAULT_FUEL)]
+    fuel: usize,
+    /// How to print the final state of the machine
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+    /// Rejects memory files which write the same address more than once
+    #[arg(long)]
+    reject_overlap: bool,
+    /// Writes the final memory to this file
+    #[arg(long)]
+    dump_memory: Option<String>,
+    /// The format of the memory dump, guessed from the file extension if not given
+    #[arg(long, value_enum, requires = "dump_memory")]
+    dump_format: Option<DumpFormat>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OutputFormat {
+    /// Debug-printed arrays
+    Text,
+    /// A single JSON object, with memory and registers as hex strings
+    Json,
+    /// Aligned tables, with memory as a hex dump
+    Table,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum DiagnosticFormat {
+    /// Reports quoting the source
+    Human,
+    /// One JSON object per line, with file names and 1-based line and column positions
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum HighlightFormat {
+    /// ANSI escape codes for terminals
+    Ansi,
+    /// A `<pre>` element with CSS classes, preceded by a stylesheet
+    Html,
+    /// A fancyvrb `Verbatim` environment, preceded by the commands it uses
+    Latex,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Isa {
+    /// The twelve instructions of the textbook, with opcodes 1 to C
+    Textbook,
+    /// The textbook instructions, plus no-op, indirect loads and stores, and the jumps of opcode F
+    Classic,
+    /// The classic instructions, plus rotates and shifts
+    Extended,
+    /// The extended instructions, plus subtract, compare, add with carry and signed tests
+    Arithmetic,
+    /// The arithmetic instructions, plus a page register, far jumps and access to every register
+    Wide,
+}
+
+impl From<Isa> for IsaProfile {
+    fn from(isa: Isa) -> Self {
+        match isa {
+            Isa::Textbook => IsaProfile::Textbook,
+            Isa::Classic => IsaProfile::Classic,
+            Isa::Extended => IsaProfile::Extended,
+            Isa::Arithmetic => IsaProfile::Arithmetic,
+            Isa::Wide => IsaProfile::Wide,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Decode {
+    /// Fault on either
+    Strict,
+    /// Fault on reserved bits, and never jump on unknown tests
+    Lenient,
+    /// Execute either, warning with the address of each
+    Warn,
+}
+
+impl From<Decode> for DecodePolicy {
+    fn from(decode: Decode) -> Self {
+        match decode {
+            Decode::Strict => DecodePolicy::Strict,
+            Decode::Lenient => DecodePolicy::Lenient,
+            Decode::Warn => DecodePolicy::Warn,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum DumpFormat {
+    /// Lines of an address followed by bytes, as read by `execute`
+    Text,
+    /// Intel HEX records
+    IntelHex,
+    /// The 256 bytes of memory
+    Binary,
+}
+
+impl DumpFormat {
+    fn from_path(file_path: &str) -> Self {
+        if file_path.ends_with(".hex") || file_path.ends_with(".ihex") {
+            DumpFormat::IntelHex
+        } else if file_path.ends_with(".bin") {
+            DumpFormat::Binary
+        } else {
+            DumpFormat::Text
+        }
+    }
+}
+
+impl From<DumpFormat> for MemoryFormat {
+    fn from(format: DumpFormat) -> Self {
+        match format {
+            DumpFormat::Text => MemoryFormat::Text,
+            DumpFormat::IntelHex => MemoryFormat::IntelHex,
+            DumpFormat::Binary => MemoryFormat::Binary,
+        }
+    }
+}
+
+/// Writes diagnostics to stderr, in colour only if it is a terminal and `NO_COLOR` is not set.
+fn write_diagnostics(diagnostics: &[Diagnostic], sources: &Sources, format: DiagnosticFormat) {
+    let mut w = io::stderr();
+    let colored =
+        w.is_terminal() && std::env::var_os("NO_COLOR").is_none_or(|no_color| no_color.is_empty());
+    for diagnostic in diagnostics {
+        match format {
+            DiagnosticFormat::Human => diagnostic.write(sources, colored, &mut w),
+            DiagnosticFormat::Json => diagnostic.write_json(sources, &mut w),
+        }
+        .expect("Failed to write diagnostic");
+    }
+}
+
+/// Reads memory from the path, or stdin if there is none, exiting with diagnostics if it is malformed.
+/// The file can be in any [MemoryFormat], which is detected from its contents.
+fn load_memory(file: Option<&str>, options: MemoryFileOptions) -> Vec<u8> {
+    let mut bytes = vec![];
+    match file {
+        Some(file_path) => File::open(file_path)
+            .expect("File not found")
+            .read_to_end(&mut bytes),
+        None => io::stdin().read_to_end(&mut bytes),
+    }
+    .expect("Failed to read memory file");
+
+    match read_memory(&bytes, options) {
+        Ok(memory) => memory,
+        Err(errors) if MemoryFormat::detect(&bytes) == MemoryFormat::Binary => {
+            for error in errors {
+                eprintln!("Error: {}", error.kind);
+            }
+            std::process::exit(1);
+        }
+        Err(errors) => {
+            let mut sources = Sources::default();
+            let source = String::from_utf8(bytes).expect("Text formats are valid UTF-8");
+            let id = sources.add(file.unwrap_or_default(), source);
+            let diagnostics: Vec<Diagnostic> =
+                errors.iter().map(|error| error.diagnostic(id)).collect();
+            write_diagnostics(&diagnostics, &sources, DiagnosticFormat::Human);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn trace_format(file_path: &str) -> TraceFormat {
+    if file_path.ends_with(".csv") {
+        TraceFormat::Csv
+    } else {
+        TraceFormat::JsonLines
+    }
+}
+
+/// Runs a program in a machine with `M` bytes of memory, reporting how it stopped.
+fn execute<const M: usize>(args: ExecuteArgs, isa: IsaProfile, decode: DecodePolicy) {
+    let options = MemoryFileOptions {
+        reject_overlap: args.reject_overlap,
+        memory_size: M,
+    };
+    let memory: [u8; M] = load_memory(args.file.as_deref(), options)
+        .try_into()
+        .unwrap();
+    if let OutputFormat::Text = args.format {
+        println!("{:?}", memory);
+    }
+    let mut ctx: Ctx<M> = Ctx::new(memory);
+    ctx.isa = isa.clone();
+    ctx.decode_policy = decode;
+    if args.devices {
+        let input: Box<dyn Read> = match args.input {
+            Some(file_path) => Box::new(File::open(file_path).expect("File not found")),
+            None => Box::new(io::stdin()),
+        };
+        // The report is written to stdout, so the console output must not be mixed into it unless it is plain text.
+        let output: Box<dyn Write> = match (args.console, args.format) {
+            (Some(file_path), _) => {
+                Box::new(File::create(file_path).expect("Could not create file"))
+            }
+            (None, OutputFormat::Text) => Box::new(io::stdout()),
+            (None, _) => Box::new(io::stderr()),
+        };
+        ctx.devices = DeviceBus::standard(input, output);
+    }
+    let mut machine = Machine::new(ctx);
+    if args.trace.is_some() {
+        machine.trace = Some(Trace::default());
+    }
+    let reason = machine.run(args.fuel);
+    for warning in &machine.ctx.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+
+    if let (Some(file_path), Some(recorded)) = (&args.trace, &machine.trace) {
+        let mut w = File::create(file_path).expect("Could not create file");
+        recorded
+            .write(trace_format(file_path), &isa, &mut w)
+            .expect("Failed to write trace");
+    }
+
+    if let Some(file_path) = &args.dump_memory {
+        let format = args
+            .dump_format
+            .unwrap_or_else(|| DumpFormat::from_path(file_path));
+        let mut w = File::create(file_path).expect("Could not create file");
+        write_memory(&machine.ctx.memory, format.into(), &mut w)
+            .expect("Failed to write memory dump");
+    }
+
+    let report = ExecutionReport::new(&machine, reason, &memory);
+    match args.format {
+        OutputFormat::Text => {
+            println!("{}", report.stop);
+            println!("Used {} instruction cycles", report.cycles);
+            println!("PC: {:#04x}", report.pc);
+            println!("MEMORY: \n{:?}", report.memory);
+            println!("REGISTERS: \n{:?}", report.registers);
+            if let Some(flags) = report.flags {
+                println!("FLAGS: {}", flags);
+            }
+        }
+        OutputFormat::Json => report
+            .write_json(&mut io::stdout())
+            .expect("Failed to write report"),
+        OutputFormat::Table => report
+            .write_table(&mut io::stdout())
+            .expect("Failed to write report"),
+    }
+}
+
+/// Rebuilds the state of a machine with `M` bytes of memory from a trace.
+fn replay<const M: usize>(file: &str, trace: &str, steps: Option<usize>, isa: &IsaProfile) {
+    let options = MemoryFileOptions {
+        memory_size: M,
+        ..Default::default()
+    };
+    let memory: [u8; M] = load_memory(Some(file), options).try_into().unwrap();
+    let mut ctx: Ctx<M> = Ctx::new(memory);
+
+    let f = File::open(trace).expect("File not found");
+    let recorded =
+        Trace::read::<M, REGISTER_COUNT>(trace_format(trace), isa, &mut BufReader::new(f))
+            .expect("Failed to read trace");
+    recorded
+        .replay(&mut ctx, steps.unwrap_or(usize::MAX))
+        .expect("Failed to replay trace");
+
+    println!("PC: {:#04x}", ctx.pc);
+    println!("MEMORY: \n{:?}", ctx.memory);
+    println!("REGISTERS: \n{:?}", ctx.registers);
+}
+
+/// Debugs a program in a machine with `M` bytes of memory.
+fn debug<const M: usize>(file: &str, isa: IsaProfile, decode: DecodePolicy) {
+    let options = MemoryFileOptions {
+        memory_size: M,
+        ..Default::default()
+    };
+    let memory: [u8; M] = load_memory(Some(file), options).try_into().unwrap();
+    let mut ctx: Ctx<M> = Ctx::new(memory);
+    ctx.isa = isa;
+    ctx.decode_policy = decode;
+
+    let mut debugger = Debugger::new(ctx);
+    debugger
+        .repl(&mut io::stdin().lock(), &mut io::stdout())
+        .expect("Failed to run debugger");
 }
 
 fn main() {
     let args = Args::parse();
+    let mut isa = IsaProfile::from(args.isa);
+    if let Some(file_path) = &args.isa_file {
+        let source = std::fs::read_to_string(file_path).expect("File not found");
+        match CustomIsa::load(&source, isa) {
+            Ok(custom) => isa = IsaProfile::Custom(Arc::new(custom)),
+            Err(errors) => {
+                for error in errors {
+                    eprintln!("Error: {}", error);
+                }
+                std::process::exit(1);
+            }
+        }
+    }
 
     match args.command {
-        Commands::Execute { file } => {
-            let reader: Box<dyn BufRead> = match file {
+        Commands::Execute(execute_args) => match isa.memory_size() {
+            MAX_MEMORY_SIZE => execute::<MAX_MEMORY_SIZE>(execute_args, isa, args.decode.into()),
+            _ => execute::<MEMORY_SIZE>(execute_args, isa, args.decode.into()),
+        },
+        Commands::Replay { file, trace, steps } => match isa.memory_size() {
+            MAX_MEMORY_SIZE => replay::<MAX_MEMORY_SIZE>(&file, &trace, steps, &isa),
+            _ => replay::<MEMORY_SIZE>(&file, &trace, steps, &isa),
+        },
+        Commands::Debug { file } => match isa.memory_size() {
+            MAX_MEMORY_SIZE => debug::<MAX_MEMORY_SIZE>(&file, isa, args.decode.into()),
+            _ => debug::<MEMORY_SIZE>(&file, isa, args.decode.into()),
+        },
+        Commands::Assemble {
+            file,
+            output,
+            diagnostics: diagnostics_format,
+        } => {
+            let mut reader: Box<dyn BufRead> = match &file {
                 Some(file_path) => {
                     let path = std::path::PathBuf::from(file_path);
                     let f = File::open(path).expect("File not found");
@@ -44,32 +449,44 @@ fn main() {
                 }
                 None => Box::new(BufReader::new(io::stdin())),
             };
+            let mut source = String::new();
+            let _ = reader.read_to_string(&mut source);
 
-            let memory = read_memory_file(reader);
-            println!("{:?}", memory);
-            let mut ctx: Ctx = Ctx {
-                memory,
-                pc: 0,
-                registers: [0; 16],
-            };
-            let mut burned = 0;
-            const STEP: usize = 256;
-            while let (burn, res) = execute(&mut ctx, STEP) {
-                burned += STEP - burn;
-                if let Err(e) = res {
-                    match e {
-                        bmc::machine_code::Err::HaltExecution => return,
-                        _ => return println!("Encountered error {:?}, halting execution", e),
-                    }
+            let mut sources = Sources::default();
+            let root = sources.add(file.unwrap_or_default(), source);
+            let assembly = match assemble_sources(&mut sources, root, &isa, &mut |path| {
+                std::fs::read_to_string(path)
+            }) {
+                Ok(assembly) => assembly,
+                Err(errors) => {
+                    let diagnostics: Vec<Diagnostic> =
+                        errors.iter().map(|error| error.diagnostic()).collect();
+                    write_diagnostics(&diagnostics, &sources, diagnostics_format);
+                    std::process::exit(1);
                 }
-            }
+            };
 
-            println!("Used {} instruction cycles", burned);
-            println!("MEMORY: \n{:?}", ctx.memory);
-            println!("REGISTERS: \n{:?}", ctx.registers);
+            let mut w: Box<dyn Write> = match output {
+                Some(file_path) => {
+                    Box::new(File::create(file_path).expect("Could not create file"))
+                }
+                None => Box::new(io::stdout()),
+            };
+            write_memory_file(&assembly.memory, &mut w).expect("Failed to write memory file");
+        }
+        Commands::Disassemble { file } => {
+            let memory: MachineMemory = load_memory(file.as_deref(), MemoryFileOptions::default())
+                .try_into()
+                .unwrap();
+            disassemble(&memory, &isa, &mut io::stdout()).expect("Failed to write disassembly");
         }
-        Commands::Highlight { file } => {
-            let mut reader: Box<dyn BufRead> = match file {
+        Commands::Highlight {
+            file,
+            memory,
+            format,
+            diagnostics: diagnostics_format,
+        } => {
+            let mut reader: Box<dyn BufRead> = match &file {
                 Some(file_path) => {
                     let path = std::path::PathBuf::from(file_path);
                     let f = File::open(path).expect("File not found");
@@ -82,8 +499,25 @@ fn main() {
             let mut w: Vec<u8> = Vec::new();
             let _ = reader.read_to_string(&mut source);
 
-            highlight(&source, &mut w);
+            let mut sources = Sources::default();
+            let id = sources.add(file.unwrap_or_default(), source);
+            let mut renderer: Box<dyn Renderer> = match format {
+                HighlightFormat::Ansi => Box::new(AnsiRenderer::default()),
+                HighlightFormat::Html => Box::new(HtmlRenderer {
+                    theme: Theme::default(),
+                    stylesheet: true,
+                }),
+                HighlightFormat::Latex => Box::new(LatexRenderer::new(Theme::default(), true)),
+            };
+            let diagnostics = if memory {
+                highlight_memory(&sources, id, &isa, renderer.as_mut(), &mut w)
+            } else {
+                highlight(&sources, id, renderer.as_mut(), &mut w)
+            }
+            .expect("Failed to highlight");
             let _ = io::stdout().write(&w);
+            write_diagnostics(&diagnostics, &sources, diagnostics_format);
         }
+        Commands::Lsp => bmc::lsp::run(isa).expect("Language server failed"),
     };
 }
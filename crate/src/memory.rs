@@ -1,21 +1,604 @@
-use std::io::BufRead;
-
-use crate::machine_code::MachineMemory;
-
-pub fn read_memory_file(reader: Box<dyn BufRead>) -> MachineMemory {
-    let mut memory: MachineMemory = [0; 256];
-    for (number, line) in reader.lines().enumerate() {
-        let value = line.unwrap_or_else(|_| panic!("Failed to read line {}", number + 1));
-        let mut components = value.split_whitespace();
-        if let (Some(loc), Some(value)) = (components.next(), components.next()) {
-            let loc = u8::from_str_radix(&loc[0..=1], 16)
-                .unwrap_or_else(|_| panic!("Invalid memory location on line {}", number + 1));
-
-            for i in 0..value.len() / 2 {
-                memory[loc as usize + i] = u8::from_str_radix(&value[(i * 2)..=(i * 2) + 1], 16)
-                    .unwrap_or_else(|_| panic!("Invalid memory value on line {}", number + 1));
+use std::fmt::Display;
+use std::io::{self, Write};
+
+use crate::diagnostics::{Diagnostic, Location, SourceId, Span};
+use crate::machine_code::MEMORY_SIZE;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MemoryErrorKind {
+    InvalidAddress(String),
+    InvalidByte(String),
+    /// A line has an address but nothing to write there.
+    ExpectedValue,
+    /// The byte would be written past the end of memory, at the given address.
+    OutOfRange {
+        address: usize,
+        last: usize,
+    },
+    /// The byte at the address was already written by an earlier line.
+    Overlap(u16),
+    /// An Intel HEX record which is not a colon followed by pairs of hex digits, or whose length is wrong.
+    InvalidRecord,
+    InvalidChecksum {
+        expected: u8,
+        found: u8,
+    },
+    /// An Intel HEX record type other than data or end of file.
+    UnsupportedRecord(u8),
+}
+
+impl Display for MemoryErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MemoryErrorKind::InvalidAddress(a) => {
+                write!(f, "Invalid address `{}`, expected up to four hex digits", a)
+            }
+            MemoryErrorKind::InvalidByte(b) => {
+                write!(f, "Invalid value `{}`, expected pairs of hex digits", b)
+            }
+            MemoryErrorKind::ExpectedValue => write!(f, "Expected a value after the address"),
+            MemoryErrorKind::OutOfRange { address, last } => write!(
+                f,
+                "Address {:#x} is past the end of memory, the last address is {:#04x}",
+                address, last
+            ),
+            MemoryErrorKind::Overlap(address) => {
+                write!(f, "Address {:#04x} is written more than once", address)
+            }
+            MemoryErrorKind::InvalidRecord => write!(f, "Invalid Intel HEX record"),
+            MemoryErrorKind::InvalidChecksum { expected, found } => write!(
+                f,
+                "Record checksum is {:02X}, but should be {:02X}",
+                found, expected
+            ),
+            MemoryErrorKind::UnsupportedRecord(kind) => {
+                write!(
+                    f,
+                    "Unsupported record type {:02X}, only 00 and 01 are supported",
+                    kind
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryError {
+    pub kind: MemoryErrorKind,
+    pub span: Span,
+    /// The 1-based line and column of the start of the span.
+    pub line: usize,
+    pub column: usize,
+    /// Where the byte was first written, for overlaps.
+    pub first_written: Option<Span>,
+}
+
+impl MemoryError {
+    fn new(kind: MemoryErrorKind, source: &str, span: Span) -> Self {
+        let before = &source[..span.start];
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        Self {
+            kind,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            span,
+            first_written: None,
+        }
+    }
+
+    /// Converts the error into a diagnostic in the given source.
+    pub fn diagnostic(&self, source: SourceId) -> Diagnostic {
+        let diagnostic = Diagnostic::error(
+            &self.kind,
+            Location {
+                source,
+                span: self.span.clone(),
+            },
+        );
+        match &self.first_written {
+            Some(span) => diagnostic.with_label(
+                Location {
+                    source,
+                    span: span.clone(),
+                },
+                "First written here",
+            ),
+            None => diagnostic,
+        }
+    }
+}
+
+impl Display for MemoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.kind)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryFileOptions {
+    /// Reports bytes which are written by more than one line, rather than keeping the last value.
+    pub reject_overlap: bool,
+    /// The bytes of memory to read into, which is [MEMORY_SIZE] by default.
+    pub memory_size: usize,
+}
+
+impl Default for MemoryFileOptions {
+    fn default() -> Self {
+        Self {
+            reject_overlap: false,
+            memory_size: MEMORY_SIZE,
+        }
+    }
+}
+
+/// The formats memory images can be read and written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryFormat {
+    /// Lines of an address followed by bytes, as read by [read_memory_file].
+    Text,
+    /// Intel HEX data records, with a 16-bit address space of which only the bytes of memory are used.
+    IntelHex,
+    /// The bytes of memory, with nothing else.
+    Binary,
+}
+
+impl MemoryFormat {
+    /// Guesses the format of a file from its contents.
+    /// Files which are not entirely printable text are binary, and text files whose first line starts with `:` are Intel HEX.
+    pub fn detect(bytes: &[u8]) -> Self {
+        let is_text = std::str::from_utf8(bytes)
+            .is_ok_and(|text| text.chars().all(|c| !c.is_control() || c.is_whitespace()));
+        if !is_text {
+            MemoryFormat::Binary
+        } else if bytes.trim_ascii_start().starts_with(b":") {
+            MemoryFormat::IntelHex
+        } else {
+            MemoryFormat::Text
+        }
+    }
+}
+
+/// Memory being filled in by a parser, which remembers where each byte was written.
+struct Image {
+    memory: Vec<u8>,
+    written: Vec<Option<Span>>,
+    options: MemoryFileOptions,
+}
+
+impl Image {
+    fn new(options: MemoryFileOptions) -> Self {
+        Self {
+            memory: vec![0; options.memory_size],
+            written: vec![None; options.memory_size],
+            options,
+        }
+    }
+
+    /// Writes a byte which was parsed from `span` of the source.
+    fn write(
+        &mut self,
+        address: usize,
+        byte: u8,
+        source: &str,
+        span: Span,
+    ) -> Result<(), MemoryError> {
+        if address >= self.memory.len() {
+            return Err(MemoryError::new(
+                MemoryErrorKind::OutOfRange {
+                    address,
+                    last: self.memory.len() - 1,
+                },
+                source,
+                span,
+            ));
+        }
+        if let (true, Some(first)) = (self.options.reject_overlap, &self.written[address]) {
+            return Err(MemoryError {
+                first_written: Some(first.clone()),
+                ..MemoryError::new(MemoryErrorKind::Overlap(address as u16), source, span)
+            });
+        }
+        self.memory[address] = byte;
+        self.written[address] = Some(span);
+        Ok(())
+    }
+}
+
+/// Parses a hex number with an optional `0x` prefix.
+fn parse_hex(text: &str) -> Option<&str> {
+    let digits = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .unwrap_or(text);
+    (!digits.is_empty() && digits.chars().all(|c| c.is_ascii_hexdigit())).then_some(digits)
+}
+
+/// What a [MemoryToken] of a memory file is.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MemoryTokenKind {
+    /// The address at the start of a line.
+    Address(usize),
+    /// Bytes written together from an address, such as `2103` or `0x21`.
+    Bytes { address: usize, bytes: Vec<u8> },
+    /// Everything after `//` or `;` on a line.
+    Comment,
+    /// An address or bytes which are not hex, or anything after an invalid address.
+    Invalid,
+}
+
+/// A field or comment of a memory file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryToken {
+    pub kind: MemoryTokenKind,
+    pub span: Span,
+}
+
+/// Splits a memory file into tokens, in the order they are written, with an error for every invalid field.
+/// This is the syntax read by [read_memory_file].
+pub fn memory_file_tokens(source: &str) -> (Vec<MemoryToken>, Vec<MemoryError>) {
+    let mut tokens = vec![];
+    let mut errors = vec![];
+
+    let mut line_start = 0;
+    for line in source.split_inclusive('\n') {
+        let offset = line_start;
+        line_start += line.len();
+        let content = line.trim_end_matches(['\r', '\n']);
+        let comment = ["//", ";"]
+            .iter()
+            .filter_map(|marker| content.find(marker))
+            .min();
+        let code = comment.map_or(content, |comment| &content[..comment]);
+        let mut fields = code.split_whitespace().map(|field| {
+            let start = offset + (field.as_ptr() as usize - line.as_ptr() as usize);
+            (field, start..start + field.len())
+        });
+
+        if let Some((field, span)) = fields.next() {
+            let address = parse_hex(field)
+                .filter(|digits| digits.len() <= 4)
+                .and_then(|digits| usize::from_str_radix(digits, 16).ok());
+            match address {
+                Some(mut address) => {
+                    let mut fields = fields.peekable();
+                    if fields.peek().is_none() {
+                        errors.push(MemoryError::new(
+                            MemoryErrorKind::ExpectedValue,
+                            source,
+                            span.clone(),
+                        ));
+                    }
+                    tokens.push(MemoryToken {
+                        kind: MemoryTokenKind::Address(address),
+                        span,
+                    });
+                    for (field, span) in fields {
+                        let Some(digits) =
+                            parse_hex(field).filter(|digits| digits.len().is_multiple_of(2))
+                        else {
+                            errors.push(MemoryError::new(
+                                MemoryErrorKind::InvalidByte(field.to_owned()),
+                                source,
+                                span.clone(),
+                            ));
+                            tokens.push(MemoryToken {
+                                kind: MemoryTokenKind::Invalid,
+                                span,
+                            });
+                            continue;
+                        };
+                        let bytes: Vec<u8> = (0..digits.len())
+                            .step_by(2)
+                            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+                            .collect();
+                        let kind = MemoryTokenKind::Bytes { address, bytes };
+                        address += digits.len() / 2;
+                        tokens.push(MemoryToken { kind, span });
+                    }
+                }
+                None => {
+                    errors.push(MemoryError::new(
+                        MemoryErrorKind::InvalidAddress(field.to_owned()),
+                        source,
+                        span.clone(),
+                    ));
+                    // The rest of the line has nowhere to be written.
+                    let end = fields.next_back().map_or(span.end, |(_, last)| last.end);
+                    tokens.push(MemoryToken {
+                        kind: MemoryTokenKind::Invalid,
+                        span: span.start..end,
+                    });
+                }
             }
         }
+        if let Some(comment) = comment {
+            tokens.push(MemoryToken {
+                kind: MemoryTokenKind::Comment,
+                span: offset + comment..offset + content.len(),
+            });
+        }
+    }
+    (tokens, errors)
+}
+
+/// Parses a memory file, where each line is an address followed by the bytes to write from there, all in hex.
+/// Bytes can be written together, as in `00 2103`, or separated by whitespace, as in `00 21 03`, and either may have a `0x` prefix.
+/// Addresses have up to four digits, so that memories larger than 256 bytes can be written.
+/// Everything after `//` or `;` on a line is a comment.
+pub fn read_memory_file(
+    source: &str,
+    options: MemoryFileOptions,
+) -> Result<Vec<u8>, Vec<MemoryError>> {
+    let mut image = Image::new(options);
+    let (tokens, mut errors) = memory_file_tokens(source);
+
+    for token in tokens {
+        let MemoryTokenKind::Bytes { address, bytes } = token.kind else {
+            continue;
+        };
+        // The digits of the bytes are at the end of the field, after any prefix.
+        let digits_start = token.span.end - 2 * bytes.len();
+        for (i, byte) in bytes.into_iter().enumerate() {
+            let byte_span = digits_start + 2 * i..digits_start + 2 * i + 2;
+            match image.write(address + i, byte, source, byte_span) {
+                Ok(()) => {}
+                Err(e) if matches!(e.kind, MemoryErrorKind::OutOfRange { .. }) => {
+                    errors.push(e);
+                    break;
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(image.memory)
+    } else {
+        errors.sort_by_key(|e| e.span.start);
+        Err(errors)
+    }
+}
+
+/// Parses Intel HEX data records, stopping at the end of file record.
+pub fn read_intel_hex(
+    source: &str,
+    options: MemoryFileOptions,
+) -> Result<Vec<u8>, Vec<MemoryError>> {
+    let mut image = Image::new(options);
+    let mut errors = vec![];
+
+    let mut line_start = 0;
+    for line in source.split_inclusive('\n') {
+        let record = line.trim();
+        let start = line_start + (line.len() - line.trim_start().len());
+        line_start += line.len();
+        if record.is_empty() {
+            continue;
+        }
+        let span = start..start + record.len();
+        let bytes: Option<Vec<u8>> = record
+            .strip_prefix(':')
+            .filter(|hex| hex.len() % 2 == 0 && hex.is_ascii())
+            .and_then(|hex| {
+                (0..hex.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+                    .collect()
+            });
+        let Some(bytes) =
+            bytes.filter(|bytes| bytes.len() >= 5 && bytes.len() == bytes[0] as usize + 5)
+        else {
+            errors.push(MemoryError::new(
+                MemoryErrorKind::InvalidRecord,
+                source,
+                span,
+            ));
+            continue;
+        };
+
+        let (contents, checksum) = bytes.split_at(bytes.len() - 1);
+        let expected = contents
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+            .wrapping_neg();
+        if expected != checksum[0] {
+            errors.push(MemoryError::new(
+                MemoryErrorKind::InvalidChecksum {
+                    expected,
+                    found: checksum[0],
+                },
+                source,
+                span.end - 2..span.end,
+            ));
+            continue;
+        }
+        match contents[3] {
+            0x00 => {
+                let address = u16::from_be_bytes([contents[1], contents[2]]) as usize;
+                for (i, &byte) in contents[4..].iter().enumerate() {
+                    // Each data byte is two hex digits, after the colon and the eight digits of the header.
+                    let byte_start = start + 9 + 2 * i;
+                    if let Err(e) =
+                        image.write(address + i, byte, source, byte_start..byte_start + 2)
+                    {
+                        errors.push(e);
+                        break;
+                    }
+                }
+            }
+            0x01 => break,
+            kind => errors.push(MemoryError::new(
+                MemoryErrorKind::UnsupportedRecord(kind),
+                source,
+                start + 7..start + 9,
+            )),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(image.memory)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Reads raw bytes into the start of `memory_size` bytes of memory.
+/// Files shorter than memory leave the rest zeroed.
+pub fn read_binary(bytes: &[u8], memory_size: usize) -> Result<Vec<u8>, MemoryError> {
+    if bytes.len() > memory_size {
+        return Err(MemoryError {
+            kind: MemoryErrorKind::OutOfRange {
+                address: bytes.len() - 1,
+                last: memory_size - 1,
+            },
+            span: memory_size..bytes.len(),
+            line: 1,
+            column: memory_size + 1,
+            first_written: None,
+        });
+    }
+    let mut memory = vec![0; memory_size];
+    memory[..bytes.len()].copy_from_slice(bytes);
+    Ok(memory)
+}
+
+/// Reads memory in any of the formats, detected with [MemoryFormat::detect].
+pub fn read_memory(bytes: &[u8], options: MemoryFileOptions) -> Result<Vec<u8>, Vec<MemoryError>> {
+    match MemoryFormat::detect(bytes) {
+        MemoryFormat::Binary => read_binary(bytes, options.memory_size).map_err(|e| vec![e]),
+        // Detection only picks the text formats for valid UTF-8.
+        MemoryFormat::Text => read_memory_file(std::str::from_utf8(bytes).unwrap(), options),
+        MemoryFormat::IntelHex => read_intel_hex(std::str::from_utf8(bytes).unwrap(), options),
+    }
+}
+
+/// Writes memory in any of the formats.
+pub fn write_memory(memory: &[u8], format: MemoryFormat, w: &mut dyn Write) -> io::Result<()> {
+    match format {
+        MemoryFormat::Text => write_memory_file(memory, w),
+        MemoryFormat::IntelHex => write_intel_hex(memory, w),
+        MemoryFormat::Binary => w.write_all(memory),
+    }
+}
+
+/// Writes memory as Intel HEX, with a data record for every 16 bytes followed by an end of file record.
+pub fn write_intel_hex(memory: &[u8], w: &mut dyn Write) -> io::Result<()> {
+    for (i, row) in memory.chunks(16).enumerate() {
+        let address = ((i * 16) as u16).to_be_bytes();
+        let mut record = vec![row.len() as u8, address[0], address[1], 0x00];
+        record.extend_from_slice(row);
+        let checksum = record
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+            .wrapping_neg();
+        record.push(checksum);
+        let hex: String = record.iter().map(|byte| format!("{:02X}", byte)).collect();
+        writeln!(w, ":{}", hex)?;
+    }
+    writeln!(w, ":00000001FF")
+}
+
+/// Writes memory in the format read by [read_memory_file], one two byte word per line.
+/// Words which are zero are skipped, as memory starts out zeroed.
+/// Addresses have two digits, or four if memory is larger than 256 bytes.
+pub fn write_memory_file(memory: &[u8], w: &mut dyn Write) -> io::Result<()> {
+    let digits = if memory.len() > MEMORY_SIZE { 4 } else { 2 };
+    for (i, word) in memory.chunks(2).enumerate() {
+        if word.iter().any(|&b| b != 0) {
+            writeln!(
+                w,
+                "{:0digits$X} {:02X}{:02X}",
+                i * 2,
+                word[0],
+                word.get(1).copied().unwrap_or_default()
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn memory_formats_round_trip() {
+    use crate::machine_code::MAX_MEMORY_SIZE;
+
+    let mut memory = [0; MEMORY_SIZE];
+    memory[..4].copy_from_slice(&[0x21, 0x03, 0xC0, 0x00]);
+    memory[0xFF] = 0x42;
+    for format in [
+        MemoryFormat::Text,
+        MemoryFormat::IntelHex,
+        MemoryFormat::Binary,
+    ] {
+        let mut w = Vec::new();
+        write_memory(&memory, format, &mut w).unwrap();
+        assert_eq!(MemoryFormat::detect(&w), format);
+        assert_eq!(
+            read_memory(&w, MemoryFileOptions::default()),
+            Ok(memory.to_vec())
+        );
     }
-    memory
+
+    let mut wide = vec![0; MAX_MEMORY_SIZE];
+    wide[0x1234] = 0x42;
+    let options = MemoryFileOptions {
+        memory_size: MAX_MEMORY_SIZE,
+        ..Default::default()
+    };
+    for format in [MemoryFormat::Text, MemoryFormat::IntelHex] {
+        let mut w = Vec::new();
+        write_memory(&wide, format, &mut w).unwrap();
+        assert_eq!(read_memory(&w, options), Ok(wide.clone()));
+    }
+    assert_eq!(read_memory_file("1234 42", options), Ok(wide));
+
+    let errors =
+        read_intel_hex(":0100000021DF\n:02001000C0", MemoryFileOptions::default()).unwrap_err();
+    let kinds: Vec<_> = errors.into_iter().map(|e| e.kind).collect();
+    assert_eq!(
+        kinds,
+        [
+            MemoryErrorKind::InvalidChecksum {
+                expected: 0xDE,
+                found: 0xDF
+            },
+            MemoryErrorKind::InvalidRecord
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn read_memory_file_reports_errors() {
+    let source = "00 2103 // load_value r1, 3\n0x02 0xC0 00 ; halt\n\n04 C0\n";
+    let memory = read_memory_file(source, MemoryFileOptions::default()).unwrap();
+    assert_eq!(memory[..6], [0x21, 0x03, 0xC0, 0x00, 0xC0, 0x00]);
+
+    let source = "01 AABB\nZZ 00\n03 ABC\nFE 0102 03\n02 11\n";
+    let options = MemoryFileOptions {
+        reject_overlap: true,
+        ..Default::default()
+    };
+    let errors = read_memory_file(source, options).unwrap_err();
+    let found: Vec<_> = errors
+        .iter()
+        .map(|e| (e.kind.clone(), e.line, e.column))
+        .collect();
+    assert_eq!(
+        found,
+        [
+            (MemoryErrorKind::InvalidAddress("ZZ".to_owned()), 2, 1),
+            (MemoryErrorKind::InvalidByte("ABC".to_owned()), 3, 4),
+            (
+                MemoryErrorKind::OutOfRange {
+                    address: 0x100,
+                    last: 0xFF
+                },
+                4,
+                9
+            ),
+            (MemoryErrorKind::Overlap(0x02), 5, 4),
+        ]
+    );
+    assert_eq!(errors[3].first_written, Some(5..7));
 }
@@ -0,0 +1,476 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+use logos::Logos;
+
+use crate::assembler::{AssemblyError, AssemblyErrorKind};
+use crate::diagnostics::{Location, SourceId, Sources, Span};
+use crate::lexer::Token;
+
+/// The maximum depth of nested macro expansions, so that recursive macros are reported rather than expanded forever.
+const MAX_EXPANSION_DEPTH: usize = 64;
+/// The maximum depth of nested includes, so that a file which includes itself through a path that is not recognised is still reported.
+const MAX_INCLUDE_DEPTH: usize = 64;
+
+/// Where a token was written, along with the macro invocations it was expanded from, innermost first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Origin {
+    pub source: SourceId,
+    pub span: Span,
+    pub expansions: Vec<Location>,
+}
+
+impl Origin {
+    /// Where the token was written, ignoring the expansions.
+    pub fn location(&self) -> Location {
+        Location {
+            source: self.source,
+            span: self.span.clone(),
+        }
+    }
+}
+
+/// A significant token, with its text after macro expansion.
+#[derive(Debug, Clone)]
+pub(crate) struct Tok {
+    pub token: Token,
+    pub text: String,
+    pub origin: Origin,
+}
+
+#[derive(Debug)]
+struct Macro {
+    /// The name in the `.macro` line.
+    origin: Origin,
+    params: Vec<String>,
+    body: Vec<Vec<Tok>>,
+    /// Labels defined in the body, which are renamed in every expansion so that each has its own copy.
+    locals: BTreeSet<String>,
+}
+
+/// Splits a source into lines of significant tokens, skipping whitespace and comments.
+fn tokenize(sources: &Sources, id: SourceId, errors: &mut Vec<AssemblyError>) -> Vec<Vec<Tok>> {
+    let text = &sources.get(id).text;
+    let mut lines = vec![vec![]];
+    for (token, span) in Token::lexer(text).spanned() {
+        let origin = Origin {
+            source: id,
+            span: span.clone(),
+            expansions: vec![],
+        };
+        match token {
+            Ok(Token::NewLine) => lines.push(vec![]),
+            Ok(Token::WhiteSpace | Token::LineComment | Token::BlockComment) => {}
+            Ok(token) => lines.last_mut().unwrap().push(Tok {
+                token,
+                text: text[span].to_owned(),
+                origin,
+            }),
+            Err(_) => errors.push(AssemblyError::new(AssemblyErrorKind::UnknownToken, origin)),
+        }
+    }
+    lines
+}
+
+/// The first token of a line which is not a label.
+fn statement(line: &[Tok]) -> Option<(usize, &Tok)> {
+    line.iter()
+        .enumerate()
+        .find(|(_, tok)| tok.token != Token::Label)
+}
+
+fn is_directive(tok: &Tok, name: &str) -> bool {
+    tok.token == Token::Directive && tok.text[1..].eq_ignore_ascii_case(name)
+}
+
+/// Removes the `.` components of a path, and the `..` components along with the directory before them, without reading the file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(
+                    normalized.components().next_back(),
+                    Some(Component::Normal(_))
+                ) =>
+            {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Splits tokens into groups separated by commas, with no groups if there are no tokens.
+fn split_commas(tokens: &[Tok]) -> Vec<&[Tok]> {
+    if tokens.is_empty() {
+        return vec![];
+    }
+    tokens.split(|tok| tok.token == Token::Comma).collect()
+}
+
+/// Expands includes and macros, producing the lines of the whole program.
+struct Preprocessor<'a> {
+    sources: &'a mut Sources,
+    load: &'a mut dyn FnMut(&Path) -> io::Result<String>,
+    macros: BTreeMap<String, Rc<Macro>>,
+    /// The files currently being included, to detect an include of a file into itself.
+    including: Vec<PathBuf>,
+    expansion_count: usize,
+    errors: &'a mut Vec<AssemblyError>,
+    output: Vec<Vec<Tok>>,
+}
+
+impl Preprocessor<'_> {
+    fn file(&mut self, id: SourceId) {
+        let mut lines = tokenize(self.sources, id, self.errors).into_iter();
+        while let Some(line) = lines.next() {
+            match statement(&line) {
+                Some((_, tok)) if is_directive(tok, "macro") => self.define(line, &mut lines),
+                _ => self.line(line, 0),
+            }
+        }
+    }
+
+    /// Records a macro, whose body is every line up to the next `.endm`.
+    fn define(&mut self, header: Vec<Tok>, lines: &mut impl Iterator<Item = Vec<Tok>>) {
+        let (index, directive) = statement(&header).unwrap();
+        let directive = directive.clone();
+        let mut body = vec![];
+        loop {
+            let Some(line) = lines.next() else {
+                self.errors.push(AssemblyError::new(
+                    AssemblyErrorKind::UnterminatedMacro,
+                    directive.origin.clone(),
+                ));
+                return;
+            };
+            match statement(&line) {
+                Some((_, tok)) if is_directive(tok, "endm") => break,
+                Some((_, tok)) if is_directive(tok, "macro") => {
+                    self.errors.push(AssemblyError::new(
+                        AssemblyErrorKind::NestedMacro,
+                        tok.origin.clone(),
+                    ));
+                }
+                _ => body.push(line),
+            }
+        }
+
+        let Some((name, params)) = header[index + 1..].split_first() else {
+            self.errors.push(AssemblyError::new(
+                AssemblyErrorKind::ExpectedName,
+                directive.origin,
+            ));
+            return;
+        };
+        let mut names = vec![];
+        for group in split_commas(params) {
+            match group {
+                [param] if param.token == Token::Identifier => names.push(param.text.clone()),
+                _ => {
+                    let origin = group.first().unwrap_or(name).origin.clone();
+                    self.errors
+                        .push(AssemblyError::new(AssemblyErrorKind::ExpectedName, origin));
+                    return;
+                }
+            }
+        }
+        if name.token != Token::Identifier {
+            self.errors.push(AssemblyError::new(
+                AssemblyErrorKind::ExpectedName,
+                name.origin.clone(),
+            ));
+            return;
+        }
+        if let Some(first) = self.macros.get(&name.text) {
+            self.errors.push(
+                AssemblyError::new(
+                    AssemblyErrorKind::DuplicateMacro(name.text.clone()),
+                    name.origin.clone(),
+                )
+                .with_related(&first.origin, "First defined here"),
+            );
+            return;
+        }
+
+        let locals = body
+            .iter()
+            .flatten()
+            .filter(|tok| tok.token == Token::Label)
+            .map(|tok| tok.text.trim_end_matches(':').to_owned())
+            .collect();
+        let definition = Macro {
+            origin: name.origin.clone(),
+            params: names,
+            body,
+            locals,
+        };
+        self.macros.insert(name.text.clone(), Rc::new(definition));
+    }
+
+    /// Handles a line which is not part of a macro definition.
+    fn line(&mut self, line: Vec<Tok>, depth: usize) {
+        let Some((index, tok)) = statement(&line) else {
+            self.output.push(line);
+            return;
+        };
+        let definition = match tok.token {
+            Token::Identifier => self.macros.get(&tok.text).cloned(),
+            _ => None,
+        };
+        let is_include = is_directive(tok, "include");
+        if definition.is_none() && !is_include {
+            if is_directive(tok, "macro") {
+                self.errors.push(AssemblyError::new(
+                    AssemblyErrorKind::NestedMacro,
+                    tok.origin.clone(),
+                ));
+            } else if is_directive(tok, "endm") {
+                self.errors.push(AssemblyError::new(
+                    AssemblyErrorKind::UnexpectedToken,
+                    tok.origin.clone(),
+                ));
+            } else {
+                self.output.push(line);
+            }
+            return;
+        }
+
+        // Labels keep the address of the first statement the line expands to.
+        let (labels, statement) = line.split_at(index);
+        if !labels.is_empty() {
+            self.output.push(labels.to_vec());
+        }
+        match definition {
+            Some(definition) => self.expand(&definition, &statement[0], &statement[1..], depth),
+            None => self.include(&statement[0], &statement[1..]),
+        }
+    }
+
+    fn include(&mut self, directive: &Tok, operands: &[Tok]) {
+        let [path] = operands else {
+            let origin = operands.first().unwrap_or(directive).origin.clone();
+            self.errors.push(AssemblyError::new(
+                AssemblyErrorKind::ExpectedString,
+                origin,
+            ));
+            return;
+        };
+        if path.token != Token::String {
+            self.errors.push(AssemblyError::new(
+                AssemblyErrorKind::ExpectedString,
+                path.origin.clone(),
+            ));
+            return;
+        }
+        let name = &path.text[1..path.text.len() - 1];
+        // Includes are relative to the file they are written in.
+        let including = Path::new(&self.sources.get(path.origin.source).name);
+        let resolved = normalize(&including.parent().unwrap_or(Path::new("")).join(name));
+        if self.including.contains(&resolved) {
+            self.errors.push(AssemblyError::new(
+                AssemblyErrorKind::RecursiveInclude(name.to_owned()),
+                path.origin.clone(),
+            ));
+            return;
+        }
+        if self.including.len() > MAX_INCLUDE_DEPTH {
+            self.errors.push(AssemblyError::new(
+                AssemblyErrorKind::IncludeTooDeep(name.to_owned()),
+                path.origin.clone(),
+            ));
+            return;
+        }
+        match (self.load)(&resolved) {
+            Ok(text) => {
+                let id = self.sources.add(resolved.to_string_lossy(), text);
+                self.including.push(resolved);
+                self.file(id);
+                self.including.pop();
+            }
+            Err(e) => self.errors.push(AssemblyError::new(
+                AssemblyErrorKind::IncludeFailed(name.to_owned(), e.to_string()),
+                path.origin.clone(),
+            )),
+        }
+    }
+
+    fn expand(&mut self, definition: &Macro, name: &Tok, operands: &[Tok], depth: usize) {
+        let args = split_commas(operands);
+        if args.len() != definition.params.len() {
+            let usage = format!("{} {}", name.text, definition.params.join(", "));
+            self.errors.push(
+                AssemblyError::new(
+                    AssemblyErrorKind::WrongOperandCount {
+                        expected: definition.params.len(),
+                        found: args.len(),
+                    },
+                    name.origin.clone(),
+                )
+                .with_related(&definition.origin, "Macro defined here")
+                .with_help(format!("Write `{}`", usage.trim_end())),
+            );
+            return;
+        }
+        if depth >= MAX_EXPANSION_DEPTH {
+            self.errors.push(AssemblyError::new(
+                AssemblyErrorKind::MacroTooDeep(name.text.clone()),
+                name.origin.clone(),
+            ));
+            return;
+        }
+
+        self.expansion_count += 1;
+        let suffix = self.expansion_count;
+        let mut expansions = vec![Location {
+            source: name.origin.source,
+            span: name.origin.span.clone(),
+        }];
+        expansions.extend(name.origin.expansions.iter().cloned());
+
+        for body_line in &definition.body {
+            let mut line = vec![];
+            for tok in body_line {
+                let param = definition.params.iter().position(|p| *p == tok.text);
+                match (tok.token, param) {
+                    (Token::Identifier, Some(i)) => line.extend(args[i].iter().cloned()),
+                    _ => {
+                        let mut tok = tok.clone();
+                        let local = tok.text.trim_end_matches(':');
+                        if matches!(tok.token, Token::Label | Token::Identifier)
+                            && definition.locals.contains(local)
+                        {
+                            // `@` can not be written in source, so the renamed label can not collide with another.
+                            tok.text =
+                                tok.text
+                                    .replacen(local, &format!("{}@{}", local, suffix), 1);
+                        }
+                        tok.origin.expansions = expansions.clone();
+                        line.push(tok);
+                    }
+                }
+            }
+            self.line(line, depth + 1);
+        }
+    }
+}
+
+/// Expands every `.include` and macro in the source, returning the lines of the whole program.
+///
+/// `.include "path"` reads a file relative to the file it is written in, using `load`.
+/// `.macro name param, ...` starts the definition of a macro, which ends at `.endm`.
+/// A macro is used like an instruction, and its parameters are replaced by the operands it is given.
+/// Labels defined in a macro are local to each expansion of it.
+pub(crate) fn preprocess(
+    sources: &mut Sources,
+    root: SourceId,
+    load: &mut dyn FnMut(&Path) -> io::Result<String>,
+    errors: &mut Vec<AssemblyError>,
+) -> Vec<Vec<Tok>> {
+    let including = vec![normalize(Path::new(&sources.get(root).name))];
+    let mut preprocessor = Preprocessor {
+        sources,
+        load,
+        macros: BTreeMap::new(),
+        including,
+        expansion_count: 0,
+        errors,
+        output: vec![],
+    };
+    preprocessor.file(root);
+    preprocessor.output
+}
+
+#[cfg(test)]
+#[test]
+fn macros_and_includes_expand() {
+    use crate::assembler::assemble_sources;
+    use crate::instructions::IsaProfile;
+
+    let library = "
+    .macro count_to r, limit
+        load_value r0, limit
+        load_value r1, 1
+    loop:
+        add_integer r, r, r1
+        jump_if_eq r, done
+        jump loop
+    done:
+    .endm
+    ";
+    let program = r#"
+        .include "lib.s"
+    start:
+        count_to r2, 3
+        count_to r3, 4
+        halt
+    "#;
+    let mut load = |path: &Path| match path.to_str() {
+        Some("lib/lib.s") => Ok(library.to_owned()),
+        _ => Err(io::Error::from(io::ErrorKind::NotFound)),
+    };
+    let mut sources = Sources::default();
+    let root = sources.add("lib/main.s", program);
+    let assembly = assemble_sources(&mut sources, root, &IsaProfile::default(), &mut load).unwrap();
+    assert_eq!(sources.files.len(), 2);
+    assert_eq!(assembly.labels["start"], 0);
+    assert_eq!(assembly.labels["loop@1"], 4);
+    assert_eq!(assembly.labels["loop@2"], 14);
+    assert_eq!(
+        assembly.memory[..12],
+        [0x20, 0x03, 0x21, 0x01, 0x52, 0x21, 0xB2, 0x0A, 0xB0, 0x04, 0x20, 0x04]
+    );
+
+    let mut sources = Sources::default();
+    let root = sources.add(
+        "main.s",
+        ".macro twice x\n  load_value x, 0x100\n.endm\n.macro outer\n  twice r1\n.endm\nouter\n.include \"missing.s\"\n",
+    );
+    let errors =
+        assemble_sources(&mut sources, root, &IsaProfile::default(), &mut load).unwrap_err();
+    assert_eq!(errors.len(), 2);
+    assert!(matches!(
+        errors[0].kind,
+        AssemblyErrorKind::IncludeFailed(ref path, _) if path == "missing.s"
+    ));
+    let text = &sources.get(root).text;
+    let error = &errors[1];
+    assert_eq!(&text[error.origin.span.clone()], "0x100");
+    let expansions: Vec<_> = error
+        .origin
+        .expansions
+        .iter()
+        .map(|location| &text[location.span.clone()])
+        .collect();
+    assert_eq!(expansions, ["twice", "outer"]);
+
+    // A file which includes itself through `..`, and one which includes itself through ever longer paths.
+    let mut load = |path: &Path| match path.file_name() {
+        Some(name) if name == "b.s" => Ok(".include \"d/b.s\"\n".to_owned()),
+        _ => Err(io::Error::from(io::ErrorKind::NotFound)),
+    };
+    for (name, text, kind) in [
+        (
+            "x/a.s",
+            ".include \"../x/a.s\"\n",
+            AssemblyErrorKind::RecursiveInclude("../x/a.s".to_owned()),
+        ),
+        (
+            "b.s",
+            ".include \"d/b.s\"\n",
+            AssemblyErrorKind::IncludeTooDeep("d/b.s".to_owned()),
+        ),
+    ] {
+        let mut sources = Sources::default();
+        let root = sources.add(name, text);
+        let errors =
+            assemble_sources(&mut sources, root, &IsaProfile::default(), &mut load).unwrap_err();
+        let kinds: Vec<_> = errors.into_iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, [kind]);
+    }
+}
@@ -0,0 +1,137 @@
+use std::io::{self, Write};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::machine::{Machine, StopReason};
+use crate::machine_code::{Flags, PC};
+
+/// Serialises bytes as a single string of hex pairs, such as `"2103C000"`.
+mod hex {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        serializer.serialize_str(&hex)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                    .ok_or_else(|| serde::de::Error::custom("invalid hex string"))
+            })
+            .collect()
+    }
+}
+
+/// The outcome of running a program, with the final state of the machine and the image it started from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExecutionReport {
+    pub stop: StopReason,
+    pub pc: PC,
+    pub cycles: usize,
+    #[serde(with = "hex")]
+    pub registers: Vec<u8>,
+    /// The status flags, if the instruction set has them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flags: Option<Flags>,
+    #[serde(with = "hex")]
+    pub memory: Vec<u8>,
+    #[serde(with = "hex")]
+    pub initial_memory: Vec<u8>,
+}
+
+impl ExecutionReport {
+    pub fn new<const M: usize, const R: usize>(
+        machine: &Machine<M, R>,
+        stop: StopReason,
+        initial_memory: &[u8; M],
+    ) -> Self {
+        Self {
+            stop,
+            pc: machine.ctx.pc,
+            cycles: machine.cycles,
+            registers: machine.ctx.registers.to_vec(),
+            flags: machine.ctx.isa.has_flags().then_some(machine.ctx.flags),
+            memory: machine.ctx.memory.to_vec(),
+            initial_memory: initial_memory.to_vec(),
+        }
+    }
+
+    /// Writes the report as a single JSON object.
+    pub fn write_json(&self, w: &mut dyn Write) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut *w, self)?;
+        writeln!(w)
+    }
+
+    /// Writes the report as aligned tables, with memory shown as a hex dump sixteen bytes to a row.
+    pub fn write_table(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "Stop:    {}", self.stop)?;
+        writeln!(w, "PC:      {:02X}", self.pc)?;
+        writeln!(w, "Cycles:  {}", self.cycles)?;
+        writeln!(w)?;
+        writeln!(w, "Registers:")?;
+        for (i, values) in self.registers.chunks(4).enumerate() {
+            let row: Vec<String> = values
+                .iter()
+                .enumerate()
+                .map(|(j, value)| format!("{:<3} {:02X}", format!("r{}", i * 4 + j), value))
+                .collect();
+            writeln!(w, "  {}", row.join("   "))?;
+        }
+        if let Some(flags) = self.flags {
+            writeln!(w, "Flags:   {}", flags)?;
+        }
+        writeln!(w)?;
+        writeln!(w, "Memory:")?;
+        write_hex_dump(&self.memory, w)?;
+        writeln!(w)?;
+        writeln!(w, "Initial memory:")?;
+        write_hex_dump(&self.initial_memory, w)
+    }
+}
+
+fn write_hex_dump(memory: &[u8], w: &mut dyn Write) -> io::Result<()> {
+    write!(w, "     ")?;
+    for col in 0..16 {
+        write!(w, " {:2X}", col)?;
+    }
+    writeln!(w)?;
+    for (row, values) in memory.chunks(16).enumerate() {
+        write!(w, "{:04X}:", row * 16)?;
+        for value in values {
+            write!(w, " {:02X}", value)?;
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn report_round_trips_as_json() {
+    use crate::machine_code::Ctx;
+
+    let mut ctx: Ctx = Ctx::new([0; 256]);
+    // load_value r1, 3; halt
+    ctx.memory[..4].copy_from_slice(&[0x21, 0x03, 0xC0, 0x00]);
+    let initial = ctx.memory;
+    let mut machine = Machine::new(ctx);
+    let stop = machine.run(16);
+    let report = ExecutionReport::new(&machine, stop, &initial);
+
+    let mut w = Vec::new();
+    report.write_json(&mut w).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&w).unwrap();
+    assert_eq!(json["stop"], "Halted");
+    assert_eq!(json["cycles"], 2);
+    assert_eq!(json["registers"], "00030000000000000000000000000000");
+    assert!(json["memory"].as_str().unwrap().starts_with("2103C000"));
+    assert_eq!(
+        serde_json::from_value::<ExecutionReport>(json).unwrap(),
+        report
+    );
+}
@@ -0,0 +1,409 @@
+use std::fmt::Display;
+use std::ops::Range;
+
+use logos::Logos;
+
+use crate::machine_code::{Ctx, Err, Res, PC};
+
+#[derive(Logos, Debug, Clone, Copy, PartialEq)]
+#[logos(skip r"[ \t\r]+")]
+enum Token {
+    #[token("\n")]
+    #[token(";")]
+    Separator,
+    #[regex("[0-9]+")]
+    Integer,
+    #[regex("0[xX][0-9a-fA-F]+")]
+    HexInteger,
+    #[regex("[_a-zA-Z][_0-9a-zA-Z]*")]
+    Identifier,
+    #[token("<-")]
+    Assign,
+    #[token("[")]
+    OpenBracket,
+    #[token("]")]
+    CloseBracket,
+    #[token("(")]
+    OpenParen,
+    #[token(")")]
+    CloseParen,
+    #[token("~")]
+    Not,
+    #[token("|", |_| BinaryOp::Or)]
+    #[token("^", |_| BinaryOp::Xor)]
+    #[token("&", |_| BinaryOp::And)]
+    #[token("==", |_| BinaryOp::Eq)]
+    #[token("!=", |_| BinaryOp::Ne)]
+    #[token("<", |_| BinaryOp::Lt)]
+    #[token("<=", |_| BinaryOp::Le)]
+    #[token(">", |_| BinaryOp::Gt)]
+    #[token(">=", |_| BinaryOp::Ge)]
+    #[token("<<", |_| BinaryOp::Shl)]
+    #[token(">>", |_| BinaryOp::Shr)]
+    #[token("+", |_| BinaryOp::Add)]
+    #[token("-", |_| BinaryOp::Sub)]
+    #[token("*", |_| BinaryOp::Mul)]
+    Binary(BinaryOp),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Or,
+    Xor,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+}
+
+/// The binary operators from the loosest binding to the tightest.
+const PRECEDENCE: &[&[BinaryOp]] = &[
+    &[BinaryOp::Or],
+    &[BinaryOp::Xor],
+    &[BinaryOp::And],
+    &[
+        BinaryOp::Eq,
+        BinaryOp::Ne,
+        BinaryOp::Lt,
+        BinaryOp::Le,
+        BinaryOp::Gt,
+        BinaryOp::Ge,
+    ],
+    &[BinaryOp::Shl, BinaryOp::Shr],
+    &[BinaryOp::Add, BinaryOp::Sub],
+    &[BinaryOp::Mul],
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(u32),
+    /// The value of the instruction operand with the given index.
+    Operand(usize),
+    Register(Box<Expr>),
+    Memory(Box<Expr>),
+    Pc,
+    Negate(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+/// Somewhere a value can be stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Register(Expr),
+    Memory(Expr),
+    Pc,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Statement {
+    Assign(Target, Expr),
+    If(Expr, Box<Statement>),
+    Halt,
+}
+
+/// The words with a meaning of their own in semantics, which can not name operands.
+pub const KEYWORDS: [&str; 6] = ["R", "M", "PC", "if", "then", "halt"];
+
+/// A mistake in register-transfer semantics, with the span of the text it was found at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtlError {
+    pub message: String,
+    pub span: Range<usize>,
+}
+
+impl Display for RtlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.span.start)
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(Result<Token, ()>, Range<usize>)>,
+    next: usize,
+    operands: &'a [&'a str],
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<Result<Token, ()>> {
+        self.tokens.get(self.next).map(|(token, _)| *token)
+    }
+
+    fn span(&self) -> Range<usize> {
+        self.tokens
+            .get(self.next)
+            .map_or(self.source.len()..self.source.len(), |(_, span)| {
+                span.clone()
+            })
+    }
+
+    fn text(&self) -> &str {
+        &self.source[self.span()]
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, RtlError> {
+        Err(RtlError {
+            message: message.into(),
+            span: self.span(),
+        })
+    }
+
+    fn expect(&mut self, token: Token, what: &str) -> Result<(), RtlError> {
+        if self.peek() != Some(Ok(token)) {
+            return self.error(format!("Expected {}", what));
+        }
+        self.next += 1;
+        Ok(())
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let found = self.peek() == Some(Ok(Token::Identifier)) && self.text() == keyword;
+        if found {
+            self.next += 1;
+        }
+        found
+    }
+
+    fn statement(&mut self) -> Result<Statement, RtlError> {
+        if self.keyword("halt") {
+            return Ok(Statement::Halt);
+        }
+        if self.keyword("if") {
+            let condition = self.expr(0)?;
+            if !self.keyword("then") {
+                return self.error("Expected `then`");
+            }
+            return Ok(Statement::If(condition, Box::new(self.statement()?)));
+        }
+        let target = if self.keyword("R") {
+            Target::Register(self.index()?)
+        } else if self.keyword("M") {
+            Target::Memory(self.index()?)
+        } else if self.keyword("PC") {
+            Target::Pc
+        } else {
+            return self.error("Expected `R[...]`, `M[...]`, `PC`, `if` or `halt`");
+        };
+        self.expect(Token::Assign, "`<-`")?;
+        Ok(Statement::Assign(target, self.expr(0)?))
+    }
+
+    fn index(&mut self) -> Result<Expr, RtlError> {
+        self.expect(Token::OpenBracket, "`[`")?;
+        let index = self.expr(0)?;
+        self.expect(Token::CloseBracket, "`]`")?;
+        Ok(index)
+    }
+
+    fn expr(&mut self, level: usize) -> Result<Expr, RtlError> {
+        let Some(ops) = PRECEDENCE.get(level) else {
+            return self.unary();
+        };
+        let mut lhs = self.expr(level + 1)?;
+        while let Some(Ok(Token::Binary(op))) = self.peek() {
+            if !ops.contains(&op) {
+                break;
+            }
+            self.next += 1;
+            let rhs = self.expr(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, RtlError> {
+        let expr = match self.peek() {
+            Some(Ok(Token::Binary(BinaryOp::Sub))) => {
+                self.next += 1;
+                Expr::Negate(Box::new(self.unary()?))
+            }
+            Some(Ok(Token::Not)) => {
+                self.next += 1;
+                Expr::Not(Box::new(self.unary()?))
+            }
+            Some(Ok(Token::OpenParen)) => {
+                self.next += 1;
+                let expr = self.expr(0)?;
+                self.expect(Token::CloseParen, "`)`")?;
+                expr
+            }
+            Some(Ok(token @ (Token::Integer | Token::HexInteger))) => {
+                let text = self.text();
+                let value = match token {
+                    Token::HexInteger => u32::from_str_radix(&text[2..], 16),
+                    _ => text.parse(),
+                };
+                let Ok(value) = value else {
+                    return self.error("Number is too large");
+                };
+                self.next += 1;
+                Expr::Number(value)
+            }
+            Some(Ok(Token::Identifier)) => {
+                if self.keyword("R") {
+                    Expr::Register(Box::new(self.index()?))
+                } else if self.keyword("M") {
+                    Expr::Memory(Box::new(self.index()?))
+                } else if self.keyword("PC") {
+                    Expr::Pc
+                } else {
+                    let name = self.text();
+                    let Some(index) = self.operands.iter().position(|operand| *operand == name)
+                    else {
+                        return self.error(format!("Unknown operand `{}`", name));
+                    };
+                    self.next += 1;
+                    Expr::Operand(index)
+                }
+            }
+            _ => return self.error("Expected a value"),
+        };
+        Ok(expr)
+    }
+}
+
+/// Parses register-transfer semantics, in which `operands` name the operands of the instruction.
+///
+/// Statements are separated by newlines or `;`, and are one of:
+/// - `R[i] <- value`, `M[address] <- value` or `PC <- address`, which store a value.
+/// - `if condition then statement`, which runs the statement if the condition is not zero.
+/// - `halt`, which stops the machine.
+///
+/// Values are unsigned, and can use operands, numbers, `R[i]`, `M[address]`, `PC`, parentheses, unary `-` and `~`,
+/// and the binary operators `| ^ & == != < <= > >= << >> + - *`, from the loosest binding to the tightest.
+/// Comparisons give 1 if they hold and 0 otherwise.
+pub fn parse(source: &str, operands: &[&str]) -> Result<Vec<Statement>, RtlError> {
+    let mut parser = Parser {
+        source,
+        tokens: Token::lexer(source).spanned().collect(),
+        next: 0,
+        operands,
+    };
+    let mut statements = vec![];
+    loop {
+        while parser.peek() == Some(Ok(Token::Separator)) {
+            parser.next += 1;
+        }
+        if parser.peek().is_none() {
+            return Ok(statements);
+        }
+        statements.push(parser.statement()?);
+        if parser.peek().is_some() {
+            parser.expect(Token::Separator, "a newline or `;`")?;
+        }
+    }
+}
+
+fn eval<const M: usize, const R: usize>(expr: &Expr, operands: &[u8], ctx: &mut Ctx<M, R>) -> u32 {
+    match expr {
+        Expr::Number(value) => *value,
+        Expr::Operand(index) => operands[*index] as u32,
+        Expr::Register(index) => ctx.registers[register(eval(index, operands, ctx))] as u32,
+        Expr::Memory(address) => {
+            let address = eval(address, operands, ctx) as usize % M;
+            ctx.read(address) as u32
+        }
+        Expr::Pc => ctx.pc as u32,
+        Expr::Negate(value) => eval(value, operands, ctx).wrapping_neg(),
+        Expr::Not(value) => !eval(value, operands, ctx),
+        Expr::Binary(op, lhs, rhs) => {
+            let (a, b) = (eval(lhs, operands, ctx), eval(rhs, operands, ctx));
+            match op {
+                BinaryOp::Or => a | b,
+                BinaryOp::Xor => a ^ b,
+                BinaryOp::And => a & b,
+                BinaryOp::Eq => (a == b) as u32,
+                BinaryOp::Ne => (a != b) as u32,
+                BinaryOp::Lt => (a < b) as u32,
+                BinaryOp::Le => (a <= b) as u32,
+                BinaryOp::Gt => (a > b) as u32,
+                BinaryOp::Ge => (a >= b) as u32,
+                BinaryOp::Shl => a.checked_shl(b).unwrap_or(0),
+                BinaryOp::Shr => a.checked_shr(b).unwrap_or(0),
+                BinaryOp::Add => a.wrapping_add(b),
+                BinaryOp::Sub => a.wrapping_sub(b),
+                BinaryOp::Mul => a.wrapping_mul(b),
+            }
+        }
+    }
+}
+
+/// Instructions can only name the first 16 registers, so register numbers wrap around.
+fn register(index: u32) -> usize {
+    index as usize % 16
+}
+
+/// Runs parsed semantics in order, with `operands` holding the operand values of the instruction.
+/// Stored values are truncated to the width of what they are stored in, and addresses wrap around memory.
+pub fn execute<const M: usize, const R: usize>(
+    statements: &[Statement],
+    operands: &[u8],
+    ctx: &mut Ctx<M, R>,
+) -> Res {
+    for statement in statements {
+        let mut statement = statement;
+        while let Statement::If(condition, then) = statement {
+            if eval(condition, operands, ctx) == 0 {
+                break;
+            }
+            statement = then;
+        }
+        match statement {
+            Statement::If(..) => {}
+            Statement::Halt => return Err(Err::HaltExecution),
+            Statement::Assign(target, value) => {
+                let value = eval(value, operands, ctx);
+                match target {
+                    Target::Register(index) => {
+                        let index = register(eval(index, operands, ctx));
+                        ctx.set_register(index, value as u8);
+                    }
+                    Target::Memory(address) => {
+                        let address = eval(address, operands, ctx) as usize % M;
+                        ctx.write(address, value as u8);
+                    }
+                    Target::Pc => ctx.pc = (value as usize % M) as PC,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn rtl_parses_and_executes() {
+    let statements = parse(
+        "R[r] <- R[r] - R[s] * 2\nif R[r] >= 0x80 then M[xy] <- ~R[r] + 1; PC <- PC + 2",
+        &["r", "s", "xy"],
+    )
+    .unwrap();
+    assert_eq!(statements.len(), 3);
+
+    let mut ctx: Ctx = Ctx::new([0; 256]);
+    ctx.registers[1] = 3;
+    ctx.registers[2] = 2;
+    assert_eq!(execute(&statements, &[1, 2, 0x40], &mut ctx), Ok(()));
+    assert_eq!(ctx.registers[1], 0xFF);
+    assert_eq!(ctx.memory[0x40], 1);
+    assert_eq!(ctx.pc, 2);
+
+    assert_eq!(
+        parse("R[r] <- t", &["r"]),
+        Err(RtlError {
+            message: "Unknown operand `t`".to_owned(),
+            span: 8..9
+        })
+    );
+    assert!(parse("if R[0] then", &[]).is_err());
+    assert_eq!(parse("halt;\n", &[]), Ok(vec![Statement::Halt]));
+}
@@ -0,0 +1,497 @@
+use std::io::{self, BufRead, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::instructions::{Instr, IsaProfile};
+use crate::machine_code::{Ctx, Flags, Overwrite, Res, PC};
+use crate::step_instr;
+
+/// A single register or memory cell which was written by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CellWrite {
+    pub location: u16,
+    pub value: u8,
+}
+
+/// The effects of running a single instruction.
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceStep {
+    pub pc: PC,
+    pub instr: Instr,
+    pub registers: Vec<CellWrite>,
+    pub memory: Vec<CellWrite>,
+    /// The status flags set by the instruction, if it set any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flags: Option<Flags>,
+    /// The memory page selected by the instruction, if it selected one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page: Option<u8>,
+    /// The program counter after the instruction ran.
+    pub next_pc: PC,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One JSON object per line.
+    JsonLines,
+    /// Comma separated values, with a header row. Writes are listed as `location=value`, separated by spaces, flags as `CVZN`, and pages in hex.
+    Csv,
+}
+
+/// A custom instruction in JSON, named by its mnemonic.
+#[derive(Deserialize)]
+struct JsonCustomInstr {
+    mnemonic: String,
+    operands: Vec<u8>,
+}
+
+/// An instruction in JSON, where custom instructions can only be built once the instruction set which describes them is known.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonInstr {
+    Custom {
+        #[serde(rename = "Custom")]
+        custom: JsonCustomInstr,
+    },
+    BuiltIn(Instr),
+}
+
+/// A [TraceStep] in JSON.
+#[derive(Deserialize)]
+struct JsonStep {
+    pc: PC,
+    instr: JsonInstr,
+    registers: Vec<CellWrite>,
+    memory: Vec<CellWrite>,
+    #[serde(default)]
+    flags: Option<Flags>,
+    #[serde(default)]
+    page: Option<u8>,
+    next_pc: PC,
+}
+
+const CSV_HEADER: &str = "pc,instr,registers,memory,flags,page,next_pc";
+
+/// A record of every instruction executed by a program.
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+    pub steps: Vec<TraceStep>,
+}
+
+impl Trace {
+    /// Executes the instruction at the program counter, recording its effects.
+    /// Nothing is recorded if the instruction can not be fetched.
+    pub fn step<const M: usize, const R: usize>(&mut self, ctx: &mut Ctx<M, R>) -> Res {
+        let pc = ctx.pc;
+        let (instr, res) = step_instr(ctx);
+        if let Some(instr) = instr {
+            self.record(instr, pc, ctx);
+        }
+        res
+    }
+
+    /// Records the effects of an instruction which started at `pc`, from the journal it left in `ctx`.
+    pub fn record<const M: usize, const R: usize>(
+        &mut self,
+        instr: Instr,
+        pc: PC,
+        ctx: &Ctx<M, R>,
+    ) {
+        let mut registers = vec![];
+        let mut memory = vec![];
+        let mut flags = None;
+        let mut page = None;
+        for overwrite in &ctx.journal {
+            match *overwrite {
+                Overwrite::Register { register, new, .. } => registers.push(CellWrite {
+                    location: register as u16,
+                    value: new,
+                }),
+                Overwrite::Memory { address, new, .. } => memory.push(CellWrite {
+                    location: address as u16,
+                    value: new,
+                }),
+                Overwrite::Flags { new, .. } => flags = Some(new),
+                Overwrite::Page { new, .. } => page = Some(new),
+                _ => {}
+            }
+        }
+        self.steps.push(TraceStep {
+            pc,
+            instr,
+            registers,
+            memory,
+            flags,
+            page,
+            next_pc: ctx.pc,
+        });
+    }
+
+    /// Rebuilds the state after the first `steps` instructions, starting from the initial state of the program.
+    /// Fails without changing `ctx` if any of those instructions writes outside its registers or memory.
+    pub fn replay<const M: usize, const R: usize>(
+        &self,
+        ctx: &mut Ctx<M, R>,
+        steps: usize,
+    ) -> io::Result<()> {
+        if let Some(number) = self
+            .steps
+            .iter()
+            .take(steps)
+            .position(|step| !step.fits(M, R))
+        {
+            return Err(invalid_step(number));
+        }
+        for step in self.steps.iter().take(steps) {
+            for write in &step.registers {
+                ctx.registers[write.location as usize] = write.value;
+            }
+            for write in &step.memory {
+                ctx.memory[write.location as usize] = write.value;
+            }
+            if let Some(flags) = step.flags {
+                ctx.flags = flags;
+            }
+            if let Some(page) = step.page {
+                ctx.page = page;
+            }
+            ctx.pc = step.next_pc;
+        }
+        Ok(())
+    }
+
+    /// Writes the trace, encoding instructions in the CSV format with `isa`.
+    /// Fails if `isa` can not encode one of the instructions.
+    pub fn write(
+        &self,
+        format: TraceFormat,
+        isa: &IsaProfile,
+        w: &mut dyn Write,
+    ) -> io::Result<()> {
+        match format {
+            TraceFormat::JsonLines => {
+                for step in &self.steps {
+                    serde_json::to_writer(&mut *w, step)?;
+                    writeln!(w)?;
+                }
+            }
+            TraceFormat::Csv => {
+                writeln!(w, "{}", CSV_HEADER)?;
+                for step in &self.steps {
+                    let word = isa.encode(&step.instr).ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Can not encode {:?} at {:#04x}", step.instr, step.pc),
+                        )
+                    })?;
+                    writeln!(
+                        w,
+                        "{:02X},{:04X},{},{},{},{},{:02X}",
+                        step.pc,
+                        word,
+                        format_writes(&step.registers),
+                        format_writes(&step.memory),
+                        step.flags
+                            .map(|flags| flags.to_string())
+                            .unwrap_or_default(),
+                        step.page
+                            .map(|page| format!("{:02X}", page))
+                            .unwrap_or_default(),
+                        step.next_pc
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a trace written by [Trace::write] with the same profile, building its instructions with `isa`.
+    /// Fails if a step writes outside the `M` bytes of memory or `R` registers it is replayed into.
+    pub fn read<const M: usize, const R: usize>(
+        format: TraceFormat,
+        isa: &IsaProfile,
+        reader: &mut dyn BufRead,
+    ) -> io::Result<Self> {
+        let mut steps = vec![];
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() || (format == TraceFormat::Csv && line == CSV_HEADER) {
+                continue;
+            }
+            let invalid = || invalid_step(number);
+            let step = match format {
+                TraceFormat::JsonLines => {
+                    let step: JsonStep = serde_json::from_str(&line)?;
+                    let instr = match step.instr {
+                        JsonInstr::Custom { custom } => isa
+                            .instr(&custom.mnemonic, &custom.operands)
+                            .ok_or_else(invalid)?,
+                        JsonInstr::BuiltIn(instr) => isa
+                            .instr(instr.mnemonic(), &instr.operands())
+                            .ok_or_else(invalid)?,
+                    };
+                    TraceStep {
+                        pc: step.pc,
+                        instr,
+                        registers: step.registers,
+                        memory: step.memory,
+                        flags: step.flags,
+                        page: step.page,
+                        next_pc: step.next_pc,
+                    }
+                }
+                TraceFormat::Csv => parse_csv_step(&line, isa).ok_or_else(invalid)?,
+            };
+            if !step.fits(M, R) {
+                return Err(invalid());
+            }
+            steps.push(step);
+        }
+        Ok(Self { steps })
+    }
+}
+
+impl TraceStep {
+    /// Whether every write lands inside `memory` bytes of memory and `registers` registers.
+    fn fits(&self, memory: usize, registers: usize) -> bool {
+        let inside =
+            |writes: &[CellWrite], len| writes.iter().all(|write| (write.location as usize) < len);
+        inside(&self.registers, registers) && inside(&self.memory, memory)
+    }
+}
+
+/// The error for the step at the zero-based `number`.
+fn invalid_step(number: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid trace on line {}", number + 1),
+    )
+}
+
+fn format_writes(writes: &[CellWrite]) -> String {
+    writes
+        .iter()
+        .map(|write| format!("{:02X}={:02X}", write.location, write.value))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn parse_writes(field: &str) -> Option<Vec<CellWrite>> {
+    field
+        .split_whitespace()
+        .map(|write| {
+            let (location, value) = write.split_once('=')?;
+            Some(CellWrite {
+                location: u16::from_str_radix(location, 16).ok()?,
+                value: u8::from_str_radix(value, 16).ok()?,
+            })
+        })
+        .collect()
+}
+
+fn parse_flags(field: &str) -> Option<Option<Flags>> {
+    if field.is_empty() {
+        return Some(None);
+    }
+    if field.len() != 4 {
+        return None;
+    }
+    let set: Vec<bool> = field
+        .chars()
+        .zip("CVZN".chars())
+        .map(|(c, name)| match c {
+            '-' => Some(false),
+            c if c == name => Some(true),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    let [carry, overflow, zero, negative] = set[..] else {
+        return None;
+    };
+    Some(Some(Flags {
+        carry,
+        overflow,
+        zero,
+        negative,
+    }))
+}
+
+fn parse_page(field: &str) -> Option<Option<u8>> {
+    if field.is_empty() {
+        return Some(None);
+    }
+    u8::from_str_radix(field, 16).ok().map(Some)
+}
+
+fn parse_csv_step(line: &str, isa: &IsaProfile) -> Option<TraceStep> {
+    let mut fields = line.split(',');
+    let step = TraceStep {
+        pc: PC::from_str_radix(fields.next()?, 16).ok()?,
+        instr: isa
+            .decode(u16::from_str_radix(fields.next()?, 16).ok()?)
+            .ok()?,
+        registers: parse_writes(fields.next()?)?,
+        memory: parse_writes(fields.next()?)?,
+        flags: parse_flags(fields.next()?)?,
+        page: parse_page(fields.next()?)?,
+        next_pc: PC::from_str_radix(fields.next()?, 16).ok()?,
+    };
+    fields.next().is_none().then_some(step)
+}
+
+#[cfg(test)]
+#[test]
+fn trace_replays() {
+    use crate::machine_code::{MEMORY_SIZE, REGISTER_COUNT};
+
+    let mut ctx: Ctx = Ctx::new([0; 256]);
+    ctx.isa = IsaProfile::Arithmetic;
+    // load_value r1, 3; add_integer r2, r1, r1; store_memory r2, 0x80; halt
+    ctx.memory[..8].copy_from_slice(&[0x21, 0x03, 0x52, 0x11, 0x32, 0x80, 0xC0, 0x00]);
+    let initial = ctx.clone();
+
+    let mut trace = Trace::default();
+    while trace.step(&mut ctx).is_ok() {}
+    assert_eq!(trace.steps.len(), 4);
+    assert_eq!(
+        trace.steps[2].memory,
+        [CellWrite {
+            location: 0x80,
+            value: 6
+        }]
+    );
+    assert_eq!(trace.steps[0].flags, None);
+    assert_eq!(trace.steps[1].flags, Some(Flags::of(6)));
+
+    for format in [TraceFormat::JsonLines, TraceFormat::Csv] {
+        let mut w = Vec::new();
+        trace.write(format, &ctx.isa, &mut w).unwrap();
+        let read =
+            Trace::read::<MEMORY_SIZE, REGISTER_COUNT>(format, &ctx.isa, &mut &w[..]).unwrap();
+
+        let mut replayed = initial.clone();
+        read.replay(&mut replayed, 2).unwrap();
+        assert_eq!(replayed.pc, 4);
+        assert_eq!(replayed.registers[2], 6);
+        assert_eq!(replayed.memory[0x80], 0);
+        assert_eq!(replayed.flags, Flags::of(6));
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn trace_round_trips_custom_instructions() {
+    use std::sync::Arc;
+
+    use crate::custom_isa::CustomIsa;
+    use crate::machine_code::{MEMORY_SIZE, REGISTER_COUNT};
+
+    let source = r#"
+        [[instruction]]
+        mnemonic = "double"
+        pattern = 0x0100
+        mask = 0xFFF0
+        operands = [{ name = "r", kind = "register", shift = 0, mask = 0xf }]
+        semantics = "R[r] <- R[r] + R[r]"
+    "#;
+    let isa = IsaProfile::Custom(Arc::new(
+        CustomIsa::load(source, IsaProfile::Classic).unwrap(),
+    ));
+    let mut ctx: Ctx = Ctx::new([0; 256]);
+    ctx.isa = isa.clone();
+    // load_value r1, 3; double r1; halt
+    ctx.memory[..6].copy_from_slice(&[0x21, 0x03, 0x01, 0x01, 0xC0, 0x00]);
+    let mut trace = Trace::default();
+    while trace.step(&mut ctx).is_ok() {}
+
+    for format in [TraceFormat::JsonLines, TraceFormat::Csv] {
+        let mut w = Vec::new();
+        trace.write(format, &isa, &mut w).unwrap();
+        let read = Trace::read::<MEMORY_SIZE, REGISTER_COUNT>(format, &isa, &mut &w[..]).unwrap();
+        assert_eq!(read.steps.len(), 3);
+        assert_eq!(format!("{:?}", read.steps[1].instr), "Custom(double(1))");
+        assert_eq!(
+            read.steps[1].registers,
+            [CellWrite {
+                location: 1,
+                value: 6
+            }]
+        );
+        assert!(Trace::read::<MEMORY_SIZE, REGISTER_COUNT>(
+            format,
+            &IsaProfile::Classic,
+            &mut &w[..]
+        )
+        .is_err());
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn trace_replays_pages() {
+    use crate::machine_code::{MEMORY_SIZE, REGISTER_COUNT};
+
+    let mut ctx: Ctx = Ctx::new([0; 256]);
+    ctx.isa = IsaProfile::Wide;
+    // load_value r1, 1; set_page r1; halt
+    ctx.memory[..6].copy_from_slice(&[0x21, 0x01, 0x04, 0x01, 0xC0, 0x00]);
+    let initial = ctx.clone();
+    let mut trace = Trace::default();
+    while trace.step(&mut ctx).is_ok() {}
+    assert_eq!(trace.steps[1].page, Some(1));
+
+    for format in [TraceFormat::JsonLines, TraceFormat::Csv] {
+        let mut w = Vec::new();
+        trace.write(format, &ctx.isa, &mut w).unwrap();
+        let read =
+            Trace::read::<MEMORY_SIZE, REGISTER_COUNT>(format, &ctx.isa, &mut &w[..]).unwrap();
+        let mut replayed = initial.clone();
+        read.replay(&mut replayed, usize::MAX).unwrap();
+        assert_eq!(replayed.page, 1);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn trace_rejects_invalid_steps() {
+    use crate::machine_code::{MEMORY_SIZE, REGISTER_COUNT};
+
+    let isa = IsaProfile::Classic;
+    for line in ["00,2103,20=03,,,,02", "00,2103,,1FF=03,,,02"] {
+        let csv = format!("{}\n{}\n", CSV_HEADER, line);
+        let read =
+            Trace::read::<MEMORY_SIZE, REGISTER_COUNT>(TraceFormat::Csv, &isa, &mut csv.as_bytes());
+        assert_eq!(read.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+    let json = r#"{"pc":0,"instr":{"Subtract":[1,2]},"registers":[],"memory":[],"next_pc":2}"#;
+    let read = Trace::read::<MEMORY_SIZE, REGISTER_COUNT>(
+        TraceFormat::JsonLines,
+        &isa,
+        &mut json.as_bytes(),
+    );
+    assert_eq!(read.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert!(Trace::read::<MEMORY_SIZE, REGISTER_COUNT>(
+        TraceFormat::JsonLines,
+        &IsaProfile::Arithmetic,
+        &mut json.as_bytes()
+    )
+    .is_ok());
+
+    let mut ctx: Ctx = Ctx::new([0; 256]);
+    let trace = Trace {
+        steps: vec![TraceStep {
+            pc: 0,
+            instr: isa.decode(0x2103).unwrap(),
+            registers: vec![CellWrite {
+                location: 0x20,
+                value: 3,
+            }],
+            memory: vec![],
+            flags: None,
+            page: None,
+            next_pc: 2,
+        }],
+    };
+    assert!(trace.replay(&mut ctx, 1).is_err());
+    assert_eq!(ctx.pc, 0);
+}